@@ -0,0 +1,230 @@
+// Benchmarks comparing the vectors against a `Mutex<Vec>` and crossbeam's `SegQueue`
+//
+// Every workload does `OPS` operations in total, split evenly between the threads. Threads
+// are spawned and the vector is filled before the clock starts, then a barrier releases
+// all the threads at once. After each workload, the number of allocations per operation
+// is printed for every contender, counted by a `CountingAlloc`. `SegQueue` goes through the
+// `ConcurrentStack` impl behind the `segqueue` feature, without it the benchmarks skip it.
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion};
+use criterion::{measurement::WallTime, SamplingMode, Throughput};
+#[cfg(feature = "segqueue")]
+use crossbeam_queue::SegQueue;
+use std::alloc::System;
+use std::hint::black_box;
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use unlocked::counting_alloc::{AllocStats, CountingAlloc};
+use unlocked::{leaky, sealed, ConcurrentStack};
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc<System> = CountingAlloc::new(System);
+
+const OPS: usize = 10_000;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+// Elements in the vector before a read-heavy workload, reads are spread over all of them
+const READ_PREFILL: usize = 1_000;
+
+const SAMPLE_SIZE: usize = 20;
+const WARM_UP: Duration = Duration::from_millis(500);
+const MEASUREMENT: Duration = Duration::from_secs(2);
+
+/// What the benchmarks need on top of `ConcurrentStack`
+trait Contender: ConcurrentStack<u64> + Default + Sync {
+    const NAME: &'static str;
+    /// How to read the element at an index, the read-heavy workload skips contenders without it
+    const READ: Option<fn(&Self, usize) -> Option<u64>> = None;
+    /// Whether memory is never freed, these only get a few iterations so they don't use up
+    /// all the memory. Their numbers are less precise.
+    const LEAKS: bool = false;
+}
+
+impl Contender for sealed::SecVec<'_, u64> {
+    const NAME: &'static str = "sealed";
+    const READ: Option<fn(&Self, usize) -> Option<u64>> = Some(Self::read);
+}
+
+impl Contender for leaky::SecVec<'_, u64> {
+    const NAME: &'static str = "leaky";
+    const LEAKS: bool = true;
+}
+
+impl Contender for Mutex<Vec<u64>> {
+    const NAME: &'static str = "mutex_vec";
+    const READ: Option<fn(&Self, usize) -> Option<u64>> =
+        Some(|vec, index| vec.lock().unwrap().get(index).copied());
+}
+
+#[cfg(feature = "segqueue")]
+impl Contender for SegQueue<u64> {
+    const NAME: &'static str = "seg_queue";
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Workload {
+    /// Every thread pushes onto an empty vector
+    PushOnly,
+    /// Every thread pops from a vector holding exactly enough elements
+    PopOnly,
+    /// Every thread alternates pushes and pops
+    Mixed,
+    /// Nine reads for every push
+    ReadHeavy,
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Workload::PushOnly => "push_only",
+            Workload::PopOnly => "pop_only",
+            Workload::Mixed => "mixed",
+            Workload::ReadHeavy => "read_heavy",
+        }
+    }
+
+    fn prefill(self) -> usize {
+        match self {
+            Workload::PopOnly => OPS,
+            Workload::ReadHeavy => READ_PREFILL,
+            Workload::PushOnly | Workload::Mixed => 0,
+        }
+    }
+
+    fn run<C: Contender>(self, contender: &C, thread: usize, ops: usize) {
+        for i in 0..ops {
+            match self {
+                Workload::PushOnly => contender.push(i as u64),
+                Workload::PopOnly => {
+                    black_box(contender.pop());
+                }
+                Workload::Mixed if i % 2 == 0 => contender.push(i as u64),
+                Workload::Mixed => {
+                    black_box(contender.pop());
+                }
+                Workload::ReadHeavy if i % 10 == 0 => contender.push(i as u64),
+                Workload::ReadHeavy => {
+                    let read = C::READ.expect("bench_contender skips contenders that can't read");
+                    black_box(read(contender, (i * 7 + thread) % READ_PREFILL));
+                }
+            }
+        }
+    }
+}
+
+/// Run `workload` once on a fresh `C`, returning how long the operations took
+/// and what they allocated
+fn run_once<C: Contender>(workload: Workload, threads: usize) -> (Duration, AllocStats) {
+    let contender = C::default();
+    for i in 0..workload.prefill() {
+        contender.push(i as u64);
+    }
+
+    // The first barrier waits for every thread to start up, so their startup allocations
+    // aren't counted. The second one starts the work.
+    let (started, go) = (Barrier::new(threads + 1), Barrier::new(threads + 1));
+    thread::scope(|s| {
+        let handles = (0..threads)
+            .map(|thread| {
+                let (contender, started, go) = (&contender, &started, &go);
+                s.spawn(move || {
+                    started.wait();
+                    go.wait();
+                    workload.run(contender, thread, OPS / threads);
+                })
+            })
+            .collect::<Vec<_>>();
+        started.wait();
+        let before = ALLOCATOR.stats();
+        let start = Instant::now();
+        go.wait();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        (start.elapsed(), ALLOCATOR.stats() - before)
+    })
+}
+
+fn bench_contender<C: Contender>(
+    group: &mut BenchmarkGroup<WallTime>,
+    report: &mut Vec<String>,
+    workload: Workload,
+    threads: usize,
+) {
+    if workload == Workload::ReadHeavy && C::READ.is_none() {
+        return;
+    }
+
+    if C::LEAKS {
+        group
+            .sampling_mode(SamplingMode::Flat)
+            .sample_size(10)
+            .warm_up_time(Duration::from_millis(10))
+            .measurement_time(Duration::from_millis(100));
+    }
+    group.bench_with_input(
+        BenchmarkId::new(C::NAME, threads),
+        &threads,
+        |b, &threads| {
+            b.iter_custom(|iters| (0..iters).map(|_| run_once::<C>(workload, threads).0).sum())
+        },
+    );
+
+    if C::LEAKS {
+        group
+            .sampling_mode(SamplingMode::Auto)
+            .sample_size(SAMPLE_SIZE)
+            .warm_up_time(WARM_UP)
+            .measurement_time(MEASUREMENT);
+    }
+
+    let (_, stats) = run_once::<C>(workload, threads);
+    report.push(format!(
+        "{:>10}/{threads}: {:>6.2} allocations, {:>7.1} bytes per operation",
+        C::NAME,
+        stats.allocations as f64 / OPS as f64,
+        stats.allocated_bytes as f64 / OPS as f64,
+    ));
+}
+
+fn bench_workload(c: &mut Criterion, workload: Workload) {
+    let mut group = c.benchmark_group(workload.name());
+    group.throughput(Throughput::Elements(OPS as u64));
+    let mut report = Vec::new();
+    for threads in THREADS {
+        bench_contender::<sealed::SecVec<u64>>(&mut group, &mut report, workload, threads);
+        bench_contender::<leaky::SecVec<u64>>(&mut group, &mut report, workload, threads);
+        bench_contender::<Mutex<Vec<u64>>>(&mut group, &mut report, workload, threads);
+        #[cfg(feature = "segqueue")]
+        bench_contender::<SegQueue<u64>>(&mut group, &mut report, workload, threads);
+    }
+    group.finish();
+
+    println!("{}: allocations", workload.name());
+    for line in report {
+        println!("{line}");
+    }
+}
+
+fn push_only(c: &mut Criterion) {
+    bench_workload(c, Workload::PushOnly);
+}
+
+fn pop_only(c: &mut Criterion) {
+    bench_workload(c, Workload::PopOnly);
+}
+
+fn mixed(c: &mut Criterion) {
+    bench_workload(c, Workload::Mixed);
+}
+
+fn read_heavy(c: &mut Criterion) {
+    bench_workload(c, Workload::ReadHeavy);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(SAMPLE_SIZE)
+        .warm_up_time(WARM_UP)
+        .measurement_time(MEASUREMENT);
+    targets = push_only, pop_only, mixed, read_heavy
+}
+criterion_main!(benches);
@@ -0,0 +1,6 @@
+// leaky::SecVec never frees its descriptors, so run this one with `-detect_leaks=0`
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use unlocked_fuzz::{run, Scripts};
+
+fuzz_target!(|scripts: Scripts| run::<unlocked::leaky::SecVec<u64>>(scripts));
@@ -0,0 +1,5 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use unlocked_fuzz::{run, Scripts};
+
+fuzz_target!(|scripts: Scripts| run::<unlocked::sealed::SecVec<u64>>(scripts));
@@ -0,0 +1,96 @@
+//! Shared harness for the fuzz targets: the input decodes into one script of operations per
+//! thread, the scripts run on real threads against the same vector, and afterwards the results
+//! are checked against what must have happened whatever the interleaving was.
+use arbitrary::Arbitrary;
+use std::collections::HashSet;
+use std::thread;
+use unlocked::ConcurrentStack;
+
+/// More threads than this don't find more bugs, they just make every run slower
+const MAX_THREADS: usize = 8;
+
+/// Same for operations per thread
+const MAX_OPS: usize = 256;
+
+#[derive(Arbitrary, Clone, Copy, Debug)]
+pub enum Op {
+    Push,
+    Pop,
+    Size,
+    /// Reserve is capped so that the fuzzer doesn't spend its time in the allocator
+    Reserve(u16),
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct Scripts(pub Vec<Vec<Op>>);
+
+/// Run the scripts on their own threads, then check that
+/// - the final size is the number of pushes minus the number of successful pops
+/// - every popped element was pushed, and popped exactly once
+/// - popping everything that's left gives back exactly the elements that weren't popped
+pub fn run<V: ConcurrentStack<u64> + Default + Sync>(Scripts(scripts): Scripts) {
+    let scripts = scripts
+        .into_iter()
+        .take(MAX_THREADS)
+        .map(|mut script| {
+            script.truncate(MAX_OPS);
+            script
+        })
+        .collect::<Vec<_>>();
+
+    let vec = V::default();
+    // Every pushed element is unique: the thread in the high bits, the operation in the low ones
+    let popped = thread::scope(|s| {
+        let handles = scripts
+            .iter()
+            .enumerate()
+            .map(|(thread, script)| {
+                let vec = &vec;
+                s.spawn(move || {
+                    let mut popped = Vec::new();
+                    for (i, op) in script.iter().enumerate() {
+                        match *op {
+                            Op::Push => vec.push(((thread as u64) << 32) | i as u64),
+                            Op::Pop => popped.extend(vec.pop()),
+                            Op::Size => {
+                                vec.size();
+                            }
+                            Op::Reserve(size) => vec.reserve(size as usize),
+                        }
+                    }
+                    popped
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let pushed = scripts
+        .iter()
+        .enumerate()
+        .flat_map(|(thread, script)| {
+            script
+                .iter()
+                .enumerate()
+                .filter(|(_, op)| matches!(op, Op::Push))
+                .map(move |(i, _)| ((thread as u64) << 32) | i as u64)
+        })
+        .collect::<HashSet<_>>();
+
+    assert_eq!(vec.size(), pushed.len() - popped.len());
+
+    let mut seen = HashSet::new();
+    for elem in &popped {
+        assert!(pushed.contains(elem), "popped {elem:#x}, which was never pushed");
+        assert!(seen.insert(*elem), "popped {elem:#x} twice");
+    }
+
+    while let Some(elem) = vec.pop() {
+        assert!(pushed.contains(&elem), "popped {elem:#x}, which was never pushed");
+        assert!(seen.insert(elem), "popped {elem:#x} twice");
+    }
+    assert_eq!(seen, pushed);
+}
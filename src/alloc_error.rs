@@ -1,4 +1,6 @@
 extern crate alloc;
+use alloc::alloc::{handle_alloc_error, Layout};
+use core::fmt;
 use core::mem;
 
 // Allocation error handling
@@ -23,6 +25,23 @@ pub(crate) fn alloc_guard(alloc_size: usize) -> Result<(), TryReserveError> {
     }
 }
 
+// Turn the error from a `try_` method into what the infallible version does instead:
+// panic on capacity overflow, abort through `handle_alloc_error` if the allocator failed.
+#[inline]
+pub(crate) fn handle_reserve(result: Result<(), TryReserveError>) {
+    if let Err(err) = result {
+        handle_error(err)
+    }
+}
+
+#[inline]
+pub(crate) fn handle_error(err: TryReserveError) -> ! {
+    match err.kind {
+        TryReserveErrorKind::CapacityOverflow => capacity_overflow(),
+        TryReserveErrorKind::AllocError { layout } => handle_alloc_error(layout),
+    }
+}
+
 // One central function responsible for reporting capacity overflows. This'll
 // ensure that the code generation related to these panics is minimal as there's
 // only one location which panics rather than a bunch throughout the module.
@@ -33,15 +52,69 @@ pub(crate) fn capacity_overflow() -> ! {
 // https://doc.rust-lang.org/src/alloc/collections/mod.rs.html#58-147
 
 /// The error type for `try_reserve` methods.
-#[derive(PartialEq, Eq)]
-pub(crate) struct TryReserveError {
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TryReserveError {
     kind: TryReserveErrorKind,
 }
 
+impl TryReserveError {
+    /// Details about the allocation that caused the error
+    pub fn kind(&self) -> TryReserveErrorKind {
+        self.kind.clone()
+    }
+}
+
+impl From<TryReserveErrorKind> for TryReserveError {
+    fn from(kind: TryReserveErrorKind) -> Self {
+        Self { kind }
+    }
+}
+
+impl fmt::Display for TryReserveError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("memory allocation failed")?;
+        let reason = match self.kind {
+            TryReserveErrorKind::CapacityOverflow => {
+                " because the computed capacity exceeded the collection's maximum"
+            }
+            TryReserveErrorKind::AllocError { .. } => {
+                " because the memory allocator returned an error"
+            }
+        };
+        fmt.write_str(reason)
+    }
+}
+
 /// Details of the allocation that caused a `TryReserveError`
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum TryReserveErrorKind {
     /// Error due to the computed capacity exceeding the collection's maximum
     /// (usually `isize::MAX` bytes).
     CapacityOverflow,
+
+    /// The memory allocator returned an error
+    AllocError {
+        /// The layout of the allocation request that failed
+        layout: Layout,
+    },
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alloc_guard_rejects_more_than_isize_max_on_small_targets() {
+        assert!(alloc_guard(isize::MAX as usize).is_ok());
+        let too_big = alloc_guard(isize::MAX as usize + 1);
+        if mem::size_of::<usize>() < 8 {
+            assert_eq!(
+                too_big.unwrap_err().kind(),
+                TryReserveErrorKind::CapacityOverflow
+            );
+        } else {
+            // The allocator is trusted to fail on its own
+            assert!(too_big.is_ok());
+        }
+    }
 }
@@ -0,0 +1,98 @@
+// The allocator the buckets come from
+//
+// With the `nightly` feature this is the unstable `Allocator` API. Without it, a stand-in with
+// the same names and signatures calls the stable global allocation functions, so the rest of
+// the crate is written the same way either way.
+extern crate alloc;
+use crate::alloc_error::{alloc_guard, TryReserveError, TryReserveErrorKind};
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+
+#[cfg(feature = "nightly")]
+pub(crate) use alloc::alloc::{Allocator, Global};
+
+#[cfg(not(feature = "nightly"))]
+pub(crate) use self::stable::{Allocator, Global};
+
+/// Allocate a zeroed array of `len` values of type `E`
+///
+/// Fails with `CapacityOverflow` if it wouldn't fit in `isize::MAX` bytes, and with `AllocError`
+/// if the allocator fails.
+pub(crate) fn allocate_zeroed_array<E>(len: usize) -> Result<NonNull<E>, TryReserveError> {
+    let layout = Layout::array::<E>(len)
+        .map_err(|_| TryReserveError::from(TryReserveErrorKind::CapacityOverflow))?;
+
+    // Make sure allocation is ok
+    alloc_guard(layout.size())?;
+
+    match Global.allocate_zeroed(layout) {
+        Ok(ptr) => Ok(ptr.cast()),
+        Err(_) => Err(TryReserveErrorKind::AllocError { layout }.into()),
+    }
+}
+
+/// Free an array from `allocate_zeroed_array`, without dropping its values
+///
+/// # Safety
+/// `ptr` must have come from `allocate_zeroed_array::<E>(len)`, and nobody may use it anymore
+pub(crate) unsafe fn deallocate_array<E>(ptr: NonNull<E>, len: usize) {
+    // # Safety
+    // The layout was fine when the array was allocated, and it was allocated with it
+    unsafe {
+        let layout = Layout::array::<E>(len).unwrap_unchecked();
+        Global.deallocate(ptr.cast(), layout);
+    }
+}
+
+#[cfg(not(feature = "nightly"))]
+mod stable {
+    use super::alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use core::ptr::NonNull;
+
+    /// The global allocator, like `alloc::alloc::Global`
+    #[derive(Clone, Copy, Debug, Default)]
+    pub(crate) struct Global;
+
+    /// The allocator returned null
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(crate) struct AllocError;
+
+    /// The subset of `core::alloc::Allocator` the crate uses
+    pub(crate) trait Allocator {
+        fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+
+        /// # Safety
+        /// `ptr` must have been allocated by this allocator with `layout`
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+    }
+
+    impl Global {
+        fn alloc_zeroed_impl(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            // The global allocator can't be asked for zero bytes
+            if layout.size() == 0 {
+                let dangling = NonNull::new(layout.align() as *mut u8).unwrap();
+                return Ok(NonNull::slice_from_raw_parts(dangling, 0));
+            }
+            // # Safety
+            // The layout isn't zero-sized
+            let ptr = unsafe { alloc_zeroed(layout) };
+            NonNull::new(ptr)
+                .map(|ptr| NonNull::slice_from_raw_parts(ptr, layout.size()))
+                .ok_or(AllocError)
+        }
+    }
+
+    impl Allocator for Global {
+        fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.alloc_zeroed_impl(layout)
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            if layout.size() != 0 {
+                // # Safety
+                // Guaranteed by the caller
+                unsafe { dealloc(ptr.as_ptr(), layout) }
+            }
+        }
+    }
+}
@@ -0,0 +1,357 @@
+// An append-only vector. Without `pop`, an element is never removed or moved once it's written,
+// so the vector can hand out plain references to its elements.
+extern crate alloc;
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use crossbeam_utils::CachePadded;
+
+/// A lock-free vector that can only grow, for use as a concurrent arena or registry.
+///
+/// Elements live in the same buckets as a `SecVec`'s, which are never moved or freed until the
+/// vector is dropped. So `push` can return the index it wrote to, and `get` returns a `&T` that
+/// lives as long as the vector does. Unlike a `SecVec`, `T` doesn't have to be `Copy`.
+///
+/// Reading doesn't go through descriptors or hazard pointers: `get` is two acquire loads,
+/// one for the bucket and one for the element's ready flag (plain loads on x86).
+/// ```rust
+/// use unlocked::append::AppendVec;
+///
+/// let names = AppendVec::new();
+/// let alice = names.push(String::from("alice"));
+/// let first = names.get(alice).unwrap();
+/// for i in 0..100 {
+///     names.push(i.to_string());
+/// }
+/// // Growing the vector didn't move anything
+/// assert_eq!(first, "alice");
+/// assert_eq!(names.len(), 101);
+/// ```
+pub struct AppendVec<T> {
+    buckets: Buckets<Entry<T>>,
+    // The number of indices handed out, some of which may not be written yet
+    len: CachePadded<AtomicUsize>,
+}
+
+struct Entry<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    // Set once `value` has been written
+    ready: AtomicBool,
+}
+
+// # Safety
+// An uninitialized value and a false flag are all zeroes
+unsafe impl<T> Zeroable for Entry<T> {
+    fn zeroed() -> Self {
+        Self {
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            ready: AtomicBool::new(false),
+        }
+    }
+}
+
+impl<T> Drop for Entry<T> {
+    fn drop(&mut self) {
+        if self.ready.load(Ordering::Relaxed) {
+            // # Safety
+            // The flag is only set after the value is written, and we have &mut self
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+// # Safety
+// Elements are moved in by any thread with a `&AppendVec` (T: Send)
+// and shared with all of them through `get` (T: Sync)
+unsafe impl<T: Send + Sync> Sync for AppendVec<T> {}
+
+impl<T> AppendVec<T> {
+    /// Return a new, empty vector. No buckets are allocated until the first push.
+    pub fn new() -> Self {
+        Self {
+            buckets: Buckets::new(),
+            len: CachePadded::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Append an element and return its index
+    /// ```rust
+    /// # use unlocked::append::AppendVec;
+    /// let av = AppendVec::new();
+    /// assert_eq!(av.push('a'), 0);
+    /// assert_eq!(av.push('b'), 1);
+    /// ```
+    pub fn push(&self, elem: T) -> usize {
+        let index = self.len.fetch_add(1, Ordering::Relaxed);
+        let entry = match self.buckets.get_or_allocate(index) {
+            Ok(entry) => entry,
+            Err(err) => handle_error(err),
+        };
+        // # Safety
+        // `fetch_add` gave this index to us alone, and nobody reads the value before `ready` is set
+        unsafe { (*entry.value.get()).write(elem) };
+        entry.ready.store(true, Ordering::Release);
+        index
+    }
+
+    /// Return a reference to the element at `index`, or `None` if nothing has been written there yet
+    /// ```rust
+    /// # use unlocked::append::AppendVec;
+    /// let av = AppendVec::new();
+    /// av.push(1);
+    /// assert_eq!(av.get(0), Some(&1));
+    /// assert_eq!(av.get(1), None);
+    /// ```
+    pub fn get(&self, index: usize) -> Option<&T> {
+        let entry = self.buckets.get(index)?;
+        if entry.ready.load(Ordering::Acquire) {
+            // # Safety
+            // The value was written before `ready` was set, and is never written again
+            Some(unsafe { (*entry.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Return the number of elements, including ones whose push hasn't finished writing them yet.
+    /// `get` returns `None` for those until it has.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the elements in index order, skipping any that aren't written yet
+    /// ```rust
+    /// # use unlocked::append::AppendVec;
+    /// let av = AppendVec::new();
+    /// av.push(1);
+    /// av.push(2);
+    /// assert_eq!(av.iter().sum::<i32>(), 3);
+    /// ```
+    pub fn iter(&self) -> impl Iterator<Item = &T> + '_ {
+        (0..self.len()).filter_map(|index| self.get(index))
+    }
+
+    pub fn reserve(&self, size: usize) {
+        handle_reserve(self.try_reserve(size))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    ///
+    /// Buckets allocated before the failure stay part of the vector.
+    /// ```rust
+    /// # use unlocked::append::AppendVec;
+    /// let av = AppendVec::<u64>::new();
+    /// assert!(av.try_reserve(100).is_ok());
+    /// assert!(av.try_reserve(usize::MAX).is_err());
+    /// ```
+    pub fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        self.buckets.try_reserve(size)
+    }
+}
+
+impl<T> Default for AppendVec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for AppendVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use crate::TryReserveErrorKind;
+    use alloc::string::{String, ToString};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::thread;
+
+    #[test]
+    fn push_returns_consecutive_indices() {
+        let av = AppendVec::new();
+        assert!(av.is_empty());
+        for i in 0..100 {
+            assert_eq!(av.push(i * 2), i);
+        }
+        assert_eq!(av.len(), 100);
+        for i in 0..100 {
+            assert_eq!(av.get(i), Some(&(i * 2)));
+        }
+        assert_eq!(av.get(100), None);
+        assert_eq!(av.get(usize::MAX), None);
+    }
+
+    #[test]
+    fn references_survive_growth() {
+        let av = AppendVec::new();
+        av.push(String::from("first"));
+        let first = av.get(0).unwrap();
+        let addr = first as *const String;
+        for i in 0..10_000 {
+            av.push(i.to_string());
+        }
+        assert_eq!(first, "first");
+        assert_eq!(av.get(0).unwrap() as *const String, addr);
+    }
+
+    #[test]
+    fn concurrent_pushes_get_distinct_indices() {
+        let av = AppendVec::new();
+        let indices = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|t| {
+                    let av = &av;
+                    s.spawn(move || {
+                        (0..1000)
+                            .map(|i| (av.push(t * 1000 + i), t * 1000 + i))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(av.len(), 4000);
+        for (index, elem) in &indices {
+            assert_eq!(av.get(*index), Some(elem));
+        }
+        let mut seen = indices.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..4000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn readers_see_concurrent_pushes() {
+        let av = AppendVec::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..10_000u64 {
+                    av.push(i);
+                }
+            });
+            s.spawn(|| {
+                while av.len() < 10_000 {
+                    for (i, elem) in av.iter().enumerate().take(100) {
+                        // Pushes come from one thread, so there are no gaps
+                        assert_eq!(*elem, i as u64);
+                    }
+                }
+            });
+        });
+        assert_eq!(av.iter().count(), 10_000);
+    }
+
+    #[test]
+    fn drop_drops_every_element() {
+        let counter = Arc::new(());
+        let av = AppendVec::new();
+        for _ in 0..100 {
+            av.push(Arc::clone(&counter));
+        }
+        assert_eq!(Arc::strong_count(&counter), 101);
+        drop(av);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn drop_frees_elements_and_skips_unwritten_entries() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let av = AppendVec::new();
+            for i in 0..1000 {
+                av.push(i.to_string());
+            }
+            // Most of these entries are never written, dropping one would free garbage
+            av.reserve(5000);
+        });
+        assert_eq!(stats.live_bytes(), 0, "elements leaked");
+    }
+
+    #[test]
+    fn failed_reserve_hands_out_no_indices() {
+        let av = AppendVec::new();
+        av.push(0);
+        // Bucket 0 is allocated, so this fails on bucket 1
+        let err = failing_alloc::fail_nth(0, || av.try_reserve(1000)).unwrap_err();
+        assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+        // Reserving doesn't take any indices, the next push still gets 1
+        assert_eq!(av.len(), 1);
+        assert_eq!(av.get(1), None);
+        for i in 1..9 {
+            assert_eq!(av.push(i), i);
+        }
+        // Index 8 is the first in bucket 1
+        assert_eq!(av.get(8), Some(&8));
+    }
+
+    #[test]
+    fn try_reserve_reports_capacity_overflow() {
+        let av = AppendVec::<u64>::new();
+        assert_eq!(
+            av.try_reserve(usize::MAX).unwrap_err().kind(),
+            TryReserveErrorKind::CapacityOverflow
+        );
+    }
+
+    #[test]
+    fn push_aborts_on_allocation_failure() {
+        // The 9th element is the first in a new bucket
+        failing_alloc::assert_every_failure_aborts(
+            "append::tests::push_aborts_on_allocation_failure",
+            || {
+                let av = AppendVec::new();
+                for i in 0..8u64 {
+                    av.push(i);
+                }
+                av
+            },
+            |av| {
+                av.push(8);
+            },
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    extern crate std;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_push_and_get() {
+        loom::model(|| {
+            let av = Arc::new(AppendVec::new());
+            let av1 = Arc::clone(&av);
+            let t = thread::spawn(move || {
+                let index = av1.push(1);
+                assert_eq!(av1.get(index), Some(&1));
+                index
+            });
+            let index = av.push(2);
+            // The other push may or may not have written its element yet
+            let other = av.get(1 - index);
+            assert!(other.is_none() || other == Some(&1));
+            assert_ne!(t.join().unwrap(), index);
+
+            assert_eq!(av.len(), 2);
+            assert_eq!(av.iter().sum::<u64>(), 3);
+        });
+    }
+}
@@ -0,0 +1,229 @@
+// Command line parsing, kept by hand so the crate doesn't need a dependency for it
+use std::fmt;
+
+pub const USAGE: &str = "\
+Usage: unlocked-bench [OPTIONS]
+
+Runs a workload of random pushes, pops and reads on a vector from several threads,
+then reports throughput and per-operation latency percentiles.
+
+Options:
+  --vec <IMPL>       sealed, leaky, qsbr, waitfree or mutex [default: sealed]
+  --threads <N>      number of threads [default: 4]
+  --ops <N>          total operations, split between the threads [default: 1000000]
+  --push <WEIGHT>    relative weight of pushes [default: 50]
+  --pop <WEIGHT>     relative weight of pops [default: 50]
+  --read <WEIGHT>    relative weight of reads, only sealed and mutex can read [default: 0]
+  --reserve <N>      reserve space for this many elements first [default: 0]
+  --prefill <N>      push this many elements first, reads pick an index below it [default: 0]
+  --width <BITS>     element width: 8, 16, 32 or 64 [default: 64]
+  --format <FORMAT>  json or csv [default: json]
+  --seed <N>         seed for picking operations [default: 0]
+  -h, --help         print this message
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Impl {
+    Sealed,
+    Leaky,
+    Qsbr,
+    Waitfree,
+    Mutex,
+}
+
+impl Impl {
+    pub fn can_read(self) -> bool {
+        matches!(self, Impl::Sealed | Impl::Mutex)
+    }
+}
+
+impl fmt::Display for Impl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Impl::Sealed => "sealed",
+            Impl::Leaky => "leaky",
+            Impl::Qsbr => "qsbr",
+            Impl::Waitfree => "waitfree",
+            Impl::Mutex => "mutex",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub vec: Impl,
+    pub threads: usize,
+    pub ops: usize,
+    pub push: u32,
+    pub pop: u32,
+    pub read: u32,
+    pub reserve: usize,
+    pub prefill: usize,
+    pub width: u32,
+    pub format: Format,
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            vec: Impl::Sealed,
+            threads: 4,
+            ops: 1_000_000,
+            push: 50,
+            pop: 50,
+            read: 0,
+            reserve: 0,
+            prefill: 0,
+            width: 64,
+            format: Format::Json,
+            seed: 0,
+        }
+    }
+}
+
+/// What the command line asked for
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run(Config),
+    Help,
+}
+
+/// Parse the arguments, not including the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut config = Config::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        if flag == "-h" || flag == "--help" {
+            return Ok(Command::Help);
+        }
+        let value = match inline.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(format!("{flag} needs a value")),
+        };
+
+        match flag.as_str() {
+            "--vec" => {
+                config.vec = match value.as_str() {
+                    "sealed" => Impl::Sealed,
+                    "leaky" => Impl::Leaky,
+                    "qsbr" => Impl::Qsbr,
+                    "waitfree" => Impl::Waitfree,
+                    "mutex" => Impl::Mutex,
+                    _ => return Err(format!("unknown vector implementation {value:?}")),
+                }
+            }
+            "--threads" => config.threads = number(&flag, &value)?,
+            "--ops" => config.ops = number(&flag, &value)?,
+            "--push" => config.push = number(&flag, &value)?,
+            "--pop" => config.pop = number(&flag, &value)?,
+            "--read" => config.read = number(&flag, &value)?,
+            "--reserve" => config.reserve = number(&flag, &value)?,
+            "--prefill" => config.prefill = number(&flag, &value)?,
+            "--width" => {
+                config.width = number(&flag, &value)?;
+                if ![8, 16, 32, 64].contains(&config.width) {
+                    return Err(format!("--width must be 8, 16, 32 or 64, not {value}"));
+                }
+            }
+            "--format" => {
+                config.format = match value.as_str() {
+                    "json" => Format::Json,
+                    "csv" => Format::Csv,
+                    _ => return Err(format!("unknown format {value:?}")),
+                }
+            }
+            "--seed" => config.seed = number(&flag, &value)?,
+            _ => return Err(format!("unknown option {flag}")),
+        }
+    }
+
+    if config.threads == 0 {
+        return Err("--threads must be at least 1".into());
+    }
+    if config.push + config.pop + config.read == 0 {
+        return Err("at least one of --push, --pop and --read must be positive".into());
+    }
+    if config.read > 0 && !config.vec.can_read() {
+        return Err(format!("{} can't read, use sealed or mutex", config.vec));
+    }
+    Ok(Command::Run(config))
+}
+
+fn number<N: std::str::FromStr>(flag: &str, value: &str) -> Result<N, String> {
+    value
+        .replace('_', "")
+        .parse()
+        .map_err(|_| format!("{flag} needs a number, not {value:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn no_arguments_gives_defaults() {
+        assert_eq!(parse_str(""), Ok(Command::Run(Config::default())));
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let expected = Config {
+            vec: Impl::Mutex,
+            threads: 8,
+            ops: 10_000,
+            push: 1,
+            pop: 2,
+            read: 3,
+            reserve: 100,
+            prefill: 50,
+            width: 16,
+            format: Format::Csv,
+            seed: 7,
+        };
+        assert_eq!(
+            parse_str(
+                "--vec mutex --threads 8 --ops 10_000 --push 1 --pop=2 --read 3 \
+                 --reserve 100 --prefill 50 --width 16 --format csv --seed 7"
+            ),
+            Ok(Command::Run(expected))
+        );
+    }
+
+    #[test]
+    fn help_wins() {
+        assert_eq!(parse_str("--threads 2 --help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(parse_str("--threads").is_err());
+        assert!(parse_str("--threads two").is_err());
+        assert!(parse_str("--threads 0").is_err());
+        assert!(parse_str("--width 12").is_err());
+        assert!(parse_str("--vec vector").is_err());
+        assert!(parse_str("--push 0 --pop 0").is_err());
+        assert!(parse_str("--frobnicate 1").is_err());
+    }
+
+    #[test]
+    fn only_some_vectors_read() {
+        assert!(parse_str("--vec leaky --read 10").is_err());
+        assert!(parse_str("--vec sealed --read 10").is_ok());
+    }
+}
@@ -0,0 +1,162 @@
+// Latency histogram with logarithmic buckets
+//
+// Each power of two is split into 2^SUB_BITS buckets, so a recorded value is off by at most
+// 1/2^SUB_BITS (6.25%), while the whole u64 range fits in about a thousand counters.
+
+const SUB_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) << SUB_BITS;
+
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u128,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; BUCKETS],
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[bucket(value)] += 1;
+        self.count += 1;
+        self.sum += value as u128;
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// The smallest recorded value that `quantile` of the values are less than or equal to,
+    /// rounded up to the top of its bucket
+    pub fn percentile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((quantile * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let highest_bit = 63 - value.leading_zeros();
+    let shift = highest_bit - SUB_BITS;
+    let sub = (value >> shift) & (SUB_BUCKETS - 1);
+    (((shift + 1) as u64) << SUB_BITS | sub) as usize
+}
+
+/// The largest value that falls into `bucket`
+fn highest(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = (bucket >> SUB_BITS) - 1;
+    let lowest = (SUB_BUCKETS | (bucket & (SUB_BUCKETS - 1))) << shift;
+    lowest + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_every_value() {
+        for value in (0..100_000).chain([u64::MAX / 3, u64::MAX - 1, u64::MAX]) {
+            let b = bucket(value);
+            assert!(b < BUCKETS);
+            assert!(value <= highest(b), "{value} is above its bucket");
+            if b > 0 {
+                assert!(value > highest(b - 1), "{value} is below its bucket");
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_are_precise_to_a_sixteenth() {
+        for value in [20, 1_000, 123_456, 1 << 40] {
+            let top = highest(bucket(value));
+            assert!((top - value) as f64 <= value as f64 / SUB_BUCKETS as f64);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_uniform_values() {
+        let mut hist = Histogram::new();
+        for value in 1..=1000 {
+            hist.record(value);
+        }
+        assert_eq!(hist.count(), 1000);
+        assert_eq!(hist.max(), 1000);
+        assert_eq!(hist.mean(), 500.5);
+        let p50 = hist.percentile(0.5);
+        assert!((500..=500 + 500 / 16).contains(&p50), "p50 was {p50}");
+        assert_eq!(hist.percentile(0.999), 1000);
+        assert_eq!(hist.percentile(1.0), 1000);
+    }
+
+    #[test]
+    fn merge_adds_counts() {
+        let (mut a, mut b) = (Histogram::new(), Histogram::new());
+        a.record(1);
+        b.record(3);
+        b.record(5);
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.max(), 5);
+        assert_eq!(a.percentile(0.5), 3);
+    }
+
+    #[test]
+    fn empty_histogram_reports_zeros() {
+        let hist = Histogram::new();
+        assert_eq!(hist.percentile(0.99), 0);
+        assert_eq!(hist.mean(), 0.0);
+    }
+}
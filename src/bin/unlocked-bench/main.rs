@@ -0,0 +1,235 @@
+// Load generator: run a configurable mix of operations on one of the vectors and report
+// throughput and latency percentiles, so the same workload can be compared across machines
+// and over time. Run with `--help` for the options.
+mod args;
+mod histogram;
+mod report;
+
+use args::{Command, Config, Format, Impl};
+use histogram::Histogram;
+use report::Results;
+use std::hint::black_box;
+use std::process::ExitCode;
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use unlocked::{leaky, qsbr, sealed, waitfree, ConcurrentStack, Element};
+
+// How many operations a thread does between quiescent states on a qsbr::SecVec
+const QUIESCENT_EVERY: usize = 64;
+
+/// Element types for the different `--width`s
+trait Elem: Element + Send + Sync + 'static {
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_elem {
+    ($($ty:ty),*) => {
+        $(
+            impl Elem for $ty {
+                fn from_u64(value: u64) -> Self {
+                    value as $ty
+                }
+            }
+        )*
+    };
+}
+
+impl_elem!(u8, u16, u32, u64);
+
+/// What the load generator needs on top of `ConcurrentStack`
+trait Target<T>: ConcurrentStack<T> + Default + Sync {
+    /// Only called if `Impl::can_read`
+    fn read(&self, _index: usize) -> Option<T> {
+        unreachable!("reads are rejected when parsing arguments")
+    }
+    /// Called every `QUIESCENT_EVERY` operations
+    fn quiescent(&self) {}
+    /// Called when a thread is done with the vector
+    fn unregister(&self) {}
+}
+
+impl<T: Elem> Target<T> for sealed::SecVec<'_, T> {
+    fn read(&self, index: usize) -> Option<T> {
+        self.read(index)
+    }
+}
+
+impl<T: Elem> Target<T> for leaky::SecVec<'_, T> {}
+
+impl<T: Elem> Target<T> for qsbr::SecVec<'_, T> {
+    fn quiescent(&self) {
+        self.quiescent()
+    }
+
+    fn unregister(&self) {
+        self.unregister()
+    }
+}
+
+impl<T: Elem> Target<T> for waitfree::SecVec<'_, T> {}
+
+impl<T: Elem> Target<T> for Mutex<Vec<T>> {
+    fn read(&self, index: usize) -> Option<T> {
+        self.lock().unwrap().get(index).copied()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Push,
+    Pop,
+    Read,
+}
+
+const OPS: [(Op, &str); 3] = [(Op::Push, "push"), (Op::Pop, "pop"), (Op::Read, "read")];
+
+/// xorshift64*, plenty random for picking operations and cheap enough not to show up
+/// in the latencies
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be 0
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Config {
+    fn pick(&self, rng: &mut Rng) -> Op {
+        let roll = (rng.next() % (self.push + self.pop + self.read) as u64) as u32;
+        if roll < self.push {
+            Op::Push
+        } else if roll < self.push + self.pop {
+            Op::Pop
+        } else {
+            Op::Read
+        }
+    }
+}
+
+/// Run `ops` operations on one thread, returning a histogram for each kind of operation
+fn worker<T: Elem, V: Target<T>>(
+    vec: &V,
+    config: &Config,
+    thread: usize,
+    ops: usize,
+) -> [Histogram; 3] {
+    let mut rng = Rng::new(config.seed ^ (thread as u64).wrapping_mul(0x1234_5678_9ABC_DEF1));
+    let mut latencies = [Histogram::new(), Histogram::new(), Histogram::new()];
+    for i in 0..ops {
+        let op = config.pick(&mut rng);
+        let index = (rng.next() % config.prefill.max(1) as u64) as usize;
+        let start = Instant::now();
+        match op {
+            Op::Push => vec.push(T::from_u64(i as u64)),
+            Op::Pop => {
+                black_box(vec.pop());
+            }
+            Op::Read => {
+                black_box(vec.read(index));
+            }
+        }
+        latencies[op as usize].record(start.elapsed().as_nanos() as u64);
+        if i % QUIESCENT_EVERY == 0 {
+            vec.quiescent();
+        }
+    }
+    vec.unregister();
+    latencies
+}
+
+fn run<T: Elem, V: Target<T>>(config: &Config) -> Results {
+    let vec = V::default();
+    vec.reserve(config.reserve);
+    for i in 0..config.prefill {
+        vec.push(T::from_u64(i as u64));
+    }
+    // The main thread only waits from here on, it mustn't hold up reclamation
+    vec.unregister();
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let start = Barrier::new(config.threads + 1);
+    let (elapsed, histograms) = thread::scope(|s| {
+        let handles = (0..config.threads)
+            .map(|thread| {
+                // Spread the remainder over the first threads
+                let ops =
+                    config.ops / config.threads + usize::from(thread < config.ops % config.threads);
+                let (vec, start) = (&vec, &start);
+                s.spawn(move || {
+                    start.wait();
+                    worker::<T, V>(vec, config, thread, ops)
+                })
+            })
+            .collect::<Vec<_>>();
+        start.wait();
+        let started = Instant::now();
+        let histograms = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        (started.elapsed(), histograms)
+    });
+
+    let latencies = OPS
+        .iter()
+        .map(|&(op, name)| {
+            let mut merged = Histogram::new();
+            for thread in &histograms {
+                merged.merge(&thread[op as usize]);
+            }
+            (name, merged)
+        })
+        .collect();
+    Results {
+        timestamp,
+        elapsed,
+        latencies,
+    }
+}
+
+fn run_width<T: Elem>(config: &Config) -> Results {
+    match config.vec {
+        Impl::Sealed => run::<T, sealed::SecVec<T>>(config),
+        Impl::Leaky => run::<T, leaky::SecVec<T>>(config),
+        Impl::Qsbr => run::<T, qsbr::SecVec<T>>(config),
+        Impl::Waitfree => run::<T, waitfree::SecVec<T>>(config),
+        Impl::Mutex => run::<T, Mutex<Vec<T>>>(config),
+    }
+}
+
+fn main() -> ExitCode {
+    let config = match args::parse(std::env::args().skip(1)) {
+        Ok(Command::Run(config)) => config,
+        Ok(Command::Help) => {
+            print!("{}", args::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{}", args::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let results = match config.width {
+        8 => run_width::<u8>(&config),
+        16 => run_width::<u16>(&config),
+        32 => run_width::<u32>(&config),
+        _ => run_width::<u64>(&config),
+    };
+    match config.format {
+        Format::Json => print!("{}", report::json(&config, &results)),
+        Format::Csv => print!("{}", report::csv(&config, &results)),
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,160 @@
+// Turning the results of a run into JSON or CSV
+//
+// Both are written by hand, the output is flat enough that it doesn't need serde
+use crate::args::Config;
+use crate::histogram::Histogram;
+use std::fmt::Write;
+use std::time::Duration;
+
+pub const PERCENTILES: [(&str, f64); 3] = [("p50", 0.5), ("p99", 0.99), ("p999", 0.999)];
+
+pub struct Results {
+    /// Seconds since the unix epoch when the run started
+    pub timestamp: u64,
+    pub elapsed: Duration,
+    /// Latencies in nanoseconds for every kind of operation
+    pub latencies: Vec<(&'static str, Histogram)>,
+}
+
+impl Results {
+    fn total(&self) -> Histogram {
+        let mut total = Histogram::new();
+        for (_, hist) in &self.latencies {
+            total.merge(hist);
+        }
+        total
+    }
+
+    fn throughput(&self) -> f64 {
+        self.total().count() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Every kind of operation that happened, then all of them together
+    fn rows(&self) -> impl Iterator<Item = (&str, Histogram)> + '_ {
+        self.latencies
+            .iter()
+            .filter(|(_, hist)| hist.count() > 0)
+            .map(|(op, hist)| (*op, hist.clone()))
+            .chain([("all", self.total())])
+    }
+}
+
+pub fn json(config: &Config, results: &Results) -> String {
+    let mut out = String::new();
+    out.push_str("{\n");
+    let _ = writeln!(out, "  \"timestamp\": {},", results.timestamp);
+    let _ = writeln!(
+        out,
+        "  \"config\": {{\"vec\": \"{}\", \"threads\": {}, \"ops\": {}, \"push\": {}, \
+         \"pop\": {}, \"read\": {}, \"reserve\": {}, \"prefill\": {}, \"width\": {}, \
+         \"seed\": {}}},",
+        config.vec,
+        config.threads,
+        config.ops,
+        config.push,
+        config.pop,
+        config.read,
+        config.reserve,
+        config.prefill,
+        config.width,
+        config.seed,
+    );
+    let _ = writeln!(
+        out,
+        "  \"elapsed_s\": {:.6},",
+        results.elapsed.as_secs_f64()
+    );
+    let _ = writeln!(
+        out,
+        "  \"throughput_ops_per_s\": {:.1},",
+        results.throughput()
+    );
+    out.push_str("  \"latency_ns\": {\n");
+    let rows = results.rows().collect::<Vec<_>>();
+    for (i, (op, hist)) in rows.iter().enumerate() {
+        let _ = write!(
+            out,
+            "    \"{op}\": {{\"count\": {}, \"mean\": {:.1}",
+            hist.count(),
+            hist.mean()
+        );
+        for (name, quantile) in PERCENTILES {
+            let _ = write!(out, ", \"{name}\": {}", hist.percentile(quantile));
+        }
+        let _ = write!(out, ", \"max\": {}}}", hist.max());
+        out.push_str(if i + 1 < rows.len() { ",\n" } else { "\n" });
+    }
+    out.push_str("  }\n}\n");
+    out
+}
+
+/// One row per kind of operation, each repeating the configuration,
+/// so results from many runs can be concatenated
+pub fn csv(config: &Config, results: &Results) -> String {
+    let mut out = String::from(
+        "timestamp,vec,threads,ops,push,pop,read,reserve,prefill,width,seed,elapsed_s,\
+         throughput_ops_per_s,op,count,mean_ns,p50_ns,p99_ns,p999_ns,max_ns\n",
+    );
+    for (op, hist) in results.rows() {
+        let _ = write!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{},{:.6},{:.1},{op},{},{:.1}",
+            results.timestamp,
+            config.vec,
+            config.threads,
+            config.ops,
+            config.push,
+            config.pop,
+            config.read,
+            config.reserve,
+            config.prefill,
+            config.width,
+            config.seed,
+            results.elapsed.as_secs_f64(),
+            results.throughput(),
+            hist.count(),
+            hist.mean(),
+        );
+        for (_, quantile) in PERCENTILES {
+            let _ = write!(out, ",{}", hist.percentile(quantile));
+        }
+        let _ = writeln!(out, ",{}", hist.max());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results() -> Results {
+        let mut push = Histogram::new();
+        push.record(100);
+        push.record(300);
+        Results {
+            timestamp: 1,
+            elapsed: Duration::from_secs(2),
+            latencies: vec![("push", push), ("pop", Histogram::new())],
+        }
+    }
+
+    #[test]
+    fn json_skips_missing_operations() {
+        let json = json(&Config::default(), &results());
+        assert!(json.contains("\"throughput_ops_per_s\": 1.0,"));
+        assert!(json.contains("\"push\": {\"count\": 2, \"mean\": 200.0"));
+        assert!(json.contains("\"all\": {\"count\": 2"));
+        assert!(!json.contains("\"pop\": {"));
+    }
+
+    #[test]
+    fn csv_rows_match_header() {
+        let csv = csv(&Config::default(), &results());
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        let columns = lines[0].split(',').count();
+        assert!(lines.iter().all(|line| line.split(',').count() == columns));
+        assert!(lines[1].contains(",push,2,200.0,"));
+        assert!(lines[2].contains(",all,2,200.0,"));
+    }
+}
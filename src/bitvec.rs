@@ -0,0 +1,294 @@
+// A growable bitset on the same buckets as `AppendVec`, 64 bits to an entry
+//
+// Every operation on a bit is a single atomic operation on the word it lives in, so there are no
+// descriptors: setting a bit can't conflict with anything but the bucket allocation, which is the
+// same CAS-install the vectors use.
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::sync::atomic::{AtomicU64, Ordering};
+use core::fmt;
+
+const BITS: usize = u64::BITS as usize;
+
+/// A lock-free bitset that grows to fit the bits that are set, such as a visited set
+/// for a parallel graph traversal.
+///
+/// Bits that were never set read as clear, so only `set` and `test_and_set` allocate.
+/// Words are allocated in the same buckets as a `SecVec`'s and never move,
+/// so a bit is always in the same place whatever other threads are doing.
+/// ```rust
+/// use unlocked::bitvec::SecBitVec;
+///
+/// let visited = SecBitVec::new();
+/// assert!(!visited.test_and_set(3));
+/// assert!(visited.test_and_set(3));
+/// visited.set(1_000);
+/// assert!(visited.test(1_000));
+/// assert!(!visited.test(999));
+/// assert_eq!(visited.count_ones(), 2);
+/// ```
+pub struct SecBitVec {
+    words: Buckets<Word>,
+}
+
+struct Word(AtomicU64);
+
+// # Safety
+// A word with no bits set is all zeroes
+unsafe impl Zeroable for Word {
+    fn zeroed() -> Self {
+        Self(AtomicU64::new(0))
+    }
+}
+
+impl SecBitVec {
+    /// Return a new bitset with every bit clear. No buckets are allocated until a bit is set.
+    pub fn new() -> Self {
+        Self {
+            words: Buckets::new(),
+        }
+    }
+
+    /// Set bit `index`, growing the bitset if needed
+    pub fn set(&self, index: usize) {
+        self.test_and_set(index);
+    }
+
+    /// Clear bit `index`
+    /// ```rust
+    /// # use unlocked::bitvec::SecBitVec;
+    /// let bits = SecBitVec::new();
+    /// bits.set(5);
+    /// bits.clear(5);
+    /// assert!(!bits.test(5));
+    /// // Bits past the end are already clear
+    /// bits.clear(usize::MAX / 2);
+    /// ```
+    pub fn clear(&self, index: usize) {
+        let (word, bit) = Self::bit(index);
+        if let Some(word) = self.words.get(word) {
+            word.0.fetch_and(!bit, Ordering::AcqRel);
+        }
+    }
+
+    /// Return whether bit `index` is set
+    pub fn test(&self, index: usize) -> bool {
+        let (word, bit) = Self::bit(index);
+        self.words
+            .get(word)
+            .is_some_and(|word| word.0.load(Ordering::Acquire) & bit != 0)
+    }
+
+    /// Set bit `index` and return whether it was already set, growing the bitset if needed.
+    ///
+    /// Of several threads setting the same bit, exactly one sees `false`.
+    pub fn test_and_set(&self, index: usize) -> bool {
+        let (word, bit) = Self::bit(index);
+        let word = match self.words.get_or_allocate(word) {
+            Ok(word) => word,
+            Err(err) => handle_error(err),
+        };
+        word.0.fetch_or(bit, Ordering::AcqRel) & bit != 0
+    }
+
+    /// Return the number of bits that are set.
+    ///
+    /// The words are read one at a time, so with concurrent updates this may not be the count at
+    /// any single point in time.
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .allocated()
+            .flat_map(|(_, words)| words)
+            .map(|word| word.0.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+
+    /// Make room for bits `0..len` so that setting them doesn't allocate memory
+    pub fn reserve(&self, len: usize) {
+        handle_reserve(self.try_reserve(len))
+    }
+
+    /// Like `reserve`, but reports an error instead of aborting if the allocator fails.
+    ///
+    /// Buckets allocated before the failure stay part of the bitset.
+    pub fn try_reserve(&self, len: usize) -> Result<(), TryReserveError> {
+        self.words.try_reserve(len.div_ceil(BITS))
+    }
+
+    /// Return the index of the word bit `index` is in, and its mask in that word
+    #[inline]
+    fn bit(index: usize) -> (usize, u64) {
+        (index / BITS, 1 << (index % BITS))
+    }
+}
+
+impl Default for SecBitVec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SecBitVec {
+    /// Formats the indices of the bits that are set
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ones = self.words.allocated().flat_map(|(first, words)| {
+            words.iter().enumerate().flat_map(move |(i, word)| {
+                let word = word.0.load(Ordering::Relaxed);
+                (0..BITS)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| (first + i) * BITS + bit)
+            })
+        });
+        f.debug_set().entries(ones).finish()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate alloc;
+    extern crate std;
+    use crate::failing_alloc;
+    use crate::TryReserveErrorKind;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use std::thread;
+
+    #[test]
+    fn set_clear_and_test() {
+        let bits = SecBitVec::new();
+        for i in (0..1000).step_by(3) {
+            bits.set(i);
+        }
+        for i in 0..1100 {
+            assert_eq!(bits.test(i), i < 1000 && i % 3 == 0, "bit {i}");
+        }
+        assert_eq!(bits.count_ones(), 334);
+        for i in (0..1000).step_by(6) {
+            bits.clear(i);
+        }
+        assert_eq!(bits.count_ones(), 167);
+        assert!(!bits.test(0));
+        assert!(bits.test(3));
+    }
+
+    #[test]
+    fn sparse_bits_only_allocate_their_bucket() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let bits = SecBitVec::new();
+            bits.set(1 << 20);
+            assert!(bits.test(1 << 20));
+            assert!(!bits.test(0));
+            assert_eq!(bits.count_ones(), 1);
+        });
+        // The bit's bucket holds 2^14 words, every bucket up to it would be almost twice that
+        assert!(stats.allocated_bytes < 3 << 16);
+    }
+
+    #[test]
+    fn debug_lists_set_bits() {
+        let bits = SecBitVec::new();
+        for i in [1, 64, 65, 700] {
+            bits.set(i);
+        }
+        assert_eq!(format!("{bits:?}"), "{1, 64, 65, 700}");
+    }
+
+    #[test]
+    fn concurrent_test_and_set_has_one_winner() {
+        let bits = SecBitVec::new();
+        let won = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|_| {
+                    let bits = &bits;
+                    s.spawn(move || (0..10_000).filter(|&i| !bits.test_and_set(i)).count())
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(won, 10_000);
+        assert_eq!(bits.count_ones(), 10_000);
+    }
+
+    #[test]
+    fn concurrent_set_and_clear_of_neighbours() {
+        // Threads own alternating bits of the same words
+        let bits = SecBitVec::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let bits = &bits;
+                s.spawn(move || {
+                    for round in 0..100 {
+                        for i in (t..4096).step_by(4) {
+                            if round % 2 == 0 {
+                                bits.set(i);
+                            } else {
+                                bits.clear(i);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        // Every thread ended with a clearing round
+        assert_eq!(bits.count_ones(), 0);
+    }
+
+    #[test]
+    fn only_setting_bits_allocates() {
+        let bits = SecBitVec::new();
+        let ((), stats) = failing_alloc::measure(|| {
+            assert!(!bits.test(1 << 20));
+            bits.clear(1 << 20);
+            assert_eq!(bits.count_ones(), 0);
+        });
+        assert_eq!(stats.allocations, 0);
+    }
+
+    #[test]
+    fn try_reserve_counts_bits() {
+        // The first bucket holds 8 words
+        let bits = SecBitVec::new();
+        let ((), stats) = failing_alloc::measure(|| bits.try_reserve(8 * 64).unwrap());
+        assert_eq!(stats.allocations, 1);
+        let ((), stats) = failing_alloc::measure(|| bits.set(8 * 64 - 1));
+        assert_eq!(stats.allocations, 0);
+
+        // One more bit needs the next bucket
+        let err = failing_alloc::fail_nth(0, || bits.try_reserve(8 * 64 + 1)).unwrap_err();
+        assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+        assert!(!bits.test(8 * 64));
+        bits.try_reserve(8 * 64 + 1).unwrap();
+        let ((), stats) = failing_alloc::measure(|| bits.set(8 * 64));
+        assert_eq!(stats.allocations, 0);
+        assert_eq!(bits.count_ones(), 2);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn racing_test_and_set() {
+        loom::model(|| {
+            let bits = Arc::new(SecBitVec::new());
+            let bits1 = Arc::clone(&bits);
+            // Both threads race to allocate the bucket, and to set bit 1
+            let t = thread::spawn(move || {
+                bits1.set(0);
+                bits1.test_and_set(1)
+            });
+            let first = !bits.test_and_set(1);
+            let other_first = !t.join().unwrap();
+            assert!(first ^ other_first);
+            assert!(bits.test(0));
+            assert_eq!(bits.count_ones(), 2);
+        });
+    }
+}
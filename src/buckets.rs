@@ -0,0 +1,288 @@
+// Buckets that never move, the storage of the `SecVec`s and of the collections that hand out
+// references
+//
+// Index i lives in bucket `highest_bit(i + FIRST_BUCKET_SIZE) - highest_bit(FIRST_BUCKET_SIZE)`,
+// and bucket b holds `FIRST_BUCKET_SIZE << b` entries. A bucket is allocated the first time it's needed and installed
+// with a CAS, the loser of a race frees its allocation. Nothing is freed or moved until the
+// `Buckets` is dropped, so a reference to an entry is valid for as long as the `Buckets` is.
+extern crate alloc;
+use crate::alloc_error::{TryReserveError, TryReserveErrorKind};
+use crate::allocator::{allocate_zeroed_array, deallocate_array};
+use crate::highest_bit;
+use crate::sync::atomic::{AtomicPtr, Ordering};
+use alloc::boxed::Box;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+
+/// The number of entries in the first bucket.
+/// Must always be a power of 2.
+pub(crate) const FIRST_BUCKET_SIZE: usize = 8;
+
+// Bucket i holds FIRST_BUCKET_SIZE << i entries, so this many are enough to index every
+// entry that fits in the address space
+pub(crate) const BUCKETS: usize = (usize::BITS - highest_bit(FIRST_BUCKET_SIZE) - 1) as usize;
+
+/// An entry in a bucket. Buckets are allocated zeroed, so that has to be an empty entry.
+///
+/// # Safety
+/// All zero bytes must be a valid value of the type, the same one `zeroed` returns
+pub(crate) unsafe trait Zeroable {
+    /// Return an empty entry. Only used under loom and shuttle, whose atomics have to be
+    /// constructed in place.
+    #[cfg_attr(not(any(loom, shuttle)), allow(dead_code))]
+    fn zeroed() -> Self;
+}
+
+/// Return the bucket `index` lives in and its offset in that bucket,
+/// or `None` if it's past the last bucket
+#[inline]
+pub(crate) fn locate(index: usize) -> Option<(usize, usize)> {
+    let pos = index.checked_add(FIRST_BUCKET_SIZE)?;
+    let hibit = highest_bit(pos);
+    // pos >= FIRST_BUCKET_SIZE, so this can't underflow
+    let bucket = (hibit - highest_bit(FIRST_BUCKET_SIZE)) as usize;
+    (bucket < BUCKETS).then_some((bucket, pos ^ (1 << hibit)))
+}
+
+/// Return the number of entries in `bucket`
+#[inline]
+pub(crate) const fn bucket_len(bucket: usize) -> usize {
+    FIRST_BUCKET_SIZE << bucket
+}
+
+pub(crate) struct Buckets<E> {
+    buckets: Box<[AtomicPtr<E>; BUCKETS]>,
+    _boo: PhantomData<E>, // The buckets own their entries
+}
+
+impl<E: Zeroable> Buckets<E> {
+    pub(crate) fn new() -> Self {
+        Self {
+            buckets: Box::new(core::array::from_fn(|_| AtomicPtr::new(ptr::null_mut()))),
+            _boo: PhantomData,
+        }
+    }
+
+    /// Return the entry at `index`, or `None` if its bucket hasn't been allocated
+    #[inline]
+    pub(crate) fn get(&self, index: usize) -> Option<&E> {
+        let (bucket, offset) = locate(index)?;
+        let ptr = self.buckets[bucket].load(Ordering::Acquire);
+        // # Safety
+        // A non-null bucket holds bucket_len(bucket) initialized entries and lives as long as self
+        (!ptr.is_null()).then(|| unsafe { &*ptr.add(offset) })
+    }
+
+    /// Return the entry at `index`, allocating its bucket first if needed
+    pub(crate) fn get_or_allocate(&self, index: usize) -> Result<&E, TryReserveError> {
+        let (bucket, offset) = locate(index).ok_or(TryReserveErrorKind::CapacityOverflow)?;
+        let mut ptr = self.buckets[bucket].load(Ordering::Acquire);
+        if ptr.is_null() {
+            ptr = self.try_allocate_bucket(bucket)?;
+        }
+        // # Safety
+        // Same as in `get`
+        Ok(unsafe { &*ptr.add(offset) })
+    }
+
+    /// Make sure the buckets for indices `0..len` are allocated
+    ///
+    /// Buckets allocated before a failure stay allocated.
+    pub(crate) fn try_reserve(&self, len: usize) -> Result<(), TryReserveError> {
+        let Some(last) = len.checked_sub(1) else {
+            return Ok(());
+        };
+        let (last, _) = locate(last).ok_or(TryReserveErrorKind::CapacityOverflow)?;
+        for bucket in 0..=last {
+            if self.buckets[bucket].load(Ordering::Acquire).is_null() {
+                self.try_allocate_bucket(bucket)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterate over the buckets that are allocated, with the index of their first entry
+    pub(crate) fn allocated(&self) -> impl Iterator<Item = (usize, &[E])> + '_ {
+        self.buckets.iter().enumerate().filter_map(|(bucket, ptr)| {
+            let ptr = ptr.load(Ordering::Acquire);
+            let len = bucket_len(bucket);
+            // # Safety
+            // Same as in `get`
+            (!ptr.is_null()).then(|| {
+                (len - FIRST_BUCKET_SIZE, unsafe {
+                    &*ptr::slice_from_raw_parts(ptr, len)
+                })
+            })
+        })
+    }
+
+    /// Allocate `bucket` and return it, or the one another thread installed first
+    fn try_allocate_bucket(&self, bucket: usize) -> Result<*mut E, TryReserveError> {
+        let len = bucket_len(bucket);
+        let ptr = allocate_zeroed_array::<E>(len)?.as_ptr();
+        #[cfg(any(loom, shuttle))]
+        for i in 0..len {
+            // # Safety
+            // Nobody else can see the bucket until it is CAS'd in
+            unsafe { ptr.add(i).write(E::zeroed()) };
+        }
+
+        match self.buckets[bucket].compare_exchange(
+            ptr::null_mut(),
+            ptr,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Ok(ptr),
+            Err(installed) => {
+                // # Safety
+                // Our bucket was never shared, and it was allocated with this length
+                unsafe {
+                    ptr::drop_in_place(ptr::slice_from_raw_parts_mut(ptr, len));
+                    deallocate_array(NonNull::new_unchecked(ptr), len);
+                }
+                Ok(installed)
+            }
+        }
+    }
+}
+
+impl<E> fmt::Debug for Buckets<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.buckets.iter()).finish()
+    }
+}
+
+impl<E> Drop for Buckets<E> {
+    fn drop(&mut self) {
+        for (bucket, ptr) in self.buckets.iter().enumerate() {
+            let ptr = ptr.load(Ordering::Relaxed);
+            if ptr.is_null() {
+                continue;
+            }
+            let len = bucket_len(bucket);
+            // # Safety
+            // We have &mut self, so nobody else can access the entries anymore.
+            // The bucket isn't null, and it was allocated with this length.
+            unsafe {
+                ptr::drop_in_place(ptr::slice_from_raw_parts_mut(ptr, len));
+                deallocate_array(NonNull::new_unchecked(ptr), len);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    use crate::failing_alloc;
+    use crate::sync::atomic::AtomicU64;
+
+    // # Safety
+    // 0 is all zeroes
+    unsafe impl Zeroable for AtomicU64 {
+        fn zeroed() -> Self {
+            AtomicU64::new(0)
+        }
+    }
+
+    #[test]
+    fn locate_matches_the_secvec_layout() {
+        assert_eq!(locate(0), Some((0, 0)));
+        assert_eq!(locate(7), Some((0, 7)));
+        assert_eq!(locate(8), Some((1, 0)));
+        assert_eq!(locate(23), Some((1, 15)));
+        assert_eq!(locate(24), Some((2, 0)));
+        for bucket in 1..20 {
+            // The buckets before this one hold FIRST_BUCKET_SIZE * (2^bucket - 1) entries
+            let first = bucket_len(bucket) - FIRST_BUCKET_SIZE;
+            assert_eq!(locate(first), Some((bucket, 0)));
+            assert_eq!(
+                locate(first - 1),
+                Some((bucket - 1, bucket_len(bucket - 1) - 1))
+            );
+        }
+    }
+
+    #[test]
+    fn locate_rejects_indices_past_the_last_bucket() {
+        assert_eq!(locate(usize::MAX), None);
+        assert_eq!(locate(usize::MAX - FIRST_BUCKET_SIZE), None);
+        let last = bucket_len(BUCKETS) - FIRST_BUCKET_SIZE - 1;
+        assert_eq!(
+            locate(last),
+            Some((BUCKETS - 1, bucket_len(BUCKETS - 1) - 1))
+        );
+        assert_eq!(locate(last + 1), None);
+    }
+
+    #[test]
+    fn drop_frees_every_bucket() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let buckets = Buckets::<AtomicU64>::new();
+            buckets.try_reserve(1000).unwrap();
+            // One far past the reserved ones, with the buckets in between left empty
+            buckets.get_or_allocate(1 << 20).unwrap();
+        });
+        assert_eq!(stats.live_bytes(), 0, "buckets leaked");
+    }
+
+    #[test]
+    fn try_reserve_keeps_the_buckets_before_a_failure() {
+        // Reserving 1000 entries allocates buckets 0 through 6, fail each of them in turn
+        for n in 0..7 {
+            let buckets = Buckets::<AtomicU64>::new();
+            let err = failing_alloc::fail_nth(n, || buckets.try_reserve(1000)).unwrap_err();
+            assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+            assert_eq!(buckets.allocated().count(), n);
+            buckets.try_reserve(1000).unwrap();
+            assert_eq!(buckets.allocated().count(), 7);
+        }
+    }
+
+    #[test]
+    fn failed_allocation_leaves_the_bucket_empty() {
+        let buckets = Buckets::<AtomicU64>::new();
+        let err =
+            failing_alloc::fail_nth(0, || buckets.get_or_allocate(100).map(drop)).unwrap_err();
+        assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+        assert!(buckets.get(100).is_none());
+        buckets.get_or_allocate(100).unwrap();
+        assert!(buckets.get(100).is_some());
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::model::model;
+    use crate::slot::Slot;
+    extern crate std;
+    use loom::sync::Arc;
+    use loom::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn racing_allocations_install_one_bucket() {
+        model(|| {
+            let buckets = Arc::new(Buckets::<Slot>::new());
+            let handles = (0..2)
+                .map(|_| {
+                    let buckets = Arc::clone(&buckets);
+                    thread::spawn(move || {
+                        buckets.get_or_allocate(0).unwrap() as *const Slot as usize
+                    })
+                })
+                .collect::<Vec<_>>();
+            let seen = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>();
+
+            // Exactly one allocation wins, and both threads see it
+            assert_eq!(seen[0], seen[1]);
+            assert_eq!(seen[0], buckets.get(0).unwrap() as *const Slot as usize);
+        });
+    }
+}
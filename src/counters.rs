@@ -0,0 +1,382 @@
+// A growable array of atomic counters, on the same buckets as `SecBitVec`
+//
+// A counter is one `AtomicU64` holding its value packed like a `SecVec` element, without the tag.
+// Nothing is pushed or popped, so there is no descriptor to complete and no helper that could
+// write an old value back over a newer one: an update is a single read-modify-write of the
+// counter. It's the native `fetch_*` instruction when the operation on the packed bits is the
+// operation on the value (see `ops::Counter`), and a CAS loop otherwise, e.g. for floats.
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::ops::{Arithmetic, Bitwise, Counter};
+use crate::slot;
+use crate::sync::atomic::{AtomicU64, Ordering};
+use core::fmt;
+use core::marker::PhantomData;
+use crossbeam_utils::Backoff;
+
+/// A lock-free array of counters that grows to fit the indices that are updated, such as
+/// per-shard metrics or the bins of a histogram.
+///
+/// Every counter starts at zero and indices that were never updated read as zero, so only
+/// updates allocate. Counters live in the same buckets as a `SecVec`'s and never move.
+///
+/// `SecVec::fetch_add` and friends install a descriptor, so that they are ordered with pushes and
+/// pops, and every update to any index goes through that one descriptor. Here an update is a
+/// single atomic operation on its counter, and updates to different counters don't contend.
+/// ```rust
+/// use unlocked::counters::SecCounters;
+///
+/// let hits = SecCounters::<u64>::new();
+/// hits.fetch_add(3, 1);
+/// assert_eq!(hits.fetch_add(3, 1), 1);
+/// assert_eq!(hits.get(3), 2);
+/// assert_eq!(hits.get(1_000), 0);
+/// ```
+pub struct SecCounters<T> {
+    counters: Buckets<Word>,
+    _boo: PhantomData<T>, // Values are stored packed
+}
+
+struct Word(AtomicU64);
+
+// # Safety
+// A counter at zero is all zeroes
+unsafe impl Zeroable for Word {
+    fn zeroed() -> Self {
+        Self(AtomicU64::new(0))
+    }
+}
+
+impl<T> SecCounters<T>
+where
+    T: Counter + Send + Sync,
+{
+    /// Return a new array with every counter at zero. No buckets are allocated until an update.
+    pub fn new() -> Self {
+        Self {
+            counters: Buckets::new(),
+            _boo: PhantomData,
+        }
+    }
+
+    /// Return the value of counter `index`
+    pub fn get(&self, index: usize) -> T {
+        let bits = self
+            .counters
+            .get(index)
+            .map_or(0, |word| word.0.load(Ordering::Acquire));
+        // # Safety
+        // Counters only hold zeroes, which are a valid T, and values packed from a T
+        unsafe { slot::unpack(bits) }
+    }
+
+    /// Set counter `index` to `val`, growing the array if needed
+    pub fn store(&self, index: usize, val: T) {
+        self.word(index).0.store(slot::pack(val), Ordering::Release);
+    }
+
+    /// Replace counter `index` with `f` applied to it, and return the old value.
+    ///
+    /// `f` may be called more than once if other threads update the counter at the same time.
+    /// ```rust
+    /// # use unlocked::counters::SecCounters;
+    /// let counters = SecCounters::<u32>::new();
+    /// counters.store(0, 3);
+    /// assert_eq!(counters.fetch_update(0, |x| x * 2), 3);
+    /// assert_eq!(counters.get(0), 6);
+    /// ```
+    pub fn fetch_update<F>(&self, index: usize, mut f: F) -> T
+    where
+        F: FnMut(T) -> T,
+    {
+        let word = &self.word(index).0;
+        let backoff = Backoff::new();
+        let mut old = word.load(Ordering::Acquire);
+        loop {
+            // # Safety
+            // Same as in `get`
+            let elem = unsafe { slot::unpack(old) };
+            match word.compare_exchange_weak(
+                old,
+                slot::pack(f(elem)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return elem,
+                Err(current) => old = current,
+            }
+            backoff.spin();
+        }
+    }
+
+    /// Add `val` to counter `index` and return the old value. Integers wrap around on overflow.
+    pub fn fetch_add(&self, index: usize, val: T) -> T
+    where
+        T: Arithmetic,
+    {
+        if T::NATIVE_ADD {
+            self.native(index, val, AtomicU64::fetch_add)
+        } else {
+            self.fetch_update(index, |elem| elem.add(val))
+        }
+    }
+
+    /// Subtract `val` from counter `index` and return the old value.
+    /// Integers wrap around on overflow.
+    pub fn fetch_sub(&self, index: usize, val: T) -> T
+    where
+        T: Arithmetic,
+    {
+        if T::NATIVE_ADD {
+            self.native(index, val, AtomicU64::fetch_sub)
+        } else {
+            self.fetch_update(index, |elem| elem.sub(val))
+        }
+    }
+
+    /// Replace counter `index` with the larger of it and `val` and return the old value
+    /// ```rust
+    /// # use unlocked::counters::SecCounters;
+    /// let peaks = SecCounters::<f64>::new();
+    /// peaks.fetch_max(0, 2.5);
+    /// peaks.fetch_max(0, 1.5);
+    /// assert_eq!(peaks.get(0), 2.5);
+    /// ```
+    pub fn fetch_max(&self, index: usize, val: T) -> T
+    where
+        T: Arithmetic,
+    {
+        if T::NATIVE_ORD {
+            self.native(index, val, AtomicU64::fetch_max)
+        } else {
+            self.fetch_update(index, |elem| elem.max(val))
+        }
+    }
+
+    /// Replace counter `index` with the smaller of it and `val` and return the old value
+    pub fn fetch_min(&self, index: usize, val: T) -> T
+    where
+        T: Arithmetic,
+    {
+        if T::NATIVE_ORD {
+            self.native(index, val, AtomicU64::fetch_min)
+        } else {
+            self.fetch_update(index, |elem| elem.min(val))
+        }
+    }
+
+    /// Bitwise and `val` into counter `index` and return the old value
+    pub fn fetch_and(&self, index: usize, val: T) -> T
+    where
+        T: Bitwise,
+    {
+        if T::NATIVE_BITWISE {
+            self.native(index, val, AtomicU64::fetch_and)
+        } else {
+            self.fetch_update(index, |elem| elem.and(val))
+        }
+    }
+
+    /// Bitwise or `val` into counter `index` and return the old value
+    pub fn fetch_or(&self, index: usize, val: T) -> T
+    where
+        T: Bitwise,
+    {
+        if T::NATIVE_BITWISE {
+            self.native(index, val, AtomicU64::fetch_or)
+        } else {
+            self.fetch_update(index, |elem| elem.or(val))
+        }
+    }
+
+    /// Bitwise xor `val` into counter `index` and return the old value
+    pub fn fetch_xor(&self, index: usize, val: T) -> T
+    where
+        T: Bitwise,
+    {
+        if T::NATIVE_BITWISE {
+            self.native(index, val, AtomicU64::fetch_xor)
+        } else {
+            self.fetch_update(index, |elem| elem.xor(val))
+        }
+    }
+
+    /// Make room for counters `0..len` so that updating them doesn't allocate memory
+    pub fn reserve(&self, len: usize) {
+        handle_reserve(self.try_reserve(len))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    ///
+    /// Buckets allocated before the failure stay part of the array.
+    pub fn try_reserve(&self, len: usize) -> Result<(), TryReserveError> {
+        self.counters.try_reserve(len)
+    }
+
+    /// Return counter `index`, allocating its bucket if needed
+    fn word(&self, index: usize) -> &Word {
+        match self.counters.get_or_allocate(index) {
+            Ok(word) => word,
+            Err(err) => handle_error(err),
+        }
+    }
+
+    /// Apply `op`, an `AtomicU64` operation that gives the same result on the packed bits
+    fn native(&self, index: usize, val: T, op: fn(&AtomicU64, u64, Ordering) -> u64) -> T {
+        let old = op(&self.word(index).0, slot::pack(val), Ordering::AcqRel);
+        // # Safety
+        // Same as in `get`, `Counter` only allows operations whose result is a packed T
+        unsafe { slot::unpack(old) }
+    }
+}
+
+impl<T> Default for SecCounters<T>
+where
+    T: Counter + Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for SecCounters<T>
+where
+    T: Counter + Send + Sync + fmt::Debug,
+{
+    /// Formats the counters that aren't zero as a map from their indices
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counters = self
+            .counters
+            .allocated()
+            .flat_map(|(first, words)| (first..).zip(words))
+            .filter_map(|(index, word)| {
+                let bits = word.0.load(Ordering::Relaxed);
+                // # Safety
+                // Same as in `get`
+                (bits != 0).then(|| (index, unsafe { slot::unpack::<T>(bits) }))
+            });
+        f.debug_map().entries(counters).finish()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate alloc;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::format;
+    use std::thread;
+
+    #[test]
+    fn counters_start_at_zero() {
+        let counters = SecCounters::<i32>::new();
+        let ((), stats) = failing_alloc::measure(|| {
+            assert_eq!(counters.get(0), 0);
+            assert_eq!(counters.get(usize::MAX), 0);
+        });
+        assert_eq!(stats.allocations, 0);
+        assert_eq!(counters.fetch_sub(100, 1), 0);
+        assert_eq!(counters.get(100), -1);
+        assert_eq!(SecCounters::<f32>::new().get(5), 0.0);
+        assert!(!SecCounters::<bool>::new().get(5));
+    }
+
+    #[test]
+    fn native_updates_match_the_operations() {
+        let counters = SecCounters::<u64>::new();
+        counters.store(0, u64::MAX);
+        assert_eq!(counters.fetch_add(0, 2), u64::MAX);
+        assert_eq!(counters.get(0), 1);
+        assert_eq!(counters.fetch_max(0, 7), 1);
+        assert_eq!(counters.fetch_min(0, 3), 7);
+        assert_eq!(counters.fetch_xor(0, 0b110), 3);
+        assert_eq!(counters.get(0), 0b101);
+
+        let flags = SecCounters::<bool>::new();
+        assert!(!flags.fetch_or(1, true));
+        assert!(flags.fetch_xor(1, true));
+        assert!(!flags.get(1));
+    }
+
+    #[test]
+    fn updates_that_arent_native_match_the_operations() {
+        // Adding would carry out of a u8's byte
+        let bytes = SecCounters::<u8>::new();
+        bytes.store(0, u8::MAX);
+        assert_eq!(bytes.fetch_add(0, 2), u8::MAX);
+        assert_eq!(bytes.get(0), 1);
+        assert_eq!(bytes.fetch_max(0, 200), 1);
+        assert_eq!(bytes.get(0), 200);
+
+        // -1 packs to more bits than 1
+        let signed = SecCounters::<i32>::new();
+        signed.store(0, -1);
+        assert_eq!(signed.fetch_max(0, 1), -1);
+        assert_eq!(signed.fetch_min(0, -5), 1);
+        assert_eq!(signed.get(0), -5);
+
+        let floats = SecCounters::<f64>::new();
+        floats.fetch_add(0, 0.5);
+        floats.fetch_add(0, 0.25);
+        assert_eq!(floats.fetch_min(0, 0.5), 0.75);
+        assert_eq!(floats.get(0), 0.5);
+    }
+
+    #[test]
+    fn concurrent_fetch_add_counts_every_increment() {
+        let counters = SecCounters::<u64>::new();
+        let halves = SecCounters::<u32>::new();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    // Spread over several buckets, which the threads race to allocate
+                    for i in 0..1000 {
+                        counters.fetch_add(i % 100, 1);
+                        halves.fetch_add(i % 100, 1);
+                        counters.fetch_max(100 + i % 100, i as u64);
+                    }
+                });
+            }
+        });
+        for i in 0..100 {
+            assert_eq!(counters.get(i), 40);
+            assert_eq!(halves.get(i), 40);
+            assert_eq!(counters.get(100 + i), 900 + i as u64);
+        }
+    }
+
+    #[test]
+    fn debug_lists_counters_that_arent_zero() {
+        let counters = SecCounters::<i8>::new();
+        counters.fetch_add(1, 3);
+        counters.fetch_sub(70, 2);
+        counters.store(5, 0);
+        assert_eq!(format!("{counters:?}"), "{1: 3, 70: -2}");
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn racing_updates_to_a_new_bucket() {
+        loom::model(|| {
+            let counters = Arc::new(SecCounters::<i64>::new());
+            let counters1 = Arc::clone(&counters);
+            // Both threads race to allocate the bucket, one update is native and one a CAS loop
+            let t = thread::spawn(move || counters1.fetch_add(1, 5));
+            let old = counters.fetch_max(1, 3);
+            let added = t.join().unwrap();
+            match (old, added) {
+                (5, 0) => assert_eq!(counters.get(1), 5),
+                (0, 3) => assert_eq!(counters.get(1), 8),
+                other => panic!("the updates weren't atomic: {other:?}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,138 @@
+// An allocator wrapper that counts what goes through it, for leak checks and benchmarks
+use core::alloc::{GlobalAlloc, Layout};
+use core::ops::Sub;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Wraps another allocator and counts allocations, deallocations, and bytes.
+///
+/// Install it as the global allocator to see how much memory the vectors use:
+/// ```rust
+/// # use unlocked::counting_alloc::CountingAlloc;
+/// # use unlocked::sealed::SecVec;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: CountingAlloc<System> = CountingAlloc::new(System);
+///
+/// let (_, stats) = ALLOCATOR.measure(|| {
+///     let sv = SecVec::<u64>::new();
+///     sv.push(1);
+///     sv.pop();
+/// });
+/// assert_eq!(stats.live_bytes(), 0);
+/// ```
+/// The counts are shared by every thread, so anything else allocating at the same
+/// time shows up in a measurement.
+pub struct CountingAlloc<A> {
+    inner: A,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+    allocated_bytes: AtomicUsize,
+    deallocated_bytes: AtomicUsize,
+}
+
+/// A snapshot of the counts from a `CountingAlloc`, or the difference between two of them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Number of successful allocations
+    pub allocations: usize,
+    /// Number of deallocations
+    pub deallocations: usize,
+    /// Total bytes allocated
+    pub allocated_bytes: usize,
+    /// Total bytes freed
+    pub deallocated_bytes: usize,
+}
+
+impl AllocStats {
+    /// Bytes allocated and not freed yet
+    pub fn live_bytes(&self) -> isize {
+        self.allocated_bytes.wrapping_sub(self.deallocated_bytes) as isize
+    }
+}
+
+impl Sub for AllocStats {
+    type Output = AllocStats;
+
+    /// What happened between the snapshot `rhs` and the snapshot `self`
+    fn sub(self, rhs: Self) -> Self::Output {
+        AllocStats {
+            allocations: self.allocations.wrapping_sub(rhs.allocations),
+            deallocations: self.deallocations.wrapping_sub(rhs.deallocations),
+            allocated_bytes: self.allocated_bytes.wrapping_sub(rhs.allocated_bytes),
+            deallocated_bytes: self.deallocated_bytes.wrapping_sub(rhs.deallocated_bytes),
+        }
+    }
+}
+
+impl<A> CountingAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+            allocated_bytes: AtomicUsize::new(0),
+            deallocated_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// The counts so far
+    pub fn stats(&self) -> AllocStats {
+        AllocStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            deallocated_bytes: self.deallocated_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Run `f` and return what was allocated and freed while it ran
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> (R, AllocStats) {
+        let before = self.stats();
+        let result = f();
+        (result, self.stats() - before)
+    }
+
+    fn record_alloc(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn record_dealloc(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.deallocated_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            self.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            self.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.record_dealloc(layout.size());
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = unsafe { self.inner.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            // Counted as freeing the old block and allocating a new one
+            self.record_dealloc(layout.size());
+            self.record_alloc(new_size);
+        }
+        new
+    }
+}
@@ -0,0 +1,702 @@
+// A Chase-Lev work-stealing deque, growing the way the vectors' buckets do
+//
+// The owner pushes and pops at `bottom`, thieves take from `top` with a CAS. The owner only needs
+// a CAS to take the last element, when it may be racing a thief for it. See "Correct and Efficient
+// Work-Stealing for Weak Memory Models" (Lê et al., 2013) for the orderings.
+//
+// The elements live in a ring whose size is a power of 2, starting at `FIRST_BUCKET_SIZE`. When it
+// fills up, the owner copies the elements into a ring twice the size, the same doubling as the
+// buckets, and retires the old one through hazard pointers the way `sealed::SecVec` retires its
+// descriptors. A thief protects the ring before reading from it, so it can't be freed under it.
+//
+// The ring isn't built on `buckets`: `top` and `bottom` only ever grow, so buckets found with
+// `locate` would keep getting bigger and further apart for a deque that never holds more than a
+// few elements. Fixed-size segments retired once drained, like the queue's, would do for the
+// thieves, but the owner pops from the back too, so a segment can empty out from both ends and be
+// refilled by the owner after that. Knowing when no thief can still read from one would take a
+// hazard pointer per segment instead of one per ring. A ring wraps around, and only allocates and
+// copies when it's full. Its memory comes from the same helper as the buckets'.
+//
+// A thief reads an element before its CAS on `top` decides whether it gets to keep it, so the read
+// can race with the owner reusing the slot. The copy is thrown away without being dropped when the
+// CAS fails, and the slot is read and written with volatile operations, like crossbeam-deque does.
+extern crate alloc;
+use crate::alloc_error::{handle_error, TryReserveError, TryReserveErrorKind};
+use crate::allocator::{allocate_zeroed_array, deallocate_array};
+use crate::sync::atomic::{fence, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::MaybeUninit;
+use core::ptr::{self, NonNull};
+use crossbeam_utils::CachePadded;
+use haphazard;
+
+// Setting up hazard pointers
+// This makes sure they all use the same Domain, guaranteeing the protection is valid.
+#[non_exhaustive]
+struct Family;
+type Domain = haphazard::Domain<Family>;
+type HazardPointer<'domain> = crate::sync::HazardPointer<'domain, Family>;
+type HazAtomicPtr<T> = haphazard::AtomicPtr<T, Family>;
+
+/// The size of the first ring. Must be a power of 2.
+/// Small under loom, so that a model can grow it.
+#[cfg(not(loom))]
+const FIRST_RING_SIZE: usize = crate::buckets::FIRST_BUCKET_SIZE;
+#[cfg(loom)]
+const FIRST_RING_SIZE: usize = 2;
+
+/// The owner's end of a lock-free work-stealing deque, such as a worker's task queue in a
+/// scheduler.
+///
+/// The owner pushes and pops elements at the back, last in first out, and [`Stealer`]s take them
+/// from the front, first in first out. The owner is the only one that can push, so `SecDeque` can
+/// be sent to another thread but not shared; stealers can be cloned and shared freely.
+/// ```rust
+/// use unlocked::deque::{SecDeque, Steal};
+/// use std::thread;
+///
+/// let tasks = SecDeque::new();
+/// for i in 0..4 {
+///     tasks.push(i);
+/// }
+/// let stealer = tasks.stealer();
+/// thread::scope(|s| {
+///     // The oldest task is stolen first
+///     s.spawn(|| assert_eq!(stealer.steal(), Steal::Success(0)));
+/// });
+/// // The owner works on the newest one
+/// assert_eq!(tasks.pop(), Some(3));
+/// assert_eq!(tasks.len(), 2);
+/// ```
+pub struct SecDeque<T: Send> {
+    inner: Arc<Inner<T>>,
+    // Only the owner pushes, so it can't be shared
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+/// A handle to steal elements from the front of a [`SecDeque`]
+pub struct Stealer<T: Send> {
+    inner: Arc<Inner<T>>,
+}
+
+/// The result of [`Stealer::steal`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The deque was empty
+    Empty,
+    /// The element at the front of the deque
+    Success(T),
+    /// Another thread took the element first. The deque may or may not be empty now.
+    Retry,
+}
+
+impl<T> Steal<T> {
+    /// Return the stolen element, if there is one
+    pub fn success(self) -> Option<T> {
+        match self {
+            Steal::Success(elem) => Some(elem),
+            _ => None,
+        }
+    }
+}
+
+struct Inner<T> {
+    // The index of the front element, only ever incremented, by a CAS
+    top: CachePadded<AtomicUsize>,
+    // The index after the back element, only written by the owner
+    bottom: CachePadded<AtomicUsize>,
+    ring: CachePadded<HazAtomicPtr<Ring<T>>>,
+    domain: Domain,
+}
+
+type Slot<T> = UnsafeCell<MaybeUninit<T>>;
+
+struct Ring<T> {
+    slots: NonNull<Slot<T>>,
+    // Always a power of 2
+    size: usize,
+}
+
+// # Safety
+// The elements in the ring are only ever moved out of it by whoever claimed their index,
+// never shared
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn try_new_as_ptr(size: usize) -> Result<*mut Self, TryReserveError> {
+        let slots = allocate_zeroed_array(size)?;
+        Ok(Box::into_raw(Box::new(Ring { slots, size })))
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        // # Safety
+        // The size is a power of 2, so the masked index is in the ring
+        unsafe { (*self.slots.as_ptr().add(index & (self.size - 1))).get() }
+    }
+
+    /// # Safety
+    /// Only the owner writes, to an index that no thief can take yet
+    unsafe fn write(&self, index: usize, elem: MaybeUninit<T>) {
+        // # Safety
+        // Guaranteed by the caller
+        unsafe { ptr::write_volatile(self.slot(index), elem) }
+    }
+
+    /// Return a copy of the element at `index`, which may be a stale or torn one if the owner
+    /// reused the slot. It may only be used once the caller claimed `index`.
+    fn read(&self, index: usize) -> MaybeUninit<T> {
+        // # Safety
+        // The slot is in the ring, and a MaybeUninit can hold any bytes
+        unsafe { ptr::read_volatile(self.slot(index)) }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        // The elements are dropped by the deque, or were moved to the next ring
+        // # Safety
+        // The ring was allocated with this size
+        unsafe { deallocate_array(self.slots, self.size) }
+    }
+}
+
+impl<T: Send> SecDeque<T> {
+    /// Return a new, empty deque
+    pub fn new() -> Self {
+        let ring = match Ring::try_new_as_ptr(FIRST_RING_SIZE) {
+            Ok(ring) => ring,
+            Err(err) => handle_error(err),
+        };
+        Self {
+            inner: Arc::new(Inner {
+                top: CachePadded::new(AtomicUsize::new(0)),
+                bottom: CachePadded::new(AtomicUsize::new(0)),
+                // # Safety
+                // Rings come from Box::into_raw, and are only reclaimed by retiring them in
+                // `domain`, or in `drop` once nobody else can use the deque
+                ring: CachePadded::new(unsafe { HazAtomicPtr::new(ring) }),
+                domain: Domain::new(&Family {}),
+            }),
+            _not_sync: PhantomData,
+        }
+    }
+
+    /// Return a handle that steals from this deque
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Add an element to the back of the deque, growing it if it's full
+    pub fn push(&self, elem: T) {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        let top = inner.top.load(Ordering::Acquire);
+        let mut ring = inner.ring.load_ptr();
+        // # Safety
+        // Only the owner replaces the ring, so it can't be retired while we use it
+        if bottom.wrapping_sub(top) >= unsafe { (*ring).size } {
+            ring = self.grow(top, bottom);
+        }
+        // # Safety
+        // Thieves only take indices before `bottom`, and we are the owner
+        unsafe { (*ring).write(bottom, MaybeUninit::new(elem)) };
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Remove and return the element at the back of the deque, or `None` if it's empty
+    pub fn pop(&self) -> Option<T> {
+        let inner = &*self.inner;
+        let bottom = inner.bottom.load(Ordering::Relaxed);
+        if bottom.wrapping_sub(inner.top.load(Ordering::Relaxed)) as isize <= 0 {
+            // Skip the fence when there's nothing to pop
+            return None;
+        }
+        // Claim the back element before looking at what the thieves have taken
+        let bottom = bottom.wrapping_sub(1);
+        inner.bottom.store(bottom, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        let top = inner.top.load(Ordering::Relaxed);
+
+        // The number of elements left after this one
+        let rest = bottom.wrapping_sub(top) as isize;
+        if rest < 0 {
+            // The thieves took everything
+            inner
+                .bottom
+                .store(bottom.wrapping_add(1), Ordering::Relaxed);
+            return None;
+        }
+        // # Safety
+        // Same as in `push`
+        let ring = unsafe { &*inner.ring.load_ptr() };
+        let elem = ring.read(bottom);
+        if rest > 0 {
+            // No thief can reach the element anymore
+            // # Safety
+            // It was written by `push`, and the index is ours
+            return Some(unsafe { elem.assume_init() });
+        }
+        // It's the last element, race the thieves for it
+        let won = inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        inner
+            .bottom
+            .store(bottom.wrapping_add(1), Ordering::Relaxed);
+        // # Safety
+        // Same as above, the CAS gave us the index
+        won.then(|| unsafe { elem.assume_init() })
+    }
+
+    /// Return the number of elements in the deque.
+    /// With concurrent steals this may already be too high when it returns.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move the elements into a ring twice the size and retire the old one, returning the new one
+    #[cold]
+    fn grow(&self, top: usize, bottom: usize) -> *mut Ring<T> {
+        let inner = &*self.inner;
+        // # Safety
+        // Same as in `push`
+        let old = unsafe { &*inner.ring.load_ptr() };
+        let new = old
+            .size
+            .checked_mul(2)
+            .ok_or_else(|| TryReserveError::from(TryReserveErrorKind::CapacityOverflow))
+            .and_then(Ring::try_new_as_ptr);
+        let new = match new {
+            Ok(new) => new,
+            Err(err) => handle_error(err),
+        };
+        let mut index = top;
+        while index != bottom {
+            // Thieves may take some of the elements while we copy them. Their copies in the new
+            // ring stay before `top`, so they're never read as elements again.
+            // # Safety
+            // Nobody else can see the new ring yet
+            unsafe { (*new).write(index, old.read(index)) };
+            index = index.wrapping_add(1);
+        }
+        // # Safety
+        // `new` came from Box::into_raw in `Ring::try_new_as_ptr`
+        let replaced = unsafe { inner.ring.swap_ptr(new) };
+        // # Safety
+        // Only the owner swaps the ring out, so only we retire it. Thieves that are still reading
+        // from it have it protected.
+        unsafe {
+            replaced.expect("ring was null").retire_in(&inner.domain);
+        }
+        new
+    }
+}
+
+impl<T: Send> Stealer<T> {
+    /// Take the element at the front of the deque.
+    ///
+    /// Returns [`Steal::Retry`] if another thief or the owner took it first.
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &*self.inner;
+        let top = inner.top.load(Ordering::Acquire);
+        fence(Ordering::SeqCst);
+        let bottom = inner.bottom.load(Ordering::Acquire);
+        if bottom.wrapping_sub(top) as isize <= 0 {
+            return Steal::Empty;
+        }
+
+        let mut hp = HazardPointer::new_in_domain(&inner.domain);
+        // # Safety
+        // Rings are only retired through `inner.domain`
+        let ring = unsafe { inner.ring.load(&mut hp) }.expect("invalid ptr for ring in steal");
+        // The ring is at least as new as the push of the element at `top`, and a newer ring holds
+        // a copy of it
+        let elem = ring.read(top);
+        if inner
+            .top
+            .compare_exchange(
+                top,
+                top.wrapping_add(1),
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            // Someone else took the element, our copy is forgotten
+            return Steal::Retry;
+        }
+        // # Safety
+        // The CAS gave us the index, so nobody wrote over the element since the push
+        Steal::Success(unsafe { elem.assume_init() })
+    }
+
+    /// Return the number of elements in the deque.
+    /// With concurrent pushes, pops, and steals this may be out of date when it returns.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Inner<T> {
+    fn len(&self) -> usize {
+        let top = self.top.load(Ordering::Acquire);
+        let bottom = self.bottom.load(Ordering::Acquire);
+        // A pop that lost the race for the last element briefly makes this negative
+        (bottom.wrapping_sub(top) as isize).max(0) as usize
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // Retired rings were freed (or are freed when the domain is dropped) without their
+        // elements, the current ring holds the ones that are left
+        let ring = self.ring.load_ptr();
+        let top = self.top.load(Ordering::Relaxed);
+        let bottom = self.bottom.load(Ordering::Relaxed);
+        // # Safety
+        // We have &mut self, so nobody else can use the deque. The ring was never retired,
+        // and elements between `top` and `bottom` were pushed and not taken.
+        unsafe {
+            let mut index = top;
+            while index != bottom {
+                (*ring).read(index).assume_init_drop();
+                index = index.wrapping_add(1);
+            }
+            drop(Box::from_raw(ring));
+        }
+    }
+}
+
+impl<T: Send> Default for SecDeque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: Send> fmt::Debug for SecDeque<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecDeque")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send> fmt::Debug for Stealer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stealer")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn owner_is_last_in_first_out() {
+        let deque = SecDeque::new();
+        assert!(deque.is_empty());
+        assert_eq!(deque.pop(), None);
+        for i in 0..100 {
+            deque.push(i);
+        }
+        assert_eq!(deque.len(), 100);
+        for i in (50..100).rev() {
+            assert_eq!(deque.pop(), Some(i));
+        }
+        for i in 100..200 {
+            deque.push(i);
+        }
+        for i in (100..200).rev().chain((0..50).rev()) {
+            assert_eq!(deque.pop(), Some(i));
+        }
+        assert_eq!(deque.pop(), None);
+        assert!(deque.is_empty());
+    }
+
+    #[test]
+    fn thieves_are_first_in_first_out() {
+        let deque = SecDeque::new();
+        let stealer = deque.stealer();
+        assert_eq!(stealer.steal(), Steal::Empty);
+        for i in 0..100 {
+            deque.push(i);
+        }
+        for i in 0..50 {
+            assert_eq!(stealer.steal(), Steal::Success(i));
+        }
+        // Pushing wraps around the ring before it grows
+        for i in 100..150 {
+            deque.push(i);
+        }
+        assert_eq!(stealer.len(), 100);
+        for i in 50..150 {
+            assert_eq!(stealer.clone().steal(), Steal::Success(i));
+        }
+        assert_eq!(stealer.steal(), Steal::Empty);
+        assert_eq!(deque.pop(), None);
+    }
+
+    #[test]
+    fn pop_and_steal_meet_in_the_middle() {
+        let deque = SecDeque::new();
+        let stealer = deque.stealer();
+        for i in 0..5 {
+            deque.push(i);
+        }
+        assert_eq!(deque.pop(), Some(4));
+        assert_eq!(stealer.steal().success(), Some(0));
+        assert_eq!(deque.pop(), Some(3));
+        assert_eq!(stealer.steal().success(), Some(1));
+        assert_eq!(deque.pop(), Some(2));
+        assert_eq!(stealer.steal().success(), None);
+        assert_eq!(deque.pop(), None);
+        assert_eq!(deque.len(), 0);
+        deque.push(5);
+        assert_eq!(stealer.steal().success(), Some(5));
+    }
+
+    #[test]
+    fn drop_drops_every_element() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let deque = SecDeque::new();
+            let stealer = deque.stealer();
+            for i in 0..1000u32 {
+                deque.push(i.to_string());
+            }
+            for _ in 0..300 {
+                deque.pop();
+                stealer.steal();
+            }
+            drop(deque);
+            // The stealer keeps the elements alive
+            assert_eq!(stealer.len(), 400);
+        });
+        assert_eq!(stats.live_bytes(), 0, "elements or rings leaked");
+    }
+
+    #[test]
+    fn concurrent_steals_take_every_element_once() {
+        let deque = SecDeque::new();
+        let done = AtomicBool::new(false);
+        let (owned, stolen) = thread::scope(|s| {
+            let thieves = (0..3)
+                .map(|_| {
+                    let (stealer, done) = (deque.stealer(), &done);
+                    s.spawn(move || {
+                        let mut stolen = Vec::new();
+                        loop {
+                            match stealer.steal() {
+                                Steal::Success(elem) => stolen.push(elem),
+                                Steal::Retry => {}
+                                Steal::Empty if done.load(Ordering::Acquire) => return stolen,
+                                Steal::Empty => thread::yield_now(),
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            // The owner keeps the deque short, so it races the thieves for the last element
+            let mut owned = Vec::new();
+            for i in 0..20_000u64 {
+                deque.push(i);
+                if i % 3 == 0 {
+                    owned.extend(deque.pop());
+                }
+            }
+            while let Some(elem) = deque.pop() {
+                owned.push(elem);
+            }
+            done.store(true, Ordering::Release);
+            let stolen = thieves
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>();
+            (owned, stolen)
+        });
+        let all = owned.iter().chain(&stolen).collect::<HashSet<_>>();
+        assert_eq!(
+            all.len(),
+            owned.len() + stolen.len(),
+            "an element was taken twice"
+        );
+        assert_eq!(all.len(), 20_000);
+    }
+
+    #[test]
+    fn steals_during_growth_free_everything() {
+        // The owner is thread 0, the others steal. Only the owner ever locks it.
+        struct Shared {
+            owner: Mutex<SecDeque<String>>,
+            stealer: Stealer<String>,
+            stolen: AtomicUsize,
+        }
+        failing_alloc::assert_no_leaks_across_threads(
+            || {
+                let deque = SecDeque::new();
+                Shared {
+                    stealer: deque.stealer(),
+                    owner: Mutex::new(deque),
+                    stolen: AtomicUsize::new(0),
+                }
+            },
+            3,
+            |shared, t| {
+                if t == 0 {
+                    let deque = shared.owner.lock().unwrap();
+                    for i in 0..10_000u32 {
+                        deque.push(i.to_string());
+                    }
+                    return;
+                }
+                for _ in 0..5000 {
+                    // Stolen strings are freed on this thread, but allocated by the owner
+                    if let Steal::Success(elem) = shared.stealer.steal() {
+                        assert!(elem.parse::<u32>().unwrap() < 10_000);
+                        shared.stolen.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            },
+            |shared| {
+                let stolen = shared.stolen.load(Ordering::Relaxed);
+                assert_eq!(shared.stealer.len(), 10_000 - stolen);
+            },
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::model::model;
+    use loom::thread;
+
+    #[test]
+    fn pop_races_steal_for_the_last_element() {
+        model(|| {
+            let deque = SecDeque::new();
+            deque.push(1);
+            let stealer = deque.stealer();
+            let t = thread::spawn(move || stealer.steal());
+            let popped = deque.pop();
+            match t.join().unwrap() {
+                Steal::Success(stolen) => {
+                    assert_eq!(stolen, 1);
+                    assert_eq!(popped, None);
+                }
+                // The thief either came too late, or lost the CAS to the pop
+                Steal::Empty | Steal::Retry => assert_eq!(popped, Some(1)),
+            }
+            assert!(deque.is_empty());
+        });
+    }
+
+    #[test]
+    fn steal_while_the_ring_grows() {
+        model(|| {
+            // The first ring holds two, the third push moves the elements to a new one
+            let deque = SecDeque::new();
+            deque.push(0);
+            deque.push(1);
+            let stealer = deque.stealer();
+            let t = thread::spawn(move || stealer.steal().success());
+            deque.push(2);
+            let stolen = t.join().unwrap();
+            let rest = (deque.pop(), deque.pop(), deque.pop());
+            match stolen {
+                Some(0) => assert_eq!(rest, (Some(2), Some(1), None)),
+                _ => {
+                    assert_eq!(stolen, None);
+                    assert_eq!(rest, (Some(2), Some(1), Some(0)));
+                }
+            }
+        });
+    }
+}
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use super::*;
+    use crate::model::check;
+    extern crate std;
+    use shuttle::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn every_element_is_taken_once() {
+        check(|| {
+            let deque = SecDeque::new();
+            let thieves = (0..2)
+                .map(|_| {
+                    let stealer = deque.stealer();
+                    thread::spawn(move || {
+                        let mut stolen = Vec::new();
+                        for _ in 0..30 {
+                            if let Steal::Success(elem) = stealer.steal() {
+                                // Thieves see the elements in the order they were pushed
+                                assert!(stolen.last() < Some(&elem));
+                                stolen.push(elem);
+                            }
+                        }
+                        stolen
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut all = Vec::new();
+            for i in 0..40 {
+                deque.push(i);
+                if i % 4 == 0 {
+                    all.extend(deque.pop());
+                }
+            }
+            for thief in thieves {
+                all.extend(thief.join().unwrap());
+            }
+            while let Some(elem) = deque.pop() {
+                all.push(elem);
+            }
+            all.sort_unstable();
+            assert_eq!(all, (0..40).collect::<Vec<_>>());
+        });
+    }
+}
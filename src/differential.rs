@@ -0,0 +1,106 @@
+// Differential tests: run generated sequences of operations against every SecVec and a `Vec`,
+// and check that they always agree. proptest shrinks failing sequences down to a minimal one.
+//
+// Sequences start from a vector that was filled right up to (or one off) a bucket boundary,
+// since that is where the `highest_bit` index math is most likely to be off by one.
+extern crate std;
+use crate::sealed::FIRST_BUCKET_SIZE;
+use crate::ConcurrentStack;
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+use std::vec::Vec;
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Push(u64),
+    Pop,
+    Reserve(usize),
+    Size,
+    Read(usize),
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => any::<u64>().prop_map(Op::Push),
+        2 => Just(Op::Pop),
+        1 => boundary().prop_map(Op::Reserve),
+        1 => Just(Op::Size),
+        1 => (0..1100usize).prop_map(Op::Read),
+    ]
+}
+
+/// A size at, or one off, the start of a bucket
+///
+/// Bucket `n` starts at index `FIRST_BUCKET_SIZE * (2^n - 1)`, which is where
+/// `index + FIRST_BUCKET_SIZE` reaches a new power of two.
+fn boundary() -> impl Strategy<Value = usize> {
+    (0..8u32, -1..=1isize).prop_map(|(bucket, offset)| {
+        (FIRST_BUCKET_SIZE * ((1 << bucket) - 1)).saturating_add_signed(offset)
+    })
+}
+
+fn ops() -> impl Strategy<Value = (usize, Vec<Op>)> {
+    (boundary(), prop::collection::vec(op(), 0..100))
+}
+
+/// Run `ops` on a fresh `S` and a `Vec`, both filled with `prefill` elements first.
+/// `read` is only passed for the vectors that can read by index.
+fn agrees_with_vec<S: ConcurrentStack<u64> + Default>(
+    prefill: usize,
+    ops: Vec<Op>,
+    read: Option<fn(&S, usize) -> Option<u64>>,
+) -> Result<(), TestCaseError> {
+    let sv = S::default();
+    let mut model = Vec::new();
+    for i in 0..prefill as u64 {
+        sv.push(i);
+        model.push(i);
+    }
+    prop_assert_eq!(sv.size(), model.len());
+
+    for op in ops {
+        match op {
+            Op::Push(elem) => {
+                sv.push(elem);
+                model.push(elem);
+            }
+            Op::Pop => prop_assert_eq!(sv.pop(), model.pop()),
+            Op::Reserve(size) => sv.reserve(size),
+            Op::Size => prop_assert_eq!(sv.size(), model.len()),
+            Op::Read(index) => {
+                if let Some(read) = read {
+                    prop_assert_eq!(read(&sv, index), model.get(index).copied());
+                }
+            }
+        }
+    }
+
+    while let Some(elem) = model.pop() {
+        prop_assert_eq!(sv.pop(), Some(elem));
+    }
+    prop_assert_eq!(sv.pop(), None);
+    prop_assert_eq!(sv.size(), 0);
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn leaky_agrees_with_vec((prefill, ops) in ops()) {
+        agrees_with_vec::<crate::leaky::SecVec<u64>>(prefill, ops, None)?;
+    }
+
+    #[test]
+    fn sealed_agrees_with_vec((prefill, ops) in ops()) {
+        agrees_with_vec(prefill, ops, Some(crate::sealed::SecVec::<u64>::read))?;
+    }
+
+    #[test]
+    fn qsbr_agrees_with_vec((prefill, ops) in ops()) {
+        agrees_with_vec::<crate::qsbr::SecVec<u64>>(prefill, ops, None)?;
+    }
+
+    #[test]
+    fn waitfree_agrees_with_vec((prefill, ops) in ops()) {
+        agrees_with_vec::<crate::waitfree::SecVec<u64>>(prefill, ops, None)?;
+    }
+}
@@ -0,0 +1,204 @@
+// Global allocator for tests that counts allocations and can be told to fail a specific one
+//
+// The countdown and the per-thread counts are thread local, so tests running in parallel
+// don't see each other's allocations or failures.
+extern crate std;
+use crate::counting_alloc::AllocStats;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::Cell;
+use core::ptr;
+use std::alloc::System;
+use std::process::Command;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+#[global_allocator]
+static ALLOCATOR: FailingAlloc = FailingAlloc;
+
+struct FailingAlloc;
+
+std::thread_local! {
+    // Number of allocations left before one fails, if a failure is armed
+    static COUNTDOWN: Cell<Option<usize>> = const { Cell::new(None) };
+    // What this thread allocated and freed
+    static THREAD_STATS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            allocations: 0,
+            deallocations: 0,
+            allocated_bytes: 0,
+            deallocated_bytes: 0,
+        })
+    };
+}
+
+fn should_fail() -> bool {
+    COUNTDOWN
+        .try_with(|countdown| match countdown.get() {
+            Some(0) => {
+                countdown.set(None);
+                true
+            }
+            Some(n) => {
+                countdown.set(Some(n - 1));
+                false
+            }
+            None => false,
+        })
+        .unwrap_or(false)
+}
+
+fn record_alloc(stats: &mut AllocStats, size: usize) {
+    stats.allocations += 1;
+    stats.allocated_bytes += size;
+}
+
+fn record_dealloc(stats: &mut AllocStats, size: usize) {
+    stats.deallocations += 1;
+    stats.deallocated_bytes += size;
+}
+
+fn record(f: impl FnOnce(&mut AllocStats)) {
+    let _ = THREAD_STATS.try_with(|stats| {
+        let mut current = stats.get();
+        f(&mut current);
+        stats.set(current);
+    });
+}
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if should_fail() {
+            return ptr::null_mut();
+        }
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            record(|stats| record_alloc(stats, layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if should_fail() {
+            return ptr::null_mut();
+        }
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            record(|stats| record_alloc(stats, layout.size()));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(|stats| record_dealloc(stats, layout.size()));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if should_fail() {
+            return ptr::null_mut();
+        }
+        let new = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new.is_null() {
+            record(|stats| {
+                record_dealloc(stats, layout.size());
+                record_alloc(stats, new_size);
+            });
+        }
+        new
+    }
+}
+
+/// Run `f` and return what it allocated and freed on this thread
+///
+/// To check a multithreaded test for leaks, measure every thread that takes part and add
+/// up the live bytes, since memory can be allocated on one thread and freed on another.
+pub(crate) fn measure<R>(f: impl FnOnce() -> R) -> (R, AllocStats) {
+    let before = THREAD_STATS.with(Cell::get);
+    let result = f();
+    (result, THREAD_STATS.with(Cell::get) - before)
+}
+
+/// Check that everything `make` and `work` allocate is freed by the time the state `make`
+/// returned is dropped, with `work` running on `threads` threads at once, each given its number.
+/// `check` looks at the state after the threads are done, before it's dropped.
+///
+/// Memory may be allocated on one thread and freed on another, so the live bytes of every thread
+/// are added up. Spawning threads allocates memory that's only freed when they exit, so only
+/// `make`, `work` and the drop are measured.
+pub(crate) fn assert_no_leaks_across_threads<S: Sync>(
+    make: impl FnOnce() -> S,
+    threads: usize,
+    work: impl Fn(&S, usize) + Sync,
+    check: impl FnOnce(&S),
+) {
+    let (state, created) = measure(make);
+    let used: isize = std::thread::scope(|s| {
+        let handles = (0..threads)
+            .map(|t| {
+                let (state, work) = (&state, &work);
+                s.spawn(move || measure(|| work(state, t)).1.live_bytes())
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().map(|h| h.join().unwrap()).sum()
+    });
+    check(&state);
+    let ((), dropped) = measure(|| drop(state));
+    assert_eq!(
+        created.live_bytes() + used + dropped.live_bytes(),
+        0,
+        "memory leaked"
+    );
+}
+
+/// Run `f`, making the `n`th allocation it does on this thread fail (counting from 0)
+pub(crate) fn fail_nth<R>(n: usize, f: impl FnOnce() -> R) -> R {
+    COUNTDOWN.with(|countdown| countdown.set(Some(n)));
+    let result = f();
+    COUNTDOWN.with(|countdown| countdown.set(None));
+    result
+}
+
+const CHILD_ENV: &str = "UNLOCKED_FAIL_ALLOCATION";
+
+/// Check that failing any one of the allocations done by `op` aborts the process cleanly,
+/// through `handle_alloc_error`, instead of crashing or carrying on with a broken vector.
+///
+/// An abort takes the whole process down, so every allocation is failed in its own child
+/// process. The child re-runs `test`, which must be the full name of the calling test,
+/// and fails allocation `n` of `op` on the state returned by `setup`. Once `op` finishes
+/// without reaching allocation `n`, every allocation has been covered.
+pub(crate) fn assert_every_failure_aborts<S>(test: &str, setup: impl Fn() -> S, op: impl Fn(&S)) {
+    if let Ok(n) = std::env::var(CHILD_ENV) {
+        let state = setup();
+        fail_nth(n.parse().unwrap(), || op(&state));
+        return;
+    }
+
+    for n in 0.. {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([test, "--exact", "--nocapture", "--test-threads=1"])
+            .env(CHILD_ENV, n.to_string())
+            .output()
+            .unwrap();
+        if output.status.success() {
+            assert!(n > 0, "{test} never allocated");
+            return;
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            // 6 is SIGABRT
+            assert_eq!(
+                output.status.signal(),
+                Some(6),
+                "failing allocation {n} of {test} didn't abort:\n{stderr}"
+            );
+        }
+        assert!(
+            stderr.contains("memory allocation of"),
+            "failing allocation {n} of {test} didn't go through handle_alloc_error:\n{stderr}"
+        );
+    }
+}
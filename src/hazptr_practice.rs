@@ -45,7 +45,7 @@ where
     }
 
     pub fn load(&self) -> T {
-        let mut hp = HazardPointer::new_in_domain(&self.domain);
+        let mut hp = crate::sync::HazardPointer::new_in_domain(&self.domain);
         // # Safety // Safe because the ptr is only accessed through hazptr mechanisms
         let data = unsafe { self.data.load(&mut hp) };
         *data.unwrap()
@@ -110,37 +110,42 @@ pub fn hazptr_practice_main() {
     std::println!("{}", data.load());
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(any(loom, shuttle))))]
 mod tests {
     use super::*;
+    use crate::failing_alloc;
     extern crate std;
     use std::sync::Arc;
-    use std::thread::{self, JoinHandle};
+    use std::thread;
     use std::vec::Vec;
 
     #[test]
     fn leak() {
-        let data = Arc::new(DataPtr::new(1));
-        #[allow(clippy::needless_collect)]
-        let handles = (0..20)
-            .map(|val| {
-                let adata = Arc::clone(&data);
-                if val % 2 == 0 {
-                    thread::spawn(move || {
-                        for _ in 0..100000 {
-                            adata.store(2)
-                        }
+        // Spawning threads allocates memory that's freed when the thread exits, so only
+        // creating the DataPtr, the work on each thread, and the drop are measured
+        let (data, created) = failing_alloc::measure(|| DataPtr::new(1));
+        let used: isize = thread::scope(|s| {
+            let handles = (0..20)
+                .map(|val| {
+                    let data = &data;
+                    s.spawn(move || {
+                        let ((), stats) = failing_alloc::measure(|| {
+                            for _ in 0..100000 {
+                                if val % 2 == 0 {
+                                    data.store(2)
+                                } else {
+                                    data.load();
+                                }
+                            }
+                        });
+                        stats.live_bytes()
                     })
-                } else {
-                    thread::spawn(move || {
-                        for _ in 0..100000 {
-                            adata.load();
-                        }
-                    })
-                }
-            })
-            .collect::<Vec<JoinHandle<()>>>();
-        handles.into_iter().for_each(|h| h.join().unwrap());
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        let ((), dropped) = failing_alloc::measure(|| drop(data));
+        assert_eq!(created.live_bytes() + used + dropped.live_bytes(), 0);
     }
 
     #[test]
@@ -150,3 +155,36 @@ mod tests {
         thread::spawn(|| hp);
     }
 }
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn load_sees_old_or_new() {
+        loom::model(|| {
+            let data = Arc::new(DataPtr::new(1));
+            let adata = Arc::clone(&data);
+            let t = thread::spawn(move || adata.store(2));
+            let seen = data.load();
+            assert!(seen == 1 || seen == 2);
+            t.join().unwrap();
+            assert_eq!(data.load(), 2);
+        });
+    }
+
+    #[test]
+    fn concurrent_stores() {
+        loom::model(|| {
+            let data = Arc::new(DataPtr::new(0));
+            let adata = Arc::clone(&data);
+            let t = thread::spawn(move || adata.store(1));
+            data.store(2);
+            t.join().unwrap();
+            let seen = data.load();
+            assert!(seen == 1 || seen == 2);
+        });
+    }
+}
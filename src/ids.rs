@@ -0,0 +1,305 @@
+// Dense integer ids: freed ids are kept on a `sealed::SecVec` and handed out again before new ones
+//
+// With double-free detection, a bitmap on the same buckets as `AppendVec` has a bit set for every
+// id that is currently allocated. Freeing clears the bit with one `fetch_and`, so of two threads
+// freeing the same id exactly one sees it set.
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::sealed::SecVec;
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::fmt;
+
+const BITS: usize = usize::BITS as usize;
+
+/// A lock-free allocator of `u32` ids, such as entity or handle ids.
+///
+/// `alloc` returns a freed id if there is one, otherwise the smallest id that was never handed out,
+/// so the ids in use stay dense. `free` makes an id available again.
+/// ```rust
+/// use unlocked::ids::IdAllocator;
+///
+/// let ids = IdAllocator::new();
+/// let (a, b) = (ids.alloc(), ids.alloc());
+/// assert_eq!((a, b), (0, 1));
+/// ids.free(a).unwrap();
+/// assert_eq!(ids.alloc(), a);
+/// assert_eq!(ids.alloc(), 2);
+/// ```
+pub struct IdAllocator {
+    recycled: SecVec<'static, u32>,
+    // The first id that was never handed out, `1 << 32` once they all were
+    next: AtomicU64,
+    // One bit per id, set while it's allocated
+    allocated: Option<Buckets<Word>>,
+}
+
+struct Word(AtomicUsize);
+
+// # Safety
+// A word with no bits set is all zeroes
+unsafe impl Zeroable for Word {
+    fn zeroed() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+}
+
+/// `IdAllocator::free` was called with an id that isn't allocated.
+/// Only detected by allocators made with `with_double_free_detection`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DoubleFree(pub u32);
+
+impl fmt::Display for DoubleFree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "id {} freed while not allocated", self.0)
+    }
+}
+
+impl IdAllocator {
+    /// Return an allocator that hasn't handed out any ids.
+    /// Freeing an id twice hands it out twice.
+    pub fn new() -> Self {
+        Self {
+            recycled: SecVec::new(),
+            next: AtomicU64::new(0),
+            allocated: None,
+        }
+    }
+
+    /// Like `new`, but `free` reports ids that aren't allocated instead of recycling them.
+    /// This costs a bit per id and an atomic operation on it in `alloc` and `free`.
+    /// ```rust
+    /// # use unlocked::ids::{DoubleFree, IdAllocator};
+    /// let ids = IdAllocator::with_double_free_detection();
+    /// let id = ids.alloc();
+    /// assert_eq!(ids.free(id), Ok(()));
+    /// assert_eq!(ids.free(id), Err(DoubleFree(id)));
+    /// assert_eq!(ids.free(100), Err(DoubleFree(100)));
+    /// ```
+    pub fn with_double_free_detection() -> Self {
+        Self {
+            allocated: Some(Buckets::new()),
+            ..Self::new()
+        }
+    }
+
+    /// Return an id that isn't in use.
+    ///
+    /// # Panics
+    /// If every `u32` is in use
+    pub fn alloc(&self) -> u32 {
+        let id = match self.recycled.pop() {
+            Some(id) => id,
+            None => self
+                .next
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                    (next <= u32::MAX.into()).then_some(next + 1)
+                })
+                .map(|next| next as u32)
+                .expect("ran out of ids"),
+        };
+        if let Some(allocated) = &self.allocated {
+            let (word, bit) = Self::bit(id);
+            let word = match allocated.get_or_allocate(word) {
+                Ok(word) => word,
+                Err(err) => handle_error(err),
+            };
+            let old = word.0.fetch_or(bit, Ordering::AcqRel);
+            debug_assert_eq!(old & bit, 0, "id {id} handed out twice");
+        }
+        id
+    }
+
+    /// Make `id` available to `alloc` again.
+    ///
+    /// If the allocator detects double frees and `id` isn't allocated, nothing happens
+    /// and the error is returned. Otherwise this always succeeds.
+    pub fn free(&self, id: u32) -> Result<(), DoubleFree> {
+        if let Some(allocated) = &self.allocated {
+            let (word, bit) = Self::bit(id);
+            let was_allocated = allocated
+                .get(word)
+                .is_some_and(|word| word.0.fetch_and(!bit, Ordering::AcqRel) & bit != 0);
+            if !was_allocated {
+                return Err(DoubleFree(id));
+            }
+        }
+        self.recycled.push(id);
+        Ok(())
+    }
+
+    /// Return whether `id` is allocated, or `None` if the allocator doesn't keep track
+    pub fn is_allocated(&self, id: u32) -> Option<bool> {
+        let allocated = self.allocated.as_ref()?;
+        let (word, bit) = Self::bit(id);
+        Some(
+            allocated
+                .get(word)
+                .is_some_and(|word| word.0.load(Ordering::Acquire) & bit != 0),
+        )
+    }
+
+    /// Make room for `size` ids to be allocated and freed without allocating memory
+    pub fn reserve(&self, size: usize) {
+        handle_reserve(self.try_reserve(size))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    pub fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        self.recycled.try_reserve(size)?;
+        if let Some(allocated) = &self.allocated {
+            allocated.try_reserve(size.div_ceil(BITS))?;
+        }
+        Ok(())
+    }
+
+    /// Return the index of the word `id` is in, and its bit in that word
+    #[inline]
+    fn bit(id: u32) -> (usize, usize) {
+        let id = id as usize;
+        (id / BITS, 1 << (id % BITS))
+    }
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for IdAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdAllocator")
+            .field("next", &self.next.load(Ordering::Relaxed))
+            .field("detects_double_free", &self.allocated.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate alloc;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::vec::Vec;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn new_ids_are_dense() {
+        let ids = IdAllocator::new();
+        for i in 0..100 {
+            assert_eq!(ids.alloc(), i);
+        }
+    }
+
+    #[test]
+    fn freed_ids_are_reused_first() {
+        let ids = IdAllocator::new();
+        let all = (0..10).map(|_| ids.alloc()).collect::<Vec<_>>();
+        for &id in &all[3..6] {
+            ids.free(id).unwrap();
+        }
+        let mut reused = (0..3).map(|_| ids.alloc()).collect::<Vec<_>>();
+        reused.sort_unstable();
+        assert_eq!(reused, [3, 4, 5]);
+        assert_eq!(ids.alloc(), 10);
+    }
+
+    #[test]
+    fn double_free_is_detected() {
+        let ids = IdAllocator::with_double_free_detection();
+        let a = ids.alloc();
+        let b = ids.alloc();
+        assert_eq!(ids.is_allocated(a), Some(true));
+        assert_eq!(ids.free(a), Ok(()));
+        assert_eq!(ids.is_allocated(a), Some(false));
+        assert_eq!(ids.free(a), Err(DoubleFree(a)));
+        // Never allocated, in and past the bitmap's buckets
+        assert_eq!(ids.free(b + 1), Err(DoubleFree(b + 1)));
+        assert_eq!(ids.free(u32::MAX), Err(DoubleFree(u32::MAX)));
+        // Only the first free recycled the id
+        assert_eq!(ids.alloc(), a);
+        assert_eq!(ids.alloc(), b + 1);
+        assert_eq!(IdAllocator::new().is_allocated(a), None);
+    }
+
+    #[test]
+    fn racing_frees_of_the_same_id() {
+        let ids = IdAllocator::with_double_free_detection();
+        let all = (0..1000).map(|_| ids.alloc()).collect::<Vec<_>>();
+        let freed = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|_| {
+                    let (ids, all) = (&ids, &all);
+                    s.spawn(move || all.iter().filter(|&&id| ids.free(id).is_ok()).count())
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(freed, 1000);
+        let reused = (0..1000).map(|_| ids.alloc()).collect::<HashSet<_>>();
+        assert_eq!(reused.len(), 1000);
+        assert_eq!(ids.alloc(), 1000);
+    }
+
+    #[test]
+    fn concurrent_alloc_and_free() {
+        let ids = IdAllocator::with_double_free_detection();
+        let held = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|_| {
+                    let ids = &ids;
+                    s.spawn(move || {
+                        let mut held = Vec::new();
+                        for i in 0..2000 {
+                            let id = ids.alloc();
+                            if i % 2 == 0 {
+                                ids.free(id).unwrap();
+                            } else {
+                                held.push(id);
+                            }
+                        }
+                        held
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let distinct = held.iter().collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), held.len(), "an id was handed out twice");
+        assert!(held.iter().all(|&id| ids.is_allocated(id) == Some(true)));
+    }
+
+    #[test]
+    fn ids_run_out() {
+        let ids = IdAllocator::new();
+        ids.next.store(u32::MAX.into(), Ordering::Relaxed);
+        assert_eq!(ids.alloc(), u32::MAX);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ids.alloc()));
+        assert!(result.is_err());
+        // Freed ids can still be handed out
+        ids.free(3).unwrap();
+        assert_eq!(ids.alloc(), 3);
+    }
+
+    #[test]
+    fn drop_frees_everything() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let ids = IdAllocator::with_double_free_detection();
+            ids.reserve(1000);
+            let all = (0..5000).map(|_| ids.alloc()).collect::<Vec<_>>();
+            for id in all {
+                ids.free(id).unwrap();
+            }
+        });
+        assert_eq!(stats.live_bytes(), 0, "recycled ids or the bitmap leaked");
+    }
+}
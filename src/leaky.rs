@@ -5,21 +5,29 @@
 // https://github.com/rust-lang/rust/issues/43408
 extern crate alloc;
 use crate::alloc_error::{alloc_guard, capacity_overflow};
+use crate::allocator::{Allocator, Global};
 use crate::highest_bit;
-use alloc::alloc::{handle_alloc_error, Allocator, Global, Layout};
+use crate::slot;
+use crate::Element;
+use alloc::alloc::{handle_alloc_error, Layout};
 use alloc::boxed::Box;
 use core::fmt::Debug;
 use core::marker::PhantomData;
 use core::mem;
 use core::ptr::{self, NonNull};
-use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use core::sync::atomic::{AtomicPtr, Ordering};
 use crossbeam_utils::{Backoff, CachePadded};
+use portable_atomic::AtomicU64;
 
 // TODO: use FisrtBucketSize trait (as long as it works with the macro)
 /// The number of elements in the first allocation.
 /// Must always be a power of 2.
 pub const FIRST_BUCKET_SIZE: usize = 8;
 
+// Bucket i holds FIRST_BUCKET_SIZE << i elements, so this many are enough to index every
+// element of a vector that fits in the address space
+const BUCKETS: usize = (usize::BITS - highest_bit(FIRST_BUCKET_SIZE) - 1) as usize;
+
 /// An AtomicPtr containing a null-pointer to an AtomicU64
 #[allow(clippy::declare_interior_mutable_const)] // We actually do want this to be copied
 pub const ATOMIC_NULLPTR: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut::<AtomicU64>());
@@ -27,10 +35,10 @@ pub const ATOMIC_NULLPTR: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut::<
 // TODO: make generic parameter N: the number of buckets
 /// Things to talk about in documentation:
 /// Structure
-/// T: Copy bound because of internal usage of transmute_copy
+/// T: Copy bound because elements are copied in and out of the slots bitwise
 /// Why no lazy allocation
 ///
-/// A lock-free vector over `T: Copy` types that can be safely modified accross thread boundaries.
+/// A lock-free vector over [`Element`] types that can be safely modified accross thread boundaries.
 ///
 /// The vector is an implementation of the algorithm described in the paper _Lock-free Dynamically
 /// Resizable Arrays_ by **Dechev et. al.**, 2006.
@@ -56,8 +64,8 @@ pub const ATOMIC_NULLPTR: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut::<
 /// environment, at least one atomic read and compare_exchange), and would incur overhead on all
 /// subsequent operations.
 ///
-/// The size of the type is two pointers (16 bytes on 64-bit platforms), but the vector allocates 480
-/// bytes of heap memory upfront. Bear this in mind if you are in a memory constrained environment.
+/// The size of the type is two pointers (16 bytes on 64-bit platforms), but the vector allocates one
+/// pointer per bucket (480 bytes on 64-bit platforms) of heap memory upfront. Bear this in mind if you are in a memory constrained environment.
 ///
 /// This vector does not support types larger than usize because it uses atomic instructions internally.
 /// Larger types must be accessed through references/pointers.
@@ -76,7 +84,7 @@ pub struct SecVec<'a, T: Sized> {
     // TODO: are we going to have a false sharing problem?
     // Could use a wrapper type if so
     // See: https://github.com/Amanieu/atomic-rs/blob/master/src/fallback.rs#L21
-    buffers: CachePadded<Box<[AtomicPtr<AtomicU64>; 60]>>,
+    buffers: CachePadded<Box<[AtomicPtr<AtomicU64>; BUCKETS]>>,
     descriptor: CachePadded<AtomicPtr<Descriptor<'a, T>>>,
     // The data is technically stored as usizes, but it's really just transmuted T's
     _boo: PhantomData<T>,
@@ -139,13 +147,13 @@ impl<'a, T> WriteDescriptor<'a, T> {
 
 impl<'a, T> SecVec<'a, T>
 where
-    T: Sized + Copy,
+    T: Sized + Element,
 {
     /// Return of new instance of a SecVec, with capacity 0 and size 0;
     pub fn new() -> Self {
         let pending = WriteDescriptor::<T>::new_none_as_ptr();
         let descriptor = Descriptor::<T>::new_as_ptr(pending, 0, 0);
-        let buffers = Box::new([ATOMIC_NULLPTR; 60]);
+        let buffers = Box::new([ATOMIC_NULLPTR; BUCKETS]);
         Self {
             descriptor: CachePadded::new(AtomicPtr::new(descriptor)),
             buffers: CachePadded::new(buffers),
@@ -250,8 +258,7 @@ where
             // memory previously, so it is pointing into valid memory
             let last_elem = unsafe { &*self.get(current_desc.size) };
             let write_desc = WriteDescriptor::<T>::new_some_as_ptr(
-                // TODO: address this in macro
-                unsafe { mem::transmute_copy::<T, u64>(&elem) }, // SAFE because we know T has correct size
+                slot::pack(elem),
                 last_elem.load(Ordering::Acquire), // Load from the AtomicU64, which really containes the bytes for T
                 last_elem,
             );
@@ -317,10 +324,8 @@ where
             .is_ok()
             {
                 // SAFETY
-                // This is ok because only 64-bit values can be stored in the vector
-                // We also know that elem is a valid T because it was transmuted into a usize
-                // from a valid T, therefore we are only transmuting it back
-                return Some(unsafe { mem::transmute_copy::<u64, T>(&elem) });
+                // Everything in the vector was packed from a valid T by push
+                return Some(unsafe { slot::unpack(elem) });
             }
             backoff.spin();
         }
@@ -437,9 +442,9 @@ where
     /// 1. Calculate the amount of memory needed for the bucket
     /// 2. Allocate the memory
     /// 3. Try to CAS in the pointer from the allocation.
-    /// If the pointer in self.buffers is currently null, we know that it
-    /// has not been initalized with memory, and the CAS will succeed. If
-    /// CAS fails, then we know the bucket has already been initalized.
+    ///    If the pointer in self.buffers is currently null, we know that it
+    ///    has not been initalized with memory, and the CAS will succeed. If
+    ///    CAS fails, then we know the bucket has already been initalized.
     /// 4. If CAS failed, deallocate the memory from Step 2
     fn allocate_bucket(&self, bucket: usize) {
         // The shift-left is equivalent to raising 2 to the power of bucket
@@ -491,7 +496,7 @@ where
 
 impl<'a, T> Default for SecVec<'a, T>
 where
-    T: Copy,
+    T: Element,
 {
     fn default() -> Self {
         Self::new()
@@ -542,32 +547,3 @@ mod tests {
     //     sv.reserve(usize::MAX)
     // }
 }
-#[cfg(not(miri))] // Too slow
-#[cfg(test)]
-mod bench {
-    extern crate std;
-    extern crate test;
-    use super::*;
-    use crate::bench_macros::*;
-
-    crate::bench_macros::unlocked!(100, unlocked_1: 1);
-    unlocked!(100, unlocked_2: 2);
-    unlocked!(100, unlocked_3: 3);
-    unlocked!(100, unlocked_4: 4);
-    unlocked!(100, unlocked_5: 5);
-    unlocked!(100, unlocked_6: 6);
-    unlocked!(100, unlocked_7: 7);
-    unlocked!(100, unlocked_8: 8);
-    unlocked!(100, unlocked_9: 9);
-    unlocked!(100, unlocked_10: 10);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_1: 1);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_2: 2);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_3: 3);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_4: 4);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_5: 5);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_6: 6);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_7: 7);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_8: 8);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_9: 9);
-    mutex_vec_of_ref!(100, mutex_vec_of_ref_10: 10);
-}
@@ -1,7 +1,4 @@
-#![feature(allocator_api)]
-#![feature(try_reserve_kind)] // Might not need this
-#![feature(bench_black_box)]
-#![feature(test)]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 #![no_std]
 
 #[macro_use]
@@ -9,13 +6,96 @@
 #[deny(unsafe_op_in_unsafe_fn)]
 pub mod leaky;
 
+pub(crate) mod allocator;
+
 pub(crate) mod alloc_error;
+pub use alloc_error::{TryReserveError, TryReserveErrorKind};
+
+// Only public so the benchmarks can count allocations, it isn't part of the API
+#[doc(hidden)]
+pub mod counting_alloc;
+
+pub mod stack;
+pub use stack::ConcurrentStack;
+
+pub mod ops;
+
+pub(crate) mod slot;
+pub use slot::Element;
+
+pub(crate) mod sync;
+
+#[cfg(test)]
+pub(crate) mod linearizability;
+
+#[cfg(all(test, any(loom, shuttle)))]
+pub(crate) mod model;
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod differential;
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+pub(crate) mod failing_alloc;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod reclaim;
 
 #[deny(unused_unsafe)]
 #[deny(unsafe_op_in_unsafe_fn)]
 pub mod sealed;
 
-pub(crate) mod bench_macros;
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod qsbr;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod waitfree;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub(crate) mod buckets;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod append;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod slab;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod pool;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod ids;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod bitvec;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod counters;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod queue;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod deque;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod map;
+
+#[deny(unused_unsafe)]
+#[deny(unsafe_op_in_unsafe_fn)]
+pub mod sparse;
 
 pub mod hazptr_practice;
 
@@ -46,7 +126,7 @@ macro_rules! get_impl_type {
         } else if size <= 4 {
             unlocked::vector_impl!($type, u32, AtomicU32)
         } else if size <= 8 {
-            unlocked::vector_impl!($type, usize, AtomicUsize)
+            unlocked::vector_impl!($type, u64, AtomicU64)
         } else {
             panic!(concat!(
                 stringify!($type),
@@ -60,10 +140,11 @@ macro_rules! get_impl_type {
 /// ```
 /// # use unlocked::highest_bit;
 /// let x = 1 << 2;
-/// assert_eq!(highest_bit(x), 2)
+/// assert_eq!(highest_bit(x), 2);
+/// assert_eq!(highest_bit(usize::MAX), usize::BITS - 1);
 /// ```
 #[inline]
-pub fn highest_bit(num: usize) -> u32 {
+pub const fn highest_bit(num: usize) -> u32 {
     // Eliminate a jump/branch by not using if statement
-    (num == 0) as u32 + 63 - num.leading_zeros()
+    (num == 0) as u32 + (usize::BITS - 1) - num.leading_zeros()
 }
@@ -0,0 +1,539 @@
+// Linearizability checking for recorded concurrent histories
+//
+// Threads record every operation they perform on a vector, with a timestamp from a shared clock
+// taken right before the operation is invoked and right after it returns. The history is
+// linearizable if there is a sequential order of the operations that respects those timestamps
+// (an operation that returned before another one was invoked must come first), in which every
+// operation returns what a plain `Vec` would have returned.
+//
+// The search is the one from Wing and Gong, 1993, Testing and Verifying Concurrent Objects,
+// with the memoization from Lowe, 2017, Testing for Linearizability: a state (which operations
+// have been linearized, what the `Vec` looks like) that has already been explored is never
+// explored again.
+extern crate std;
+use crate::ops::Arithmetic;
+use core::fmt;
+use core::hash::Hash;
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashSet;
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+/// An operation on the vector, with its argument
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Op<T> {
+    Push(T),
+    Pop,
+    Size,
+    Read(usize),
+    FetchAdd(usize, T),
+}
+
+/// What an operation returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Ret<T> {
+    Pushed,
+    Value(Option<T>),
+    Size(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry<T> {
+    thread: usize,
+    op: Op<T>,
+    /// `None` if the operation hadn't returned yet, then it may or may not have taken effect
+    ret: Option<Ret<T>>,
+    call: u64,
+    ret_at: u64,
+}
+
+impl<T> Entry<T>
+where
+    T: Arithmetic + Eq,
+{
+    /// Apply the operation to the model, returning whether it returned the same thing
+    fn step(&self, model: &mut Vec<T>) -> bool {
+        let ret = match self.op {
+            Op::Push(elem) => {
+                model.push(elem);
+                Ret::Pushed
+            }
+            Op::Pop => Ret::Value(model.pop()),
+            Op::Size => Ret::Size(model.len()),
+            Op::Read(index) => Ret::Value(model.get(index).copied()),
+            Op::FetchAdd(index, val) => Ret::Value(model.get_mut(index).map(|elem| {
+                let old = *elem;
+                *elem = old.add(val);
+                old
+            })),
+        };
+        self.ret.is_none_or(|r| r == ret)
+    }
+
+    /// Whether the operation leaves the vector unchanged, whatever happens
+    fn is_observer(&self) -> bool {
+        matches!(
+            (self.op, self.ret),
+            (Op::Size, _)
+                | (Op::Read(_), _)
+                | (Op::Pop, Some(Ret::Value(None)))
+                | (Op::FetchAdd(..), Some(Ret::Value(None)))
+        )
+    }
+}
+
+/// The clock every thread takes its timestamps from
+pub(crate) struct Recorder {
+    clock: AtomicU64,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Self {
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    /// Start the log for one thread
+    pub(crate) fn log<T>(&self, thread: usize) -> ThreadLog<'_, T> {
+        ThreadLog {
+            recorder: self,
+            thread,
+            entries: Vec::new(),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// The operations one thread performed, in order
+pub(crate) struct ThreadLog<'r, T> {
+    recorder: &'r Recorder,
+    thread: usize,
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> ThreadLog<'_, T> {
+    fn record(&mut self, op: Op<T>, f: impl FnOnce() -> Ret<T>) -> Ret<T>
+    where
+        T: Copy,
+    {
+        let call = self.recorder.tick();
+        let ret = f();
+        let ret_at = self.recorder.tick();
+        self.entries.push(Entry {
+            thread: self.thread,
+            op,
+            ret: Some(ret),
+            call,
+            ret_at,
+        });
+        ret
+    }
+
+    pub(crate) fn push(&mut self, elem: T, f: impl FnOnce(T))
+    where
+        T: Copy,
+    {
+        self.record(Op::Push(elem), || {
+            f(elem);
+            Ret::Pushed
+        });
+    }
+
+    pub(crate) fn pop(&mut self, f: impl FnOnce() -> Option<T>) -> Option<T>
+    where
+        T: Copy,
+    {
+        match self.record(Op::Pop, || Ret::Value(f())) {
+            Ret::Value(popped) => popped,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn size(&mut self, f: impl FnOnce() -> usize) -> usize
+    where
+        T: Copy,
+    {
+        match self.record(Op::Size, || Ret::Size(f())) {
+            Ret::Size(size) => size,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn read(&mut self, index: usize, f: impl FnOnce(usize) -> Option<T>) -> Option<T>
+    where
+        T: Copy,
+    {
+        match self.record(Op::Read(index), || Ret::Value(f(index))) {
+            Ret::Value(read) => read,
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn fetch_add(
+        &mut self,
+        index: usize,
+        val: T,
+        f: impl FnOnce(usize, T) -> Option<T>,
+    ) -> Option<T>
+    where
+        T: Copy,
+    {
+        match self.record(Op::FetchAdd(index, val), || Ret::Value(f(index, val))) {
+            Ret::Value(old) => old,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Everything all the threads did
+pub(crate) struct History<T> {
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> History<T>
+where
+    T: Arithmetic + Eq + Hash + fmt::Debug,
+{
+    pub(crate) fn new<'r>(logs: impl IntoIterator<Item = ThreadLog<'r, T>>) -> Self {
+        let mut entries = logs
+            .into_iter()
+            .flat_map(|log| log.entries)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.call);
+        Self { entries }
+    }
+
+    /// Panic with a minimal counterexample if the history isn't linearizable
+    pub(crate) fn assert_linearizable(&self) {
+        if linearizable(&self.entries) {
+            return;
+        }
+        panic!(
+            "history is not linearizable, minimal counterexample:\n{}",
+            format_entries(&minimize(self.entries.clone()))
+        );
+    }
+}
+
+impl<T> fmt::Debug for History<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_entries(&self.entries))
+    }
+}
+
+fn format_entries<T: fmt::Debug>(entries: &[Entry<T>]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            let op = match &entry.op {
+                Op::Push(elem) => format!("push({elem:?})"),
+                Op::Pop => String::from("pop()"),
+                Op::Size => String::from("size()"),
+                Op::Read(index) => format!("read({index})"),
+                Op::FetchAdd(index, val) => format!("fetch_add({index}, {val:?})"),
+            };
+            let ret = match &entry.ret {
+                Some(Ret::Pushed) => String::new(),
+                Some(Ret::Value(value)) => format!(" -> {value:?}"),
+                Some(Ret::Size(size)) => format!(" -> {size}"),
+                None => String::from(" -> (hadn't returned)"),
+            };
+            let ret_at = match entry.ret {
+                Some(_) => format!("{:>6}", entry.ret_at),
+                None => String::from("     -"),
+            };
+            format!(
+                "thread {:>2} [{:>6}, {ret_at}] {op}{ret}\n",
+                entry.thread, entry.call
+            )
+        })
+        .collect()
+}
+
+/// Wing-Gong search with Lowe's memoization. `entries` must be sorted by invocation.
+///
+/// Walks a list of all the invocations and returns in time order. At an invocation, try to
+/// linearize that operation next, take it out of the list and start over from the front. At a
+/// return, that operation should have been linearized already, so undo the last choice instead.
+fn linearizable<T>(entries: &[Entry<T>]) -> bool
+where
+    T: Arithmetic + Eq + Hash,
+{
+    // Event 2 * i is the invocation of entries[i], event 2 * i + 1 is its return.
+    // They are doubly linked in time order, after a sentinel.
+    let sentinel = 2 * entries.len();
+    let mut events = (0..sentinel).collect::<Vec<_>>();
+    events.sort_by_key(|&event| match event % 2 {
+        0 => entries[event / 2].call,
+        _ => entries[event / 2].ret_at,
+    });
+    let mut links = std::vec![Link { prev: NIL, next: NIL }; sentinel + 1];
+    let mut last = sentinel;
+    for event in events {
+        links[last].next = event;
+        links[event].prev = last;
+        last = event;
+    }
+
+    let mut linearized = std::vec![0u64; entries.len().div_ceil(64)];
+    let mut model = Vec::new();
+    let mut seen = HashSet::new();
+    let mut choices = Vec::<(usize, Vec<T>)>::new();
+
+    let mut event = links[sentinel].next;
+    while event != NIL {
+        let i = event / 2;
+        if event.is_multiple_of(2) {
+            let mut stepped = model.clone();
+            if entries[i].step(&mut stepped) {
+                linearized[i / 64] |= 1 << (i % 64);
+                if seen.insert((linearized.clone(), stepped.clone())) {
+                    choices.push((i, mem::replace(&mut model, stepped)));
+                    unlink(&mut links, 2 * i);
+                    unlink(&mut links, 2 * i + 1);
+                    event = links[sentinel].next;
+                    continue;
+                }
+                linearized[i / 64] &= !(1 << (i % 64));
+            }
+            event = links[event].next;
+        } else if entries[i].ret.is_none() {
+            // Returns are in time order and these come last, so everything that did return has
+            // been linearized. Operations that never returned don't have to be.
+            return true;
+        } else {
+            let Some((i, previous)) = choices.pop() else {
+                return false;
+            };
+            model = previous;
+            linearized[i / 64] &= !(1 << (i % 64));
+            relink(&mut links, 2 * i + 1);
+            relink(&mut links, 2 * i);
+            event = links[2 * i].next;
+        }
+    }
+    true
+}
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct Link {
+    prev: usize,
+    next: usize,
+}
+
+fn unlink(links: &mut [Link], event: usize) {
+    let Link { prev, next } = links[event];
+    links[prev].next = next;
+    if next != NIL {
+        links[next].prev = prev;
+    }
+}
+
+/// Undo `unlink`, events have to be relinked in the opposite order they were unlinked in
+fn relink(links: &mut [Link], event: usize) {
+    let Link { prev, next } = links[event];
+    links[prev].next = event;
+    if next != NIL {
+        links[next].prev = event;
+    }
+}
+
+/// Shrink a history that isn't linearizable, keeping it not linearizable.
+///
+/// First cut it down to its shortest prefix that can't be linearized, then drop any operation
+/// that doesn't change the vector and isn't needed for the failure. Dropping an operation that
+/// does change the vector isn't sound, since a later operation might have observed it.
+fn minimize<T>(mut entries: Vec<Entry<T>>) -> Vec<Entry<T>>
+where
+    T: Arithmetic + Eq + Hash,
+{
+    // A prefix ends at some return. Operations that hadn't returned by then lose their return
+    // value, they might or might not have taken effect.
+    let mut returns = entries.iter().map(|entry| entry.ret_at).collect::<Vec<_>>();
+    returns.sort_unstable();
+    let prefix = |end: u64| {
+        entries
+            .iter()
+            .filter(|entry| entry.call <= end)
+            .map(|&entry| match entry.ret_at <= end {
+                true => entry,
+                false => Entry {
+                    ret: None,
+                    ret_at: u64::MAX,
+                    ..entry
+                },
+            })
+            .collect::<Vec<_>>()
+    };
+    if let Some(&end) = returns.iter().find(|&&end| !linearizable(&prefix(end))) {
+        entries = prefix(end);
+    }
+
+    let mut i = 0;
+    while i < entries.len() {
+        if entries[i].is_observer() {
+            let mut without = entries.clone();
+            without.remove(i);
+            if !linearizable(&without) {
+                entries = without;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn entry(thread: usize, op: Op<u64>, ret: Ret<u64>, call: u64, ret_at: u64) -> Entry<u64> {
+        Entry {
+            thread,
+            op,
+            ret: Some(ret),
+            call,
+            ret_at,
+        }
+    }
+
+    #[test]
+    fn sequential_history_is_linearizable() {
+        let recorder = Recorder::new();
+        let mut log = recorder.log(0);
+        let mut model = Vec::new();
+        log.push(1, |elem| model.push(elem));
+        log.push(2, |elem| model.push(elem));
+        log.read(0, |index| model.get(index).copied());
+        log.size(|| model.len());
+        log.fetch_add(1, 5, |index, val| {
+            let old = model[index];
+            model[index] = old + val;
+            Some(old)
+        });
+        log.read(1, |index| model.get(index).copied());
+        log.pop(|| model.pop());
+        History::new([log]).assert_linearizable();
+    }
+
+    #[test]
+    fn overlapping_operations_can_be_reordered() {
+        // The pop returns the element that was pushed concurrently
+        let entries = [
+            entry(0, Op::Pop, Ret::Value(Some(1)), 0, 3),
+            entry(1, Op::Push(1), Ret::Pushed, 1, 2),
+            entry(1, Op::Size, Ret::Size(0), 4, 5),
+        ];
+        assert!(linearizable(&entries));
+    }
+
+    #[test]
+    fn pop_of_value_never_pushed_is_not_linearizable() {
+        let entries = [
+            entry(0, Op::Push(1), Ret::Pushed, 0, 1),
+            entry(1, Op::Pop, Ret::Value(Some(2)), 2, 3),
+        ];
+        assert!(!linearizable(&entries));
+    }
+
+    #[test]
+    fn stale_size_is_not_linearizable() {
+        // The size was taken after the push returned, so it can't miss it
+        let entries = [
+            entry(0, Op::Push(1), Ret::Pushed, 0, 1),
+            entry(1, Op::Size, Ret::Size(0), 2, 3),
+        ];
+        assert!(!linearizable(&entries));
+    }
+
+    #[test]
+    fn lost_update_is_not_linearizable() {
+        // Both adds saw 0, so one of them was overwritten by the other
+        let entries = [
+            entry(0, Op::Push(0), Ret::Pushed, 0, 1),
+            entry(0, Op::FetchAdd(0, 1), Ret::Value(Some(0)), 2, 5),
+            entry(1, Op::FetchAdd(0, 1), Ret::Value(Some(0)), 3, 4),
+        ];
+        assert!(!linearizable(&entries));
+    }
+
+    #[test]
+    fn operations_that_never_returned_are_optional() {
+        let pending = |op| Entry {
+            ret: None,
+            ret_at: u64::MAX,
+            ..entry(0, op, Ret::Pushed, 0, 0)
+        };
+        // Observed by the pop
+        let entries = [
+            pending(Op::Push(1)),
+            entry(1, Op::Pop, Ret::Value(Some(1)), 1, 2),
+        ];
+        assert!(linearizable(&entries));
+        // Not observed by the size
+        let entries = [pending(Op::Push(1)), entry(1, Op::Size, Ret::Size(0), 1, 2)];
+        assert!(linearizable(&entries));
+    }
+
+    #[test]
+    fn minimize_keeps_only_what_fails() {
+        let entries = Vec::from([
+            entry(0, Op::Push(1), Ret::Pushed, 0, 1),
+            entry(1, Op::Size, Ret::Size(1), 2, 3),
+            entry(0, Op::Read(0), Ret::Value(Some(2)), 4, 5),
+            entry(1, Op::Push(3), Ret::Pushed, 6, 7),
+            entry(0, Op::Pop, Ret::Value(Some(3)), 8, 9),
+        ]);
+        let minimal = minimize(entries);
+        assert_eq!(minimal.len(), 2);
+        assert_eq!(minimal[0].op, Op::Push(1));
+        assert_eq!(minimal[1].op, Op::Read(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "history is not linearizable")]
+    fn assert_linearizable_panics() {
+        let recorder = Recorder::new();
+        let mut log = recorder.log(0);
+        log.push(1, |_| {});
+        log.pop(|| None);
+        History::new([log]).assert_linearizable();
+    }
+
+    #[test]
+    fn records_from_many_threads() {
+        let recorder = Recorder::new();
+        let logs = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|thread| {
+                    let recorder = &recorder;
+                    s.spawn(move || {
+                        let mut log = recorder.log::<u64>(thread);
+                        log.size(|| 0);
+                        log
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        let history = History::new(logs);
+        assert_eq!(history.entries.len(), 4);
+        history.assert_linearizable();
+    }
+}
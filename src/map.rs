@@ -0,0 +1,980 @@
+// A lock-free hash map: a split-ordered list (Shalev & Shavit, 2006) with a directory on the same
+// buckets as the vectors
+//
+// Every entry is in one linked list, sorted by its hash with the bits reversed. The table has a
+// power of 2 number of buckets, and each bucket starts with a dummy node in the list, so a bucket's
+// entries are the ones between its dummy and the next. When the table doubles, bucket b's entries
+// are split between b and b + size, and in bit-reversed order those are already next to each
+// other: growing is just bumping `size`, and the new bucket's dummy is inserted into the list the
+// first time it's used. Nothing ever moves, so the directory of pointers to the dummies grows on
+// `Buckets`, like `SecBitVec`'s words.
+//
+// Removal is Michael's (2002): an entry is marked deleted by tagging its `next` pointer, then
+// unlinked by whichever thread gets there first and retired through hazard pointers, the way
+// `sealed::SecVec` retires its descriptors. Dummies are never removed, so they don't need
+// protecting.
+extern crate alloc;
+use crate::alloc_error::handle_error;
+use crate::buckets::{Buckets, Zeroable};
+use crate::highest_bit;
+use crate::sync::atomic::{fence, AtomicPtr, AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use core::borrow::Borrow;
+use core::fmt;
+use core::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use crossbeam_utils::CachePadded;
+use haphazard;
+
+// Setting up hazard pointers
+// This makes sure they all use the same Domain, guaranteeing the protection is valid.
+#[non_exhaustive]
+struct Family;
+type Domain = haphazard::Domain<Family>;
+type HazardPointer<'domain> = crate::sync::HazardPointer<'domain, Family>;
+
+/// The table doubles when it has more than this many entries per bucket
+const LOAD_FACTOR: usize = 2;
+
+/// The tag on a node's `next` pointer once the node is removed
+const DELETED: usize = 1;
+
+/// A lock-free hash map, such as a cache or an index shared between threads.
+///
+/// `get` and `remove` return clones of the values, since other threads may be reading the
+/// entry at the same time. Wrap large values in an `Arc`.
+///
+/// The default hasher is fast but not resistant to collision attacks, use
+/// [`SecMap::with_hasher`] with `std`'s `RandomState` for keys that come from untrusted input.
+/// ```rust
+/// use unlocked::map::SecMap;
+/// use std::thread;
+///
+/// let map = SecMap::new();
+/// thread::scope(|s| {
+///     for t in 0..4 {
+///         let map = &map;
+///         s.spawn(move || {
+///             for i in 0..100 {
+///                 map.insert(t * 100 + i, i);
+///             }
+///         });
+///     }
+/// });
+/// assert_eq!(map.len(), 400);
+/// assert_eq!(map.get(&205), Some(5));
+/// assert_eq!(map.remove(&205), Some(5));
+/// assert_eq!(map.get(&205), None);
+/// ```
+pub struct SecMap<K, V, S = DefaultHashBuilder> {
+    // The dummy node of each bucket that has been used, bucket 0's is the head of the list
+    heads: Buckets<Head<K, V>>,
+    // The number of buckets, always a power of 2
+    size: CachePadded<AtomicUsize>,
+    len: CachePadded<AtomicUsize>,
+    hasher: S,
+    domain: Domain,
+    _boo: PhantomData<Box<Node<K, V>>>, // The map owns its nodes
+}
+
+struct Node<K, V> {
+    // The hash with the bits reversed, odd for entries and even for dummies
+    order: u64,
+    // `None` for dummies
+    entry: Option<(K, V)>,
+    // Tagged with `DELETED` once the node is removed
+    next: AtomicPtr<Node<K, V>>,
+}
+
+struct Head<K, V>(AtomicPtr<Node<K, V>>);
+
+// # Safety
+// A bucket without a dummy is a null pointer
+unsafe impl<K, V> Zeroable for Head<K, V> {
+    fn zeroed() -> Self {
+        Self(AtomicPtr::new(ptr::null_mut()))
+    }
+}
+
+// # Safety
+// Keys and values are read through shared references from any thread, and dropped by whichever
+// thread removes them or frees the retired node
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Send> Send for SecMap<K, V, S> {}
+unsafe impl<K: Send + Sync, V: Send + Sync, S: Sync> Sync for SecMap<K, V, S> {}
+
+/// The hazard pointers a search through the list holds on to
+struct Guards<'domain> {
+    prev: HazardPointer<'domain>,
+    cur: HazardPointer<'domain>,
+}
+
+impl<'domain> Guards<'domain> {
+    fn new(domain: &'domain Domain) -> Self {
+        Self {
+            prev: HazardPointer::new_in_domain(domain),
+            cur: HazardPointer::new_in_domain(domain),
+        }
+    }
+}
+
+/// Where a search stopped: `cur` is the first node at or after the one searched for (or null at
+/// the end of the list), and `prev` is the link to it
+struct Position<K, V> {
+    found: bool,
+    prev: *const AtomicPtr<Node<K, V>>,
+    cur: *mut Node<K, V>,
+}
+
+#[inline]
+fn is_deleted<T>(ptr: *mut T) -> bool {
+    ptr.addr() & DELETED != 0
+}
+
+#[inline]
+fn untagged<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !DELETED)
+}
+
+/// The position in the list of an entry with `hash`. The top bit is set so that it's odd.
+#[inline]
+fn entry_order(hash: u64) -> u64 {
+    (hash | 1 << 63).reverse_bits()
+}
+
+/// The position in the list of `bucket`'s dummy, before every entry in the bucket
+#[inline]
+fn dummy_order(bucket: usize) -> u64 {
+    (bucket as u64).reverse_bits()
+}
+
+/// Return the bucket that `bucket` was split from
+#[inline]
+fn parent(bucket: usize) -> usize {
+    bucket & !(1 << highest_bit(bucket))
+}
+
+impl<K, V> SecMap<K, V, DefaultHashBuilder> {
+    /// Return a new, empty map with the default hasher
+    pub fn new() -> Self {
+        Self::with_hasher(DefaultHashBuilder::default())
+    }
+}
+
+impl<K, V, S> SecMap<K, V, S> {
+    /// Return a new, empty map that hashes keys with `hasher`
+    /// ```rust
+    /// # use unlocked::map::SecMap;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let map = SecMap::with_hasher(RandomState::new());
+    /// map.insert("key", 1);
+    /// assert_eq!(map.get("key"), Some(1));
+    /// ```
+    pub fn with_hasher(hasher: S) -> Self {
+        let heads = Buckets::<Head<K, V>>::new();
+        let head = match heads.get_or_allocate(0) {
+            Ok(head) => head,
+            Err(err) => handle_error(err),
+        };
+        let dummy = Box::into_raw(Box::new(Node {
+            order: dummy_order(0),
+            entry: None,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        head.0.store(dummy, Ordering::Relaxed);
+        Self {
+            heads,
+            size: CachePadded::new(AtomicUsize::new(2)),
+            len: CachePadded::new(AtomicUsize::new(0)),
+            hasher,
+            domain: Domain::new(&Family {}),
+            _boo: PhantomData,
+        }
+    }
+
+    /// Return the number of entries in the map.
+    /// With concurrent inserts and removes this may be out of date when it returns.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, V, S> SecMap<K, V, S>
+where
+    K: Hash + Eq + Send + Sync,
+    V: Send + Sync,
+    S: BuildHasher,
+{
+    /// Insert `key` with `value` and return `true`, or return `false` and leave the map as it
+    /// was if `key` is already in it
+    /// ```rust
+    /// # use unlocked::map::SecMap;
+    /// let map = SecMap::new();
+    /// assert!(map.insert(1, "one"));
+    /// assert!(!map.insert(1, "uno"));
+    /// assert_eq!(map.get(&1), Some("one"));
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let hash = self.hasher.hash_one(&key);
+        let order = entry_order(hash);
+        let mut guards = Guards::new(&self.domain);
+        let dummy = self.dummy(self.bucket(hash), &mut guards);
+        let node = Box::into_raw(Box::new(Node {
+            order,
+            entry: Some((key, value)),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        // # Safety
+        // The node isn't shared until it's inserted
+        let key = unsafe { &(*node).entry.as_ref().unwrap().0 };
+        if self
+            .insert_node(dummy, node, Some(key), &mut guards)
+            .is_err()
+        {
+            // # Safety
+            // Our node was never shared
+            drop(unsafe { Box::from_raw(node) });
+            return false;
+        }
+
+        let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+        let size = self.size.load(Ordering::Relaxed);
+        if len > size.saturating_mul(LOAD_FACTOR) {
+            // The new buckets are initialized as they're used. Losing the race is fine,
+            // someone else grew the table.
+            if let Some(doubled) = size.checked_mul(2) {
+                let _ =
+                    self.size
+                        .compare_exchange(size, doubled, Ordering::Relaxed, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
+    /// Return a clone of the value for `key`, or `None` if it isn't in the map
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let hash = self.hasher.hash_one(key);
+        let mut guards = Guards::new(&self.domain);
+        let dummy = self.nearest_dummy(self.bucket(hash));
+        let pos = self.find(dummy, entry_order(hash), Some(key), &mut guards);
+        // # Safety
+        // `cur` is protected by `guards.cur`
+        pos.found
+            .then(|| unsafe { (*pos.cur).entry.as_ref().unwrap().1.clone() })
+    }
+
+    /// Return whether `key` is in the map
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let mut guards = Guards::new(&self.domain);
+        let dummy = self.nearest_dummy(self.bucket(hash));
+        self.find(dummy, entry_order(hash), Some(key), &mut guards)
+            .found
+    }
+
+    /// Remove `key` from the map and return a clone of its value, or `None` if it isn't in the map.
+    ///
+    /// Of several threads removing the same key, exactly one gets the value.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let hash = self.hasher.hash_one(key);
+        let order = entry_order(hash);
+        let mut guards = Guards::new(&self.domain);
+        let dummy = self.nearest_dummy(self.bucket(hash));
+        loop {
+            let pos = self.find(dummy, order, Some(key), &mut guards);
+            if !pos.found {
+                return None;
+            }
+            // # Safety
+            // `cur` is protected by `guards.cur`, and `prev` is a dummy's or protected by
+            // `guards.prev`
+            let node = unsafe { &*pos.cur };
+            let next = node.next.load(Ordering::Acquire);
+            // Marking the node is what removes it, only one thread can do that
+            if is_deleted(next)
+                || node
+                    .next
+                    .compare_exchange(
+                        next,
+                        next.map_addr(|addr| addr | DELETED),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                    .is_err()
+            {
+                // Removed by someone else, or a node was inserted after it. Look again.
+                continue;
+            }
+            let value = node.entry.as_ref().unwrap().1.clone();
+            self.len.fetch_sub(1, Ordering::Relaxed);
+
+            // # Safety
+            // Same as above
+            if unsafe { &*pos.prev }
+                .compare_exchange(pos.cur, next, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                // # Safety
+                // The node is unlinked, and only the thread that unlinks a node retires it
+                unsafe { self.retire(pos.cur) };
+            } else {
+                // The list changed around it, a search unlinks it on the way
+                self.find(dummy, order, Some(key), &mut guards);
+            }
+            return Some(value);
+        }
+    }
+
+    /// Return the bucket for `hash` in the table as it is now
+    #[inline]
+    fn bucket(&self, hash: u64) -> usize {
+        hash as usize & (self.size.load(Ordering::Relaxed) - 1)
+    }
+
+    /// Return `bucket`'s dummy, inserting it into the list first if the bucket hasn't been used
+    fn dummy(&self, bucket: usize, guards: &mut Guards<'_>) -> &Node<K, V> {
+        let head = match self.heads.get_or_allocate(bucket) {
+            Ok(head) => head,
+            Err(err) => handle_error(err),
+        };
+        let dummy = head.0.load(Ordering::Acquire);
+        if !dummy.is_null() {
+            // # Safety
+            // Dummies live as long as the map
+            return unsafe { &*dummy };
+        }
+
+        // Bucket 0's dummy was made with the map, so this ends
+        let parent = self.dummy(parent(bucket), guards);
+        let new = Box::into_raw(Box::new(Node {
+            order: dummy_order(bucket),
+            entry: None,
+            next: AtomicPtr::new(ptr::null_mut()),
+        }));
+        let dummy = match self.insert_node::<K>(parent, new, None, guards) {
+            Ok(()) => new,
+            Err(installed) => {
+                // # Safety
+                // Our dummy was never shared
+                drop(unsafe { Box::from_raw(new) });
+                installed
+            }
+        };
+        // Everyone that gets here stores the same dummy
+        head.0.store(dummy, Ordering::Release);
+        // # Safety
+        // Dummies live as long as the map
+        unsafe { &*dummy }
+    }
+
+    /// Return the dummy of `bucket` or of the closest bucket it was split from that has one.
+    /// The entries of `bucket` come after it in the list, so lookups don't have to allocate.
+    fn nearest_dummy(&self, bucket: usize) -> &Node<K, V> {
+        let mut bucket = bucket;
+        loop {
+            if let Some(head) = self.heads.get(bucket) {
+                let dummy = head.0.load(Ordering::Acquire);
+                if !dummy.is_null() {
+                    // # Safety
+                    // Dummies live as long as the map
+                    return unsafe { &*dummy };
+                }
+            }
+            // Bucket 0 always has a dummy
+            bucket = parent(bucket);
+        }
+    }
+
+    /// Link `node` into the list after `start`, unless there's a node with the same order and key,
+    /// in which case that node is returned instead
+    fn insert_node<Q>(
+        &self,
+        start: &Node<K, V>,
+        node: *mut Node<K, V>,
+        key: Option<&Q>,
+        guards: &mut Guards<'_>,
+    ) -> Result<(), *mut Node<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        // # Safety
+        // The node isn't shared until the CAS succeeds
+        let order = unsafe { (*node).order };
+        loop {
+            let pos = self.find(start, order, key, guards);
+            if pos.found {
+                return Err(pos.cur);
+            }
+            // # Safety
+            // Same as above
+            unsafe { (*node).next.store(pos.cur, Ordering::Relaxed) };
+            // # Safety
+            // `prev` is a dummy's or protected by `guards.prev`
+            if unsafe { &*pos.prev }
+                .compare_exchange(pos.cur, node, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Search the list from `start` for the node with `order` and `key` (or the dummy with `order`
+    /// if `key` is `None`), unlinking removed nodes on the way.
+    ///
+    /// Nodes with the same order but a different key are passed over, so entries whose hashes
+    /// collide are kept in the order they were inserted.
+    fn find<Q>(
+        &self,
+        start: &Node<K, V>,
+        order: u64,
+        key: Option<&Q>,
+        guards: &mut Guards<'_>,
+    ) -> Position<K, V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        'retry: loop {
+            let mut prev = &start.next as *const AtomicPtr<Node<K, V>>;
+            // # Safety
+            // `start` is a dummy, so it lives as long as the map and is never marked
+            let mut cur = unsafe { (*prev).load(Ordering::Acquire) };
+            loop {
+                if cur.is_null() {
+                    return Position {
+                        found: false,
+                        prev,
+                        cur,
+                    };
+                }
+                guards.cur.protect_raw(cur);
+                fence(Ordering::SeqCst);
+                // If `prev` still links to `cur` (and isn't marked itself), `cur` wasn't unlinked
+                // when we protected it, so it can't have been retired
+                // # Safety
+                // `prev` is a dummy's or protected by `guards.prev`
+                if unsafe { (*prev).load(Ordering::Acquire) } != cur {
+                    continue 'retry;
+                }
+                // # Safety
+                // Protected by `guards.cur`
+                let node = unsafe { &*cur };
+                let next = node.next.load(Ordering::Acquire);
+                if is_deleted(next) {
+                    let next = untagged(next);
+                    // # Safety
+                    // Same as above
+                    if unsafe { &*prev }
+                        .compare_exchange(cur, next, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_err()
+                    {
+                        continue 'retry;
+                    }
+                    // # Safety
+                    // We unlinked it, and only the thread that unlinks a node retires it
+                    unsafe { self.retire(cur) };
+                    cur = next;
+                    continue;
+                }
+                if node.order > order {
+                    return Position {
+                        found: false,
+                        prev,
+                        cur,
+                    };
+                }
+                if node.order == order {
+                    let matches = match (&node.entry, key) {
+                        (Some((k, _)), Some(key)) => k.borrow() == key,
+                        (None, None) => true,
+                        _ => false,
+                    };
+                    if matches {
+                        return Position {
+                            found: true,
+                            prev,
+                            cur,
+                        };
+                    }
+                }
+                // `cur` becomes the previous node, keep it protected
+                prev = &node.next;
+                mem::swap(&mut guards.prev, &mut guards.cur);
+                cur = next;
+            }
+        }
+    }
+
+    /// # Safety
+    /// `node` must be unlinked from the list, and this must be the only thread retiring it
+    unsafe fn retire(&self, node: *mut Node<K, V>) {
+        // # Safety
+        // Guaranteed by the caller, and every node came from Box::into_raw
+        unsafe { self.domain.retire_ptr::<_, Box<Node<K, V>>>(node) };
+    }
+}
+
+impl<K, V, S: Default> Default for SecMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> Drop for SecMap<K, V, S> {
+    fn drop(&mut self) {
+        // Unlinked nodes were retired, the domain frees them when it's dropped. Everything else,
+        // dummies and marked nodes that weren't unlinked yet, is still in the list.
+        let mut node = self.heads.get(0).unwrap().0.load(Ordering::Relaxed);
+        while !node.is_null() {
+            // # Safety
+            // We have &mut self, so nobody else can use the nodes, and nodes in the list were
+            // never retired
+            let next = untagged(unsafe { (*node).next.load(Ordering::Relaxed) });
+            drop(unsafe { Box::from_raw(node) });
+            node = next;
+        }
+    }
+}
+
+impl<K, V, S> fmt::Debug for SecMap<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecMap")
+            .field("len", &self.len())
+            .field("buckets", &self.size.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// The default hasher of a [`SecMap`]
+pub type DefaultHashBuilder = BuildHasherDefault<MapHasher>;
+
+/// A fast hasher with a fixed seed: a multiply-rotate over the input like FxHash, then a final mix
+/// so that every bit of the hash depends on every bit of the input. The map orders entries by the
+/// low bits of the hash, which FxHash alone leaves poorly mixed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MapHasher {
+    hash: u64,
+}
+
+impl MapHasher {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+
+    #[inline]
+    fn add(&mut self, word: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(Self::SEED);
+    }
+}
+
+impl Hasher for MapHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut rest = [0; 8];
+        rest[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        // The length tells apart inputs that only differ in trailing zeroes
+        self.add(u64::from_le_bytes(rest) ^ (bytes.len() as u64) << 56);
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.add(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.add(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.add(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        // The finalizer of MurmurHash3
+        let mut hash = self.hash;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        hash ^ hash >> 33
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+    use std::thread;
+
+    /// Every key hashes to the same bucket and order, so they all collide
+    #[derive(Clone, Copy, Default)]
+    struct Colliding;
+
+    impl BuildHasher for Colliding {
+        type Hasher = Colliding;
+
+        fn build_hasher(&self) -> Colliding {
+            Colliding
+        }
+    }
+
+    impl Hasher for Colliding {
+        fn write(&mut self, _: &[u8]) {}
+
+        fn finish(&self) -> u64 {
+            7
+        }
+    }
+
+    #[test]
+    fn insert_get_and_remove() {
+        let map = SecMap::new();
+        assert!(map.is_empty());
+        for i in 0..1000 {
+            assert!(map.insert(i, i * 2));
+        }
+        assert_eq!(map.len(), 1000);
+        assert!(!map.insert(5, 0));
+        for i in 0..1100 {
+            assert_eq!(map.get(&i), (i < 1000).then_some(i * 2));
+        }
+        for i in (0..1000).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i * 2));
+        }
+        assert_eq!(map.remove(&0), None);
+        assert_eq!(map.len(), 500);
+        for i in 0..1000 {
+            assert_eq!(map.contains_key(&i), i % 2 == 1);
+        }
+        assert!(map.insert(0, 1));
+        assert_eq!(map.get(&0), Some(1));
+    }
+
+    #[test]
+    fn table_grows_with_the_entries() {
+        let map = SecMap::new();
+        for i in 0..10_000u64 {
+            map.insert(i, ());
+        }
+        let size = map.size.load(Ordering::Relaxed);
+        assert!(size >= 10_000 / LOAD_FACTOR, "{size} buckets");
+        // Every bucket that was used has its own dummy in the list, in order
+        let mut dummies = 0;
+        let mut node = map.heads.get(0).unwrap().0.load(Ordering::Relaxed);
+        let mut last = None;
+        while !node.is_null() {
+            let n = unsafe { &*node };
+            assert!(last < Some(n.order), "the list is out of order");
+            last = Some(n.order);
+            dummies += n.entry.is_none() as usize;
+            node = n.next.load(Ordering::Relaxed);
+        }
+        assert!(dummies > size / 4);
+    }
+
+    #[test]
+    fn colliding_keys_are_told_apart() {
+        let map = SecMap::with_hasher(Colliding);
+        for i in 0..100 {
+            assert!(map.insert(i.to_string(), i));
+        }
+        assert!(!map.insert("42".to_string(), 0));
+        assert_eq!(map.get("42"), Some(42));
+        assert_eq!(map.remove("42"), Some(42));
+        assert_eq!(map.get("42"), None);
+        assert_eq!(map.get("43"), Some(43));
+        assert_eq!(map.len(), 99);
+    }
+
+    #[test]
+    fn lookups_before_a_bucket_is_used_dont_allocate() {
+        let map = SecMap::new();
+        for i in 0..100u64 {
+            map.insert(i, i);
+        }
+        // Grow the table without touching the new buckets
+        map.size.store(1 << 20, Ordering::Relaxed);
+        let (found, stats) = failing_alloc::measure(|| (0..100u64).all(|i| map.get(&i) == Some(i)));
+        assert!(found);
+        // Only hazard pointers, which are reused after the first lookup
+        assert!(stats.allocations <= 2, "{stats:?}");
+    }
+
+    #[test]
+    fn racing_inserts_and_removes_of_the_same_keys() {
+        let map = SecMap::new();
+        let (inserted, removed) = thread::scope(|s| {
+            let inserts = (0..4)
+                .map(|_| {
+                    let map = &map;
+                    s.spawn(move || (0..2000).filter(|&i| map.insert(i, i)).count())
+                })
+                .collect::<Vec<_>>();
+            let inserted = inserts
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>();
+            let removes = (0..4)
+                .map(|_| {
+                    let map = &map;
+                    s.spawn(move || (0..2000).filter(|i| map.remove(i) == Some(*i)).count())
+                })
+                .collect::<Vec<_>>();
+            let removed = removes
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>();
+            (inserted, removed)
+        });
+        assert_eq!(inserted, 2000);
+        assert_eq!(removed, 2000);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn concurrent_mixed_operations() {
+        // Threads own disjoint keys, but share the buckets and the list
+        let map = SecMap::new();
+        thread::scope(|s| {
+            for t in 0..4u64 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..5000 {
+                        let key = i * 4 + t;
+                        assert!(map.insert(key, t));
+                        if i % 3 == 0 {
+                            assert_eq!(map.remove(&key), Some(t));
+                        }
+                        assert_eq!(map.get(&(i / 2 * 4 + t)).is_some(), (i / 2) % 3 != 0);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), 4 * (5000 - 1667));
+        for key in 0..20_000u64 {
+            assert_eq!(map.get(&key), ((key / 4) % 3 != 0).then_some(key % 4));
+        }
+    }
+
+    #[test]
+    fn removed_entries_are_freed() {
+        let map = SecMap::new();
+        let ((), stats) = failing_alloc::measure(|| {
+            for i in 0..20_000u32 {
+                map.insert(i, i.to_string());
+                assert_eq!(map.remove(&i), Some(i.to_string()));
+            }
+        });
+        // Retired nodes are freed in batches, but nowhere near all of them stay around
+        assert!(stats.live_bytes() < stats.allocated_bytes as isize / 4);
+    }
+
+    #[test]
+    fn drop_frees_everything() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let map = SecMap::new();
+            for i in 0..2000u32 {
+                map.insert(i.to_string(), i.to_string());
+            }
+            for i in (0..2000u32).step_by(3) {
+                map.remove(&i.to_string());
+            }
+        });
+        assert_eq!(
+            stats.live_bytes(),
+            0,
+            "entries, dummies or the directory leaked"
+        );
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Insert(u8, u8),
+        Get(u8),
+        Remove(u8),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            2 => (any::<u8>(), any::<u8>()).prop_map(|(k, v)| Op::Insert(k, v)),
+            1 => any::<u8>().prop_map(Op::Get),
+            1 => any::<u8>().prop_map(Op::Remove),
+        ]
+    }
+
+    fn agrees_with_hashmap<S: BuildHasher>(map: SecMap<u8, u8, S>, ops: Vec<Op>) {
+        let mut model = HashMap::new();
+        for op in ops {
+            match op {
+                Op::Insert(k, v) => {
+                    let inserted = !model.contains_key(&k);
+                    model.entry(k).or_insert(v);
+                    assert_eq!(map.insert(k, v), inserted, "insert({k}, {v})");
+                }
+                Op::Get(k) => assert_eq!(map.get(&k), model.get(&k).copied(), "get({k})"),
+                Op::Remove(k) => assert_eq!(map.remove(&k), model.remove(&k), "remove({k})"),
+            }
+            assert_eq!(map.len(), model.len());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn map_agrees_with_hashmap(ops in prop::collection::vec(op(), 0..300)) {
+            agrees_with_hashmap(SecMap::new(), ops);
+        }
+
+        #[test]
+        fn colliding_map_agrees_with_hashmap(ops in prop::collection::vec(op(), 0..100)) {
+            agrees_with_hashmap(SecMap::with_hasher(Colliding), ops);
+        }
+    }
+
+    #[test]
+    fn hasher_mixes_the_low_bits() {
+        // Consecutive keys have to spread over the buckets, and strings differing only in
+        // trailing zeroes must not collide
+        let hashes = (0..64u64)
+            .map(|i| DefaultHashBuilder::default().hash_one(i) & 63)
+            .collect::<std::collections::HashSet<_>>();
+        assert!(hashes.len() > 32);
+        let build = DefaultHashBuilder::default();
+        assert_ne!(
+            build.hash_one(b"a\0".as_slice()),
+            build.hash_one(b"a".as_slice())
+        );
+        assert_ne!(build.hash_one(String::from("ab")), build.hash_one("ba"));
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::model::model;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    /// Keys hash to themselves, so they land in known buckets
+    #[derive(Clone, Copy, Default)]
+    struct Identity(u64);
+
+    impl Hasher for Identity {
+        fn write(&mut self, _: &[u8]) {
+            unreachable!()
+        }
+
+        fn write_u64(&mut self, i: u64) {
+            self.0 = i;
+        }
+
+        fn finish(&self) -> u64 {
+            self.0
+        }
+    }
+
+    type Map = SecMap<u64, u64, BuildHasherDefault<Identity>>;
+
+    #[test]
+    fn racing_inserts_initialize_a_bucket_once() {
+        model(|| {
+            // Both keys are in bucket 1, which neither thread has used yet
+            let map = Arc::new(Map::default());
+            let map1 = Arc::clone(&map);
+            let t = thread::spawn(move || map1.insert(1, 10));
+            assert!(map.insert(3, 30));
+            assert!(t.join().unwrap());
+            assert_eq!(map.get(&1), Some(10));
+            assert_eq!(map.get(&3), Some(30));
+            assert_eq!(map.len(), 2);
+        });
+    }
+
+    #[test]
+    fn remove_races_insert_of_a_neighbour() {
+        model(|| {
+            let map = Arc::new(Map::default());
+            map.insert(0, 0);
+            map.insert(2, 2);
+            let map1 = Arc::clone(&map);
+            // In bit-reversed order 0 < 4 < 2, so the insert and the remove both swing the link
+            // out of 0
+            let t = thread::spawn(move || map1.remove(&2));
+            assert!(map.insert(4, 4));
+            assert_eq!(t.join().unwrap(), Some(2));
+            assert_eq!(map.get(&0), Some(0));
+            assert_eq!(map.get(&2), None);
+            assert_eq!(map.get(&4), Some(4));
+        });
+    }
+}
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use super::*;
+    use crate::model::check;
+    extern crate std;
+    use shuttle::thread;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn every_key_is_removed_once() {
+        check(|| {
+            // The table grows while the threads insert, and they all remove the same keys
+            let map = Arc::new(SecMap::new());
+            let threads = (0..3u64)
+                .map(|t| {
+                    let map = Arc::clone(&map);
+                    thread::spawn(move || {
+                        for i in 0..10 {
+                            map.insert(t * 10 + i, t);
+                        }
+                        (0..30).filter_map(|key| map.remove(&key)).count()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let removed = threads
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>();
+            let left = (0..30).filter(|key| map.contains_key(key)).count();
+            assert_eq!(removed + left, 30);
+            assert_eq!(map.len(), left);
+        });
+    }
+}
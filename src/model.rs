@@ -0,0 +1,32 @@
+// The harnesses the loom and shuttle tests run their models with
+extern crate std;
+
+/// Run `f` under every interleaving loom explores.
+///
+/// Every load/store of a descriptor goes through a hazard pointer, so even the small cases
+/// have a lot of interleavings. Bound the preemptions to keep the runs short,
+/// `LOOM_MAX_PREEMPTIONS` still overrides it.
+#[cfg(loom)]
+pub(crate) fn model<F>(f: F)
+where
+    F: Fn() + Sync + Send + 'static,
+{
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = Some(3);
+    }
+    builder.check(f);
+}
+
+/// Run `f` under random schedules. When it fails, shuttle prints the failing schedule,
+/// which can be replayed deterministically with `SHUTTLE_SCHEDULE=<schedule> cargo test ...`
+#[cfg(shuttle)]
+pub(crate) fn check<F>(f: F)
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    match std::env::var("SHUTTLE_SCHEDULE") {
+        Ok(schedule) => shuttle::replay(f, &schedule),
+        Err(_) => shuttle::check_random(f, 100),
+    }
+}
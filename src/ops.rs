@@ -0,0 +1,155 @@
+// The operations behind the `fetch_*` methods of `sealed::SecVec` and `counters::SecCounters`
+//
+// A `SecVec` stores its elements in tagged slots, so every update is a compare-and-swap of the
+// whole slot whatever the type; these traits only say how to combine two values. A `SecCounters`
+// stores them untagged, and `Counter` says which operations are a single atomic instruction on
+// the packed bits. Integers wrap on overflow like the std atomics do.
+use crate::Element;
+
+/// Element types that can be added, subtracted, and compared
+pub trait Arithmetic: Copy {
+    fn add(self, rhs: Self) -> Self;
+    fn sub(self, rhs: Self) -> Self;
+    fn max(self, rhs: Self) -> Self;
+    fn min(self, rhs: Self) -> Self;
+}
+
+/// Element types with bitwise operations
+pub trait Bitwise: Copy {
+    fn and(self, rhs: Self) -> Self;
+    fn or(self, rhs: Self) -> Self;
+    fn xor(self, rhs: Self) -> Self;
+}
+
+/// Element types of a [`crate::counters::SecCounters`].
+///
+/// A counter is an `AtomicU64` holding the bytes of its value, the rest zero, hence the
+/// [`Element`] bound. Where an operation on those bits gives the same result as the operation
+/// on the value, the counter uses the `AtomicU64` instruction for it, otherwise a CAS loop.
+///
+/// # Safety
+/// - All zero bytes must be a valid value, counters start out zeroed.
+/// - Each `NATIVE_*` constant may only be `true` if, for every two values, the `AtomicU64`
+///   operation on their packed bits always gives the packed bits of the value-level result
+///   (`Arithmetic` or `Bitwise`), upper bytes still zero, and those bits are a valid value.
+///   `SecCounters` reads them back as a `T` without checking.
+pub unsafe trait Counter: Element {
+    /// `fetch_add` and `fetch_sub` are native. True for 64-bit integers, smaller ones
+    /// would carry out of their bytes.
+    const NATIVE_ADD: bool = false;
+    /// `fetch_max` and `fetch_min` are native. True for unsigned integers.
+    const NATIVE_ORD: bool = false;
+    /// `fetch_and`, `fetch_or` and `fetch_xor` are native. True for integers and `bool`.
+    const NATIVE_BITWISE: bool = false;
+}
+
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {$(
+        impl Arithmetic for $ty {
+            #[inline]
+            fn add(self, rhs: Self) -> Self {
+                self.wrapping_add(rhs)
+            }
+
+            #[inline]
+            fn sub(self, rhs: Self) -> Self {
+                self.wrapping_sub(rhs)
+            }
+
+            #[inline]
+            fn max(self, rhs: Self) -> Self {
+                Ord::max(self, rhs)
+            }
+
+            #[inline]
+            fn min(self, rhs: Self) -> Self {
+                Ord::min(self, rhs)
+            }
+        }
+
+        impl Bitwise for $ty {
+            #[inline]
+            fn and(self, rhs: Self) -> Self {
+                self & rhs
+            }
+
+            #[inline]
+            fn or(self, rhs: Self) -> Self {
+                self | rhs
+            }
+
+            #[inline]
+            fn xor(self, rhs: Self) -> Self {
+                self ^ rhs
+            }
+        }
+
+        // # Safety
+        // Zero is a valid integer and integers have no padding. Bitwise operations act on each
+        // byte alone; a 64-bit add wraps like `wrapping_add`, and zero-extended unsigned
+        // integers compare like their values. Every bit pattern is a valid integer.
+        unsafe impl Counter for $ty {
+            const NATIVE_ADD: bool = core::mem::size_of::<$ty>() == 8;
+            const NATIVE_ORD: bool = <$ty>::MIN == 0;
+            const NATIVE_BITWISE: bool = true;
+        }
+    )*};
+}
+
+impl_integer!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+macro_rules! impl_float {
+    ($($ty:ty),*) => {$(
+        /// `max` and `min` ignore NaN, like `f64::max` and `f64::min`
+        impl Arithmetic for $ty {
+            #[inline]
+            fn add(self, rhs: Self) -> Self {
+                self + rhs
+            }
+
+            #[inline]
+            fn sub(self, rhs: Self) -> Self {
+                self - rhs
+            }
+
+            #[inline]
+            fn max(self, rhs: Self) -> Self {
+                <$ty>::max(self, rhs)
+            }
+
+            #[inline]
+            fn min(self, rhs: Self) -> Self {
+                <$ty>::min(self, rhs)
+            }
+        }
+
+        // # Safety
+        // Zero bytes are 0.0 and floats have no padding. Nothing is native.
+        unsafe impl Counter for $ty {}
+    )*};
+}
+
+impl_float!(f32, f64);
+
+impl Bitwise for bool {
+    #[inline]
+    fn and(self, rhs: Self) -> Self {
+        self & rhs
+    }
+
+    #[inline]
+    fn or(self, rhs: Self) -> Self {
+        self | rhs
+    }
+
+    #[inline]
+    fn xor(self, rhs: Self) -> Self {
+        self ^ rhs
+    }
+}
+
+// # Safety
+// A zero byte is false. And, or and xor of 0 and 1 are 0 or 1, the same bool.
+unsafe impl Counter for bool {
+    const NATIVE_BITWISE: bool = true;
+}
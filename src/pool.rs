@@ -0,0 +1,306 @@
+// An object pool whose free list is a `sealed::SecVec`
+//
+// Idle objects are boxed, and the vector holds the pointers to them. Checking an object out pops
+// a pointer, returning it pushes the pointer back, so neither takes a lock or moves the object.
+extern crate alloc;
+use crate::sealed::SecVec;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::Element;
+use alloc::boxed::Box;
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+/// A pointer to an idle object, from `Box::into_raw`
+struct Idle<T>(NonNull<T>);
+
+impl<T> Clone for Idle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Idle<T> {}
+
+// # Safety
+// An idle object is only reachable through the vector, and whoever pops it owns it,
+// so sending the pointer is sending the object
+unsafe impl<T: Send> Send for Idle<T> {}
+unsafe impl<T: Send> Sync for Idle<T> {}
+
+// # Safety
+// A pointer has no padding
+unsafe impl<T> Element for Idle<T> {}
+
+/// A lock-free pool of reusable objects, such as large buffers.
+///
+/// `get` hands out an idle object if there is one, or builds a new one with the pool's factory.
+/// The object goes back into the pool when the returned guard is dropped, unless the pool already
+/// holds its maximum number of idle objects, in which case it's dropped instead.
+///
+/// Objects come back the way they were left, so reset them before (or after) use if that matters.
+/// ```rust
+/// use unlocked::pool::SecPool;
+///
+/// let buffers = SecPool::new(|| Vec::<u8>::with_capacity(1 << 16));
+/// {
+///     let mut buf = buffers.get();
+///     buf.extend_from_slice(b"hello");
+/// } // back in the pool
+/// let mut buf = buffers.get();
+/// assert_eq!(&buf[..], b"hello");
+/// assert!(buf.capacity() >= 1 << 16);
+/// buf.clear();
+/// ```
+pub struct SecPool<T: Send, F = fn() -> T> {
+    idle: SecVec<'static, Idle<T>>,
+    // Number of objects in `idle`, or about to be. Never less than the actual number.
+    count: AtomicUsize,
+    max_idle: usize,
+    factory: F,
+}
+
+impl<T, F> SecPool<T, F>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    /// Return an empty pool that builds objects with `factory` and keeps any number of them
+    pub fn new(factory: F) -> Self {
+        Self::with_max_idle(usize::MAX, factory)
+    }
+
+    /// Return an empty pool that keeps at most `max_idle` objects around.
+    /// Objects returned to a full pool are dropped.
+    /// ```rust
+    /// # use unlocked::pool::SecPool;
+    /// let pool = SecPool::with_max_idle(1, String::new);
+    /// let (a, b) = (pool.get(), pool.get());
+    /// drop(a);
+    /// drop(b); // Dropped, the pool already has an idle string
+    /// assert_eq!(pool.idle(), 1);
+    /// ```
+    pub fn with_max_idle(max_idle: usize, factory: F) -> Self {
+        Self {
+            idle: SecVec::new(),
+            count: AtomicUsize::new(0),
+            max_idle,
+            factory,
+        }
+    }
+
+    /// Check out an idle object, or build a new one if there is none
+    pub fn get(&self) -> Pooled<'_, T, F> {
+        let object = match self.idle.pop() {
+            Some(Idle(ptr)) => {
+                self.count.fetch_sub(1, Ordering::Relaxed);
+                // # Safety
+                // The pointer came from Box::into_raw in `put`, and popping it made it ours
+                unsafe { Box::from_raw(ptr.as_ptr()) }
+            }
+            None => Box::new((self.factory)()),
+        };
+        Pooled {
+            object: ManuallyDrop::new(object),
+            pool: self,
+        }
+    }
+
+    /// Return the number of idle objects in the pool
+    pub fn idle(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn put(&self, object: Box<T>) {
+        // Claim a place first, so concurrent returns can't overshoot the maximum
+        if self
+            .count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < self.max_idle).then_some(count + 1)
+            })
+            .is_ok()
+        {
+            self.idle.push(Idle(NonNull::from(Box::leak(object))));
+        }
+    }
+}
+
+impl<T: Send, F> Drop for SecPool<T, F> {
+    fn drop(&mut self) {
+        // The vector only frees its own memory
+        while let Some(Idle(ptr)) = self.idle.pop() {
+            // # Safety
+            // Same as in `get`
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        }
+    }
+}
+
+impl<T: Send, F> fmt::Debug for SecPool<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecPool")
+            .field("idle", &self.count.load(Ordering::Relaxed))
+            .field("max_idle", &self.max_idle)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An object checked out of a `SecPool`. It goes back into the pool when dropped.
+pub struct Pooled<'pool, T, F = fn() -> T>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    object: ManuallyDrop<Box<T>>,
+    pool: &'pool SecPool<T, F>,
+}
+
+impl<T, F> Pooled<'_, T, F>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    /// Take the object out of the pool for good
+    /// ```rust
+    /// # use unlocked::pool::SecPool;
+    /// let pool = SecPool::new(|| 7);
+    /// assert_eq!(unlocked::pool::Pooled::detach(pool.get()), 7);
+    /// assert_eq!(pool.idle(), 0);
+    /// ```
+    pub fn detach(this: Self) -> T {
+        let mut this = ManuallyDrop::new(this);
+        // # Safety
+        // `this` is never used or dropped again
+        *unsafe { ManuallyDrop::take(&mut this.object) }
+    }
+}
+
+impl<T, F> Deref for Pooled<'_, T, F>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.object
+    }
+}
+
+impl<T, F> DerefMut for Pooled<'_, T, F>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.object
+    }
+}
+
+impl<T, F> Drop for Pooled<'_, T, F>
+where
+    T: Send,
+    F: Fn() -> T,
+{
+    fn drop(&mut self) {
+        // # Safety
+        // This is the last use of the object
+        let object = unsafe { ManuallyDrop::take(&mut self.object) };
+        self.pool.put(object);
+    }
+}
+
+impl<T, F> fmt::Debug for Pooled<'_, T, F>
+where
+    T: Send + fmt::Debug,
+    F: Fn() -> T,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn objects_are_reused() {
+        let pool = SecPool::new(|| vec![0u8; 1024]);
+        let first = {
+            let mut buf = pool.get();
+            buf[0] = 1;
+            buf.as_ptr()
+        };
+        assert_eq!(pool.idle(), 1);
+        let buf = pool.get();
+        assert_eq!(buf.as_ptr(), first);
+        assert_eq!(buf[0], 1);
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn factory_builds_objects_when_the_pool_is_empty() {
+        let pool = SecPool::new(|| vec![0u8; 16]);
+        let (a, b) = (pool.get(), pool.get());
+        assert_ne!(a.as_ptr(), b.as_ptr());
+    }
+
+    #[test]
+    fn full_pool_drops_returned_objects() {
+        let counter = Arc::new(());
+        let pool = SecPool::with_max_idle(2, || Arc::clone(&counter));
+        let objects = (0..5).map(|_| pool.get()).collect::<Vec<_>>();
+        assert_eq!(Arc::strong_count(&counter), 6);
+        drop(objects);
+        assert_eq!(pool.idle(), 2);
+        assert_eq!(Arc::strong_count(&counter), 3);
+        drop(pool);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn no_object_is_checked_out_twice() {
+        let pool = SecPool::with_max_idle(8, || vec![0u64; 4]);
+        thread::scope(|s| {
+            for t in 0..4u64 {
+                let pool = &pool;
+                s.spawn(move || {
+                    for i in 0..2000 {
+                        let mut a = pool.get();
+                        let mut b = pool.get();
+                        // Nobody else may write to them while we hold them
+                        a.fill(t * 10_000 + i);
+                        b.fill(t * 10_000 + i + 1);
+                        assert!(a.iter().all(|&x| x == t * 10_000 + i));
+                        assert!(b.iter().all(|&x| x == t * 10_000 + i + 1));
+                    }
+                });
+            }
+        });
+        assert!(pool.idle() <= 8);
+        let held = (0..pool.idle()).map(|_| pool.get()).collect::<Vec<_>>();
+        let distinct = held.iter().map(|buf| buf.as_ptr()).collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), held.len());
+    }
+
+    #[test]
+    fn drop_frees_everything() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let pool = SecPool::with_max_idle(3, || vec![0u8; 100]);
+            let objects = (0..10).map(|_| pool.get()).collect::<Vec<_>>();
+            let detached = Pooled::detach(pool.get());
+            drop(objects);
+            drop(detached);
+        });
+        assert_eq!(stats.live_bytes(), 0, "objects or the free list leaked");
+    }
+}
@@ -0,0 +1,557 @@
+// Quiescent-state-based reclamation (QSBR) for `sealed::SecVec`, instead of hazard pointers
+// McKenney & Slingwine, 1998, Read-Copy Update: Using Execution History to Solve Concurrency Problems
+// Hart et. al., 2007, Performance of memory reclamation for lockless synchronization
+// https://www.cs.toronto.edu/~tomhart/papers/tomhart_thesis.pdf
+extern crate alloc;
+extern crate std;
+use crate::reclaim::raw::{Guard, Scheme};
+use crate::reclaim::Reclaim;
+use crate::sealed;
+use crate::sync::atomic::AtomicPtr as FacadePtr;
+use crate::Element;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::ptr;
+use core::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use crossbeam_utils::CachePadded;
+use portable_atomic::AtomicU64;
+
+/// The epoch a record holds while no thread is using it
+const OFFLINE: u64 = 0;
+
+/// Used to tell domains apart in the thread-local registrations
+static NEXT_DOMAIN_ID: AtomicUsize = AtomicUsize::new(0);
+
+std::thread_local! {
+    static REGISTRATIONS: RefCell<Vec<Registration>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A lock-free vector that reclaims memory with quiescent-state-based reclamation (QSBR).
+///
+/// This is [`crate::sealed::SecVec`] with [`Qsbr`] instead of hazard pointers, so descriptors
+/// are not protected one by one. Instead, every thread that uses the vector is registered with
+/// it, and periodically announces that it is in a quiescent state by calling
+/// [`SecVec::quiescent`]. A thread is quiescent when it holds no references into the
+/// vector, which is true between any two operations. Retired descriptors are freed once every
+/// registered thread has passed through a quiescent state after the descriptor was retired.
+///
+/// This removes the hazard pointer bookkeeping from `push`, `pop` and `size`, which is a good
+/// fit for threads that run event loops with natural quiescent points.
+///
+/// Threads are registered automatically the first time they use the vector, and unregistered
+/// when they exit or call [`SecVec::unregister`]. **A registered thread that never calls
+/// `quiescent` prevents all memory from being reclaimed** until it unregisters.
+///
+/// ```rust
+/// # use unlocked::qsbr::SecVec;
+/// # use std::sync::Arc;
+/// # use std::thread;
+/// let sv = Arc::new(SecVec::<isize>::new());
+/// let sv1 = Arc::clone(&sv);
+/// thread::spawn(move || {
+///     sv1.register();
+///     for i in 0..10 {
+///         sv1.push(i);
+///         // No references into the vector are held between operations
+///         sv1.quiescent();
+///     }
+///     sv1.unregister();
+/// })
+/// .join()
+/// .unwrap();
+/// assert_eq!(sv.size(), 10);
+/// ```
+pub type SecVec<'a, T> = sealed::SecVec<'a, T, Qsbr>;
+
+/// Reclaim memory with quiescent-state-based reclamation.
+///
+/// Keeps track of the registered threads and of the memory waiting to be reclaimed,
+/// see [`SecVec`] for how threads use it.
+pub struct Qsbr {
+    id: usize,
+    epoch: CachePadded<AtomicU64>,
+    // Linked list of records, nodes are only freed when the domain is dropped
+    records: AtomicPtr<Record>,
+    // Stack of retired pointers. Nodes are only pushed one at a time or taken all at once,
+    // so a node is never touched by two threads and there's no ABA.
+    retired: AtomicPtr<Retired>,
+}
+
+/// The per-thread state of a registered thread
+struct Record {
+    // The global epoch the last time the thread was quiescent, or OFFLINE
+    local: CachePadded<AtomicU64>,
+    in_use: AtomicBool,
+    // Set when the domain is dropped, so threads can forget about the record
+    orphaned: AtomicBool,
+    next: AtomicPtr<Record>,
+}
+
+/// A thread's handle on a record, marks the record as offline when the thread exits
+struct Registration {
+    domain: usize,
+    record: Arc<Record>,
+}
+
+/// A pointer waiting for every thread to pass through a quiescent state
+struct Retired {
+    epoch: u64,
+    ptr: *mut u8,
+    drop: unsafe fn(*mut u8),
+    next: *mut Retired,
+}
+
+// Public so it can be `Qsbr`'s guard type, but nothing outside the crate can name it
+mod guard {
+    /// QSBR's guard: nothing is protected, the thread only has to be registered
+    pub struct Registered;
+}
+use guard::Registered;
+
+impl Reclaim for Qsbr {}
+
+impl Scheme for Qsbr {
+    type Guard<'r> = Registered;
+
+    fn new() -> Self {
+        Qsbr {
+            id: NEXT_DOMAIN_ID.fetch_add(1, Ordering::Relaxed),
+            // Start at 1 so that no thread's epoch is ever OFFLINE by accident
+            epoch: CachePadded::new(AtomicU64::new(1)),
+            records: AtomicPtr::new(ptr::null_mut()),
+            retired: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn guard(&self) -> Registered {
+        self.register();
+        Registered
+    }
+
+    unsafe fn retire<P: Send>(&self, ptr: *mut P) {
+        unsafe fn drop_box<P>(ptr: *mut u8) {
+            // # Safety
+            // The pointer came from Box::into_raw, see `retire`
+            drop(unsafe { Box::from_raw(ptr as *mut P) });
+        }
+        // Threads whose epoch is greater than this one were quiescent after the ptr was unlinked
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst);
+        let node = Box::into_raw(Box::new(Retired {
+            epoch,
+            ptr: ptr as *mut u8,
+            drop: drop_box::<P>,
+            next: ptr::null_mut(),
+        }));
+        self.push_retired(node, node);
+    }
+}
+
+impl Guard for Registered {
+    fn protect<P>(&mut self, src: &FacadePtr<P>) -> *mut P {
+        // Nothing this thread loads can be freed before it's quiescent again
+        src.load(Ordering::Acquire)
+    }
+
+    fn protect_raw<P>(&mut self, _: *mut P) {}
+}
+
+impl Qsbr {
+    /// Call `f` with the current thread's record, registering the thread if it isn't already
+    fn with_record<R>(&self, f: impl FnOnce(&Record) -> R) -> R {
+        REGISTRATIONS.with(|registrations| {
+            let mut registrations = registrations.borrow_mut();
+            if let Some(registration) = registrations.iter().find(|r| r.domain == self.id) {
+                return f(&registration.record);
+            }
+            // We're on the slow path anyways, forget about domains that no longer exist
+            registrations.retain(|r| !r.record.orphaned.load(Ordering::Acquire));
+            let record = self.acquire_record();
+            let res = f(&record);
+            registrations.push(Registration {
+                domain: self.id,
+                record,
+            });
+            res
+        })
+    }
+
+    /// Find a record no thread is using, or add a new one to the list
+    fn acquire_record(&self) -> Arc<Record> {
+        let mut node = self.records.load(Ordering::Acquire);
+        while !node.is_null() {
+            // # Safety
+            // Records are only freed when the domain is dropped
+            let record = unsafe { &*node };
+            if record
+                .in_use
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                record
+                    .local
+                    .store(self.epoch.load(Ordering::SeqCst), Ordering::SeqCst);
+                // Order the announcement before any loads from the vector, see `quiescent`
+                atomic::fence(Ordering::SeqCst);
+                // # Safety
+                // The list holds a strong count from Arc::into_raw, so the record is alive
+                unsafe {
+                    Arc::increment_strong_count(node);
+                    return Arc::from_raw(node);
+                }
+            }
+            node = record.next.load(Ordering::Acquire);
+        }
+
+        let record = Arc::new(Record {
+            local: CachePadded::new(AtomicU64::new(self.epoch.load(Ordering::SeqCst))),
+            in_use: AtomicBool::new(true),
+            orphaned: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        });
+        let node = Arc::into_raw(Arc::clone(&record)) as *mut Record;
+        let mut head = self.records.load(Ordering::Acquire);
+        loop {
+            record.next.store(head, Ordering::Relaxed);
+            match self.records.compare_exchange_weak(
+                head,
+                node,
+                Ordering::SeqCst,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(actual) => head = actual,
+            }
+        }
+        atomic::fence(Ordering::SeqCst);
+        record
+    }
+
+    /// Register the current thread, if it isn't already
+    pub(crate) fn register(&self) {
+        self.with_record(|_| {})
+    }
+
+    pub(crate) fn unregister(&self) {
+        REGISTRATIONS.with(|registrations| {
+            // Dropping the registration marks the record as offline
+            registrations
+                .borrow_mut()
+                .retain(|registration| registration.domain != self.id)
+        })
+    }
+
+    /// Announce that the current thread holds no references to retired memory
+    pub(crate) fn quiescent(&self) {
+        self.with_record(|record| {
+            record
+                .local
+                .store(self.epoch.load(Ordering::SeqCst), Ordering::SeqCst);
+            // Pairs with the fence in `reclaim`: either the reclaiming thread sees our announcement,
+            // or our next loads from the vector see the pointers it unlinked before retiring
+            atomic::fence(Ordering::SeqCst);
+        });
+        self.reclaim();
+    }
+
+    /// Push the list of retired nodes from `first` to `last` onto the stack
+    fn push_retired(&self, first: *mut Retired, last: *mut Retired) {
+        let mut head = self.retired.load(Ordering::Relaxed);
+        loop {
+            // # Safety
+            // The nodes aren't on the stack yet, so this thread is the only one that has them
+            unsafe { (*last).next = head };
+            match self.retired.compare_exchange_weak(
+                head,
+                first,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// The smallest epoch announced by a registered thread
+    fn min_epoch(&self) -> u64 {
+        let mut min = u64::MAX;
+        let mut node = self.records.load(Ordering::Acquire);
+        while !node.is_null() {
+            // # Safety
+            // Records are only freed when the domain is dropped
+            let record = unsafe { &*node };
+            match record.local.load(Ordering::SeqCst) {
+                OFFLINE => {}
+                local => min = min.min(local),
+            }
+            node = record.next.load(Ordering::Acquire);
+        }
+        min
+    }
+
+    /// Free everything that was retired before the last quiescent state of every registered thread
+    fn reclaim(&self) {
+        atomic::fence(Ordering::SeqCst);
+        let min = self.min_epoch();
+        // Take the whole stack, anything still in use is pushed back as one list
+        let mut node = self.retired.swap(ptr::null_mut(), Ordering::Acquire);
+        let (mut keep_first, mut keep_last): (*mut Retired, *mut Retired) =
+            (ptr::null_mut(), ptr::null_mut());
+        while !node.is_null() {
+            // # Safety
+            // Taking the stack gave this thread the only pointer to its nodes
+            let retired = unsafe { Box::from_raw(node) };
+            node = retired.next;
+            if retired.epoch < min {
+                // # Safety
+                // Every registered thread has been quiescent since the pointer was retired,
+                // so no thread holds a reference to it. Unregistered threads hold no references.
+                unsafe { (retired.drop)(retired.ptr) }
+            } else {
+                let kept = Box::into_raw(retired);
+                if keep_first.is_null() {
+                    keep_first = kept;
+                } else {
+                    // # Safety
+                    // Same as above, and `keep_last` isn't on the stack yet
+                    unsafe { (*keep_last).next = kept };
+                }
+                keep_last = kept;
+            }
+        }
+        if !keep_first.is_null() {
+            self.push_retired(keep_first, keep_last);
+        }
+    }
+}
+
+impl Drop for Qsbr {
+    fn drop(&mut self) {
+        // # Safety
+        // We have exclusive access, so no thread can be using the vector
+        let mut node = *self.retired.get_mut();
+        while !node.is_null() {
+            let retired = unsafe { Box::from_raw(node) };
+            unsafe { (retired.drop)(retired.ptr) }
+            node = retired.next;
+        }
+
+        let mut node = *self.records.get_mut();
+        while !node.is_null() {
+            // # Safety
+            // The pointer came from Arc::into_raw when the record was added to the list
+            let record = unsafe { Arc::from_raw(node) };
+            record.orphaned.store(true, Ordering::Release);
+            node = record.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.record.local.store(OFFLINE, Ordering::SeqCst);
+        self.record.in_use.store(false, Ordering::Release);
+    }
+}
+
+impl<T> SecVec<'_, T>
+where
+    T: Element + Send + Sync,
+{
+    /// Register the current thread with the vector.
+    ///
+    /// Threads are registered automatically when they first use the vector,
+    /// so calling this is only needed to make registration explicit.
+    pub fn register(&self) {
+        self.reclaim().register();
+    }
+
+    /// Unregister the current thread from the vector.
+    ///
+    /// An unregistered thread does not hold up reclamation. Threads are unregistered
+    /// automatically when they exit. Using the vector again registers the thread again.
+    pub fn unregister(&self) {
+        self.reclaim().unregister();
+    }
+
+    /// Announce that the current thread is in a quiescent state, and free any
+    /// descriptors that every registered thread is done with.
+    ///
+    /// Call this between operations, for example once per iteration of an event loop.
+    /// The more often threads are quiescent, the sooner memory is reclaimed.
+    pub fn quiescent(&self) {
+        self.reclaim().quiescent();
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    use crate::linearizability::{History, Recorder};
+    use crate::TryReserveErrorKind;
+    use std::sync::atomic::AtomicIsize;
+    use std::thread::{self, JoinHandle};
+
+    #[test]
+    fn size_starts_at_0() {
+        let sv = SecVec::<usize>::new();
+        assert_eq!(0, sv.size());
+    }
+
+    #[test]
+    fn pop_empty_returns_none() {
+        let sv = SecVec::<usize>::new();
+        assert_eq!(sv.pop(), None);
+    }
+
+    #[test]
+    fn ten_push_ten_pop() {
+        let sv = SecVec::<isize>::new();
+        for i in 0..10 {
+            sv.push(i);
+            sv.quiescent();
+        }
+        for i in (0..10).rev() {
+            assert_eq!(sv.pop(), Some(i));
+            sv.quiescent();
+        }
+    }
+
+    #[test]
+    fn quiescent_reclaims_retired_descriptors() {
+        let sv = SecVec::<isize>::new();
+        for i in 0..10 {
+            sv.push(i);
+        }
+        assert!(!sv.reclaim().retired.load(Ordering::Relaxed).is_null());
+        // This is the only registered thread
+        sv.quiescent();
+        assert!(sv.reclaim().retired.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    fn registered_thread_holds_up_reclamation() {
+        let sv = Arc::new(SecVec::<isize>::new());
+        let (registered, wait) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(true)),
+        );
+        let handle = {
+            let (sv, registered, wait) =
+                (Arc::clone(&sv), Arc::clone(&registered), Arc::clone(&wait));
+            thread::spawn(move || {
+                sv.register();
+                registered.store(true, Ordering::SeqCst);
+                while wait.load(Ordering::SeqCst) {
+                    thread::yield_now();
+                }
+                // Exiting the thread unregisters it
+            })
+        };
+        while !registered.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        sv.push(1);
+        sv.push(2);
+        sv.quiescent();
+        assert!(!sv.reclaim().retired.load(Ordering::Relaxed).is_null());
+
+        wait.store(false, Ordering::SeqCst);
+        handle.join().unwrap();
+        sv.quiescent();
+        assert!(sv.reclaim().retired.load(Ordering::Relaxed).is_null());
+    }
+
+    #[test]
+    fn try_reserve_reports_capacity_overflow() {
+        let sv = SecVec::<isize>::new();
+        sv.push(1);
+        assert_eq!(
+            sv.try_reserve(usize::MAX).unwrap_err().kind(),
+            TryReserveErrorKind::CapacityOverflow
+        );
+        sv.try_reserve(100).unwrap();
+        sv.quiescent();
+        assert_eq!(sv.pop(), Some(1));
+    }
+
+    #[test]
+    fn unregister_and_register_again() {
+        let sv = SecVec::<isize>::new();
+        sv.push(1);
+        sv.unregister();
+        sv.register();
+        sv.push(2);
+        sv.quiescent();
+        assert_eq!(sv.pop(), Some(2));
+        assert_eq!(sv.pop(), Some(1));
+    }
+
+    #[test]
+    fn the_big_multithread() {
+        static FIVE: isize = 5;
+        let data = Arc::new(SecVec::<isize>::new());
+        data.reserve(100 * 5);
+        let sum = Arc::new(AtomicIsize::new(0));
+        #[allow(clippy::needless_collect)]
+        let handles = (0..5)
+            .map(|_| {
+                let data = Arc::clone(&data);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        data.push(FIVE);
+                        data.quiescent();
+                    }
+                })
+            })
+            .collect::<Vec<JoinHandle<_>>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        #[allow(clippy::needless_collect)]
+        let handles = (0..5)
+            .map(|_| {
+                let data = Arc::clone(&data);
+                let sum = Arc::clone(&sum);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        sum.fetch_add(data.pop().unwrap_or(0), Ordering::Relaxed);
+                        data.quiescent();
+                    }
+                })
+            })
+            .collect::<Vec<JoinHandle<_>>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(sum.load(Ordering::Relaxed), 100 * 5 * FIVE);
+    }
+
+    #[test]
+    fn the_big_multithread_is_linearizable() {
+        let sv = SecVec::<u64>::new();
+        let recorder = Recorder::new();
+        let logs = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|thread| {
+                    let (sv, recorder) = (&sv, &recorder);
+                    s.spawn(move || {
+                        let mut log = recorder.log(thread);
+                        for i in 0..1000 {
+                            match i % 4 {
+                                0 | 1 => log.push((thread * 1000 + i) as u64, |elem| sv.push(elem)),
+                                2 => drop(log.pop(|| sv.pop())),
+                                _ => drop(log.size(|| sv.size())),
+                            }
+                            sv.quiescent();
+                        }
+                        log
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        History::new(logs).assert_linearizable();
+    }
+}
@@ -0,0 +1,867 @@
+// A FIFO queue on buckets that never move, like the vectors'
+//
+// Every element gets an index from one of two counters: `enqueue` takes the next index from
+// `tail` with a `fetch_add`, and `dequeue` takes the next one from `head` with a CAS that never
+// lets `head` pass `tail`. Indices live in fixed-size buckets linked in order, so a queue that
+// is drained as fast as it's filled only has a couple of buckets allocated at any time.
+//
+// Each index is claimed by exactly one enqueue and one dequeue, so unlike a push on a `SecVec`,
+// a write never has to be completed by another thread through a descriptor. A dequeue that claims
+// an index whose enqueue hasn't written yet waits a moment, then closes the entry. The enqueue
+// sees that and tries again at a new index, so neither waits on the other for long.
+//
+// Dequeues that keep closing entries could make an enqueue try again forever, so after
+// `PATIENCE` tries the enqueue asks for help, like the slow path of Yang and Mellor-Crummey's
+// queue (A Wait-free Queue as Fast as Fetch-and-Add, 2016). It moves its element into one of the
+// queue's requests, next to the last index it gave up on, and keeps taking indices. A dequeue
+// that closes an empty entry offers it to a pending request. The request goes into whichever
+// entry claims it first, one the enqueue took itself or one a dequeue offered, and the dequeue of
+// that entry moves the element out of the request. Nobody waits for another thread's write, so
+// enqueue and dequeue are both lock-free. A request only goes into an entry at or after the index
+// it gave up on, which was handed out while its enqueue was running, so the queue's order is
+// still the order of the indices.
+//
+// Once every index in the head bucket has been claimed by a dequeue, the bucket is unlinked and
+// retired through hazard pointers, the same way `sealed::SecVec` retires its descriptors.
+//
+// This doesn't use the doubling buckets of the vectors, whose directory never shrinks: a queue's
+// indices only go up, so over its lifetime a queue would address more buckets than fit in the
+// directory, and memory for drained indices would never be given back. Fixed-size buckets in a
+// list can be freed as soon as they're drained.
+extern crate alloc;
+use crate::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+use crossbeam_utils::{Backoff, CachePadded};
+use haphazard;
+
+// Setting up hazard pointers
+// This makes sure they all use the same Domain, guaranteeing the protection is valid.
+#[non_exhaustive]
+struct Family;
+type Domain = haphazard::Domain<Family>;
+type HazardPointer<'domain> = crate::sync::HazardPointer<'domain, Family>;
+type HazAtomicPtr<T> = haphazard::AtomicPtr<T, Family>;
+
+/// The number of elements in a bucket.
+/// Small under loom, so that a model can cross into the next bucket.
+#[cfg(not(loom))]
+const BUCKET_SIZE: u64 = 32;
+#[cfg(loom)]
+const BUCKET_SIZE: u64 = 2;
+
+/// How many indices an enqueue tries before it asks dequeues for help.
+/// One under loom, so the models get to the requests.
+#[cfg(not(loom))]
+const PATIENCE: usize = 16;
+#[cfg(loom)]
+const PATIENCE: usize = 1;
+
+/// How many enqueues can ask for help at once, more keep trying on their own until there's room
+#[cfg(not(loom))]
+const REQUESTS: usize = 8;
+#[cfg(loom)]
+const REQUESTS: usize = 2;
+
+// The states of an entry
+const EMPTY: u32 = 0;
+const READY: u32 = 1;
+// The element was dequeued, or a dequeue gave up waiting for it
+const TAKEN: u32 = 2;
+
+// What an entry's `request` can be besides the number of a request plus one
+const NO_REQUEST: u32 = 0;
+// A dequeue closed the entry and had no request to offer it to
+const CLOSED: u32 = u32::MAX;
+
+// Set in a request's state while it waits for an entry, next to the last index its enqueue
+// gave up on. Once it's in an entry the state is that entry's index.
+const PENDING: u64 = 1 << 63;
+
+/// An unbounded multi-producer multi-consumer FIFO queue.
+///
+/// Elements are stored in buckets of a fixed size that are allocated as the queue grows, and
+/// freed once every element in them has been dequeued. An element is written once and read once,
+/// it's never moved while it's in the queue.
+///
+/// Both `enqueue` and `dequeue` are lock-free. An enqueue whose entries keep getting closed by
+/// dequeues that got to them first asks for help after 16 tries, and the next dequeue that finds
+/// an empty entry puts the element in it, see the module comments.
+/// ```rust
+/// use unlocked::queue::SecQueue;
+/// use std::thread;
+///
+/// let jobs = SecQueue::new();
+/// thread::scope(|s| {
+///     s.spawn(|| {
+///         for i in 0..100 {
+///             jobs.enqueue(i);
+///         }
+///     });
+/// });
+/// assert_eq!(jobs.len(), 100);
+/// // First in, first out
+/// assert_eq!(jobs.dequeue(), Some(0));
+/// assert_eq!(jobs.dequeue(), Some(1));
+/// ```
+pub struct SecQueue<T: Send> {
+    // The next index to dequeue from, never greater than `tail`
+    head: CachePadded<AtomicU64>,
+    // The next index to enqueue at
+    tail: CachePadded<AtomicU64>,
+    // The bucket `head` is in, or one before it that hasn't been unlinked yet
+    head_bucket: CachePadded<HazAtomicPtr<Bucket<T>>>,
+    // A bucket at or before the one `tail` is in, and never before `head_bucket`
+    tail_bucket: CachePadded<HazAtomicPtr<Bucket<T>>>,
+    // Elements of enqueues that asked for help
+    requests: Box<[CachePadded<Request<T>>; REQUESTS]>,
+    domain: Domain,
+}
+
+struct Bucket<T> {
+    // The bucket holds indices `id * BUCKET_SIZE..(id + 1) * BUCKET_SIZE`
+    id: u64,
+    entries: [Entry<T>; BUCKET_SIZE as usize],
+    next: AtomicPtr<Bucket<T>>,
+    // Set once every index in the bucket has been claimed by a dequeue, before it's unlinked
+    drained: AtomicBool,
+}
+
+struct Entry<T> {
+    state: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+    // The request offered this entry, if the element is in a request instead
+    request: AtomicU32,
+}
+
+struct Request<T> {
+    // `PENDING` and an index while it waits for an entry, then the entry's index
+    state: AtomicU64,
+    // The enqueue until it sees the request is in an entry, and the dequeue of that entry until
+    // it took the element. The request is free for another enqueue once both are done.
+    users: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// # Safety
+// Only the enqueue that claimed an entry's index writes its value, and only the dequeue that
+// swaps its state from READY reads it. The value is moved between them, never shared.
+unsafe impl<T: Send> Sync for Bucket<T> {}
+
+// # Safety
+// Only the enqueue that reserved a request writes its value, and only the dequeue of the entry it
+// went into reads it. The request isn't reused until both are done with it.
+unsafe impl<T: Send> Sync for Request<T> {}
+
+impl<T> Bucket<T> {
+    fn new_as_ptr(id: u64) -> *mut Self {
+        Box::into_raw(Box::new(Bucket {
+            id,
+            entries: core::array::from_fn(|_| Entry {
+                state: AtomicU32::new(EMPTY),
+                value: UnsafeCell::new(MaybeUninit::uninit()),
+                request: AtomicU32::new(NO_REQUEST),
+            }),
+            next: AtomicPtr::new(ptr::null_mut()),
+            drained: AtomicBool::new(false),
+        }))
+    }
+
+    /// Return the index after the last one in the bucket
+    fn end(&self) -> u64 {
+        (self.id + 1) * BUCKET_SIZE
+    }
+
+    /// Return the entry for `index`, which must be in the bucket
+    fn entry(&self, index: u64) -> &Entry<T> {
+        &self.entries[(index % BUCKET_SIZE) as usize]
+    }
+
+    /// Return the next bucket, allocating it if there isn't one yet
+    fn next_or_allocate(&self) -> *mut Bucket<T> {
+        let next = self.next.load(Ordering::Acquire);
+        if !next.is_null() {
+            return next;
+        }
+        let new = Bucket::new_as_ptr(self.id + 1);
+        match self
+            .next
+            .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => new,
+            Err(installed) => {
+                // # Safety
+                // Our bucket was never shared
+                drop(unsafe { Box::from_raw(new) });
+                installed
+            }
+        }
+    }
+}
+
+impl<T> Drop for Bucket<T> {
+    fn drop(&mut self) {
+        for entry in &mut self.entries {
+            if entry.state.load(Ordering::Relaxed) == READY {
+                // # Safety
+                // The value was written before the state was set, and nobody dequeued it
+                unsafe { entry.value.get_mut().assume_init_drop() }
+            }
+        }
+    }
+}
+
+impl<T: Send> SecQueue<T> {
+    /// Return a new, empty queue with one bucket
+    pub fn new() -> Self {
+        let bucket = Bucket::new_as_ptr(0);
+        Self {
+            head: CachePadded::new(AtomicU64::new(0)),
+            tail: CachePadded::new(AtomicU64::new(0)),
+            // # Safety
+            // Buckets come from Box::into_raw, and are only reclaimed by retiring them in
+            // `self.domain`, or in `drop` once nobody else can use the queue
+            head_bucket: CachePadded::new(unsafe { HazAtomicPtr::new(bucket) }),
+            tail_bucket: CachePadded::new(unsafe { HazAtomicPtr::new(bucket) }),
+            requests: Box::new(core::array::from_fn(|_| {
+                CachePadded::new(Request {
+                    state: AtomicU64::new(0),
+                    users: AtomicU32::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+            })),
+            domain: Domain::new(&Family {}),
+        }
+    }
+
+    /// Add an element to the back of the queue.
+    ///
+    /// If dequeues keep closing the entries it gets, it asks them to put the element in one
+    /// after 16 tries.
+    pub fn enqueue(&self, elem: T) {
+        let mut elem = elem;
+        let backoff = Backoff::new();
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            let index = match self.try_enqueue(elem) {
+                Ok(()) => return,
+                Err((back, index)) => {
+                    elem = back;
+                    index
+                }
+            };
+            if tries >= PATIENCE {
+                if let Some(request) = self.reserve_request() {
+                    return self.enqueue_slow(request, elem, index);
+                }
+            }
+            backoff.spin();
+        }
+    }
+
+    /// Take an index and write `elem` to its entry. If a dequeue closed the entry first, or its
+    /// bucket was freed, return the element and the index.
+    fn try_enqueue(&self, elem: T) -> Result<(), (T, u64)> {
+        let index = self.tail.fetch_add(1, Ordering::SeqCst);
+        let mut hp = HazardPointer::new_in_domain(&self.domain);
+        let Some(bucket) = self.find(index, &mut hp) else {
+            // The index was dequeued and its bucket freed before we got to it
+            return Err((elem, index));
+        };
+        let entry = bucket.entry(index);
+        // # Safety
+        // `fetch_add` gave the index to us alone, and the value isn't read unless the
+        // state is READY
+        unsafe { (*entry.value.get()).write(elem) };
+        match entry
+            .state
+            .compare_exchange(EMPTY, READY, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(()),
+            // A dequeue gave up on the entry, take the element back
+            // # Safety
+            // We just wrote it, and the dequeue won't read it
+            Err(_) => Err((unsafe { (*entry.value.get()).assume_init_read() }, index)),
+        }
+    }
+
+    /// Return a request nobody is using and mark it as used by us and a dequeue to come,
+    /// or `None` if they're all taken
+    fn reserve_request(&self) -> Option<usize> {
+        (0..REQUESTS).find(|&request| {
+            self.requests[request]
+                .users
+                .compare_exchange(0, 2, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        })
+    }
+
+    /// Ask for help with `elem`, whose enqueue gave up on `index` last, through `request`
+    fn enqueue_slow(&self, request: usize, elem: T, index: u64) {
+        let req = &self.requests[request];
+        // # Safety
+        // We reserved the request, so nobody else uses its value until it's in an entry
+        unsafe { (*req.value.get()).write(elem) };
+        let pending = PENDING | index;
+        req.state.store(pending, Ordering::SeqCst);
+        let offer = request as u32 + 1;
+        // Keep taking indices in case no dequeue comes along
+        while req.state.load(Ordering::SeqCst) == pending {
+            let index = self.tail.fetch_add(1, Ordering::SeqCst);
+            let mut hp = HazardPointer::new_in_domain(&self.domain);
+            let Some(bucket) = self.find(index, &mut hp) else {
+                continue;
+            };
+            let entry = bucket.entry(index);
+            // Offer our own entry to the request. If a dequeue closed the entry already, it
+            // sees the offer and puts the request in it for us.
+            if entry
+                .request
+                .compare_exchange(NO_REQUEST, offer, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+                && entry.state.load(Ordering::Acquire) == EMPTY
+            {
+                // If this fails, a dequeue put the request in another entry first
+                let _ =
+                    req.state
+                        .compare_exchange(pending, index, Ordering::SeqCst, Ordering::SeqCst);
+                break;
+            }
+        }
+        // The dequeue of the entry the request went in takes the element from here
+        self.release_request(request);
+    }
+
+    /// Put the element of a pending request in the entry at `index`, which a dequeue just closed
+    /// while it was empty, and return it if it went in. Only that dequeue may call this.
+    fn help_enqueue(&self, entry: &Entry<T>, index: u64) -> Option<T> {
+        let mut offer = entry.request.load(Ordering::Acquire);
+        if offer == NO_REQUEST {
+            // Start looking at a different request for every index, so they all get their turn
+            let waiting = (0..REQUESTS)
+                .map(|i| (index as usize + i) % REQUESTS)
+                .find(|&request| {
+                    let state = self.requests[request].state.load(Ordering::SeqCst);
+                    state & PENDING != 0 && state & !PENDING <= index
+                });
+            let new = waiting.map_or(CLOSED, |request| request as u32 + 1);
+            offer = match entry.request.compare_exchange(
+                NO_REQUEST,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                // The enqueue that took the index offered it to its request
+                Err(offer) => offer,
+            };
+        }
+        if offer == CLOSED {
+            return None;
+        }
+        let request = (offer - 1) as usize;
+        let req = &self.requests[request];
+        let state = req.state.load(Ordering::SeqCst);
+        // The request may have been reused since the entry was offered to it. Whichever request
+        // is there now can go in the entry, as long as it gave up on an index before it.
+        if state & PENDING != 0 && state & !PENDING <= index {
+            let _ = req
+                .state
+                .compare_exchange(state, index, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        if req.state.load(Ordering::SeqCst) != index {
+            // It went in another entry
+            return None;
+        }
+        // # Safety
+        // The enqueue wrote the element before it made the request pending, and the request is
+        // in our entry, so we're the only one to take it
+        let elem = unsafe { (*req.value.get()).assume_init_read() };
+        self.release_request(request);
+        Some(elem)
+    }
+
+    /// Say that the enqueue or the dequeue of a request is done with it
+    fn release_request(&self, request: usize) {
+        self.requests[request].users.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Remove and return the element at the front of the queue, or `None` if it's empty
+    pub fn dequeue(&self) -> Option<T> {
+        let backoff = Backoff::new();
+        let mut hp = HazardPointer::new_in_domain(&self.domain);
+        loop {
+            // # Safety
+            // Buckets are only retired through `self.domain`
+            let bucket = unsafe { self.head_bucket.load(&mut hp) }
+                .expect("invalid ptr for head bucket in dequeue");
+            // The bucket is protected before the index is claimed,
+            // so it can't be freed while we use the entry
+            let head = self.head.load(Ordering::SeqCst);
+            if head >= self.tail.load(Ordering::SeqCst) {
+                return None;
+            }
+            if head >= bucket.end() {
+                // The bucket is drained but hasn't been unlinked yet
+                self.advance_head();
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange_weak(head, head + 1, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+            {
+                backoff.spin();
+                continue;
+            }
+
+            let entry = bucket.entry(head);
+            // Give an enqueue that claimed the index but hasn't written yet a moment to finish
+            let wait = Backoff::new();
+            while entry.state.load(Ordering::Acquire) == EMPTY
+                && entry.request.load(Ordering::Acquire) == NO_REQUEST
+                && !wait.is_completed()
+            {
+                wait.snooze();
+            }
+            let state = entry.state.swap(TAKEN, Ordering::AcqRel);
+            if head + 1 == bucket.end() {
+                self.advance_head();
+            }
+            if state == READY {
+                // # Safety
+                // The value was written before the state was set to READY, and we are the only
+                // one to swap it out of READY
+                return Some(unsafe { (*entry.value.get()).assume_init_read() });
+            }
+            if let Some(elem) = self.help_enqueue(entry, head) {
+                return Some(elem);
+            }
+            // The enqueue will try again at a later index
+        }
+    }
+
+    /// Return the number of elements in the queue.
+    ///
+    /// Enqueues that are in progress are counted, and one that has to try again can be counted
+    /// twice, so this is only exact when no enqueue is running.
+    pub fn len(&self) -> usize {
+        // `tail` is read last, it's never less than `head` was before
+        let head = self.head.load(Ordering::SeqCst);
+        let tail = self.tail.load(Ordering::SeqCst);
+        (tail - head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the bucket `index` is in, protected by `hp`,
+    /// or `None` if every index in it was dequeued and it may be freed already
+    fn find<'hp, 'd>(
+        &'d self,
+        index: u64,
+        hp: &'hp mut HazardPointer<'d>,
+    ) -> Option<&'hp Bucket<T>> {
+        let id = index / BUCKET_SIZE;
+        let mut spare = None;
+        'retry: loop {
+            // # Safety
+            // Buckets are only retired through `self.domain`, after they've been unlinked from
+            // both `head_bucket` and `tail_bucket`
+            let mut bucket = unsafe { self.tail_bucket.load(hp) }
+                .expect("invalid ptr for tail bucket in find")
+                as *const Bucket<T>;
+            // # Safety
+            // Protected by `hp`
+            if unsafe { (*bucket).id } > id {
+                // The index is behind the tail bucket, look for it from the head
+                bucket = unsafe { self.head_bucket.load(hp) }
+                    .expect("invalid ptr for head bucket in find")
+                    as *const Bucket<T>;
+                if unsafe { (*bucket).id } > id {
+                    return None;
+                }
+            }
+
+            while unsafe { (*bucket).id } < id {
+                let current = unsafe { &*bucket };
+                let next = current.next_or_allocate();
+                let spare = spare.get_or_insert_with(|| HazardPointer::new_in_domain(&self.domain));
+                spare.protect_raw(next);
+                fence(Ordering::SeqCst);
+                // Buckets are unlinked in order, and marked as drained first. If `current` isn't,
+                // `next` hadn't been retired when we protected it.
+                if current.drained.load(Ordering::SeqCst) {
+                    // `current` is on its way out, help it along and start over
+                    self.advance_head();
+                    continue 'retry;
+                }
+                mem::swap(hp, spare);
+                // Keep the tail bucket close to the tail. It only moves forward, and only from a
+                // bucket that isn't unlinked yet, so it never points to a retired one.
+                // # Safety
+                // `next` came from Box::into_raw in `Bucket::new_as_ptr`
+                let _ = unsafe {
+                    self.tail_bucket
+                        .compare_exchange_ptr(bucket as *mut Bucket<T>, next)
+                };
+                bucket = next;
+            }
+            // # Safety
+            // Protected by `hp`
+            return Some(unsafe { &*bucket });
+        }
+    }
+
+    /// Unlink and retire head buckets whose indices have all been claimed by dequeues
+    fn advance_head(&self) {
+        let mut hp = HazardPointer::new_in_domain(&self.domain);
+        loop {
+            // # Safety
+            // Buckets are only retired through `self.domain`
+            let bucket = unsafe { self.head_bucket.load(&mut hp) }
+                .expect("invalid ptr for head bucket in advance_head");
+            if self.head.load(Ordering::SeqCst) < bucket.end() {
+                return;
+            }
+            // Dequeues that are still using an entry in the bucket have it protected
+            bucket.drained.store(true, Ordering::SeqCst);
+            let next = bucket.next_or_allocate();
+            let ptr = bucket as *const Bucket<T> as *mut Bucket<T>;
+            // The tail bucket moves on first, so the bucket can't be reached once it's retired
+            // # Safety
+            // `next` came from Box::into_raw in `Bucket::new_as_ptr`
+            let _ = unsafe { self.tail_bucket.compare_exchange_ptr(ptr, next) };
+            if let Ok(replaced) = unsafe { self.head_bucket.compare_exchange_ptr(ptr, next) } {
+                // # Safety
+                // Only the thread that unlinked the bucket retires it, and neither `head_bucket`
+                // nor `tail_bucket` point to it anymore. Threads walking the list check that the
+                // bucket before the one they step to isn't drained.
+                unsafe {
+                    replaced
+                        .expect("head bucket was null")
+                        .retire_in(&self.domain);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Send> Default for SecQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send> Drop for SecQueue<T> {
+    fn drop(&mut self) {
+        // Requests still in use went in an entry, but weren't dequeued
+        for request in self.requests.iter_mut() {
+            if request.users.load(Ordering::Relaxed) != 0 {
+                // # Safety
+                // We have &mut self, so every enqueue is done, and only the dequeue would have
+                // taken the element
+                unsafe { request.value.get_mut().assume_init_drop() }
+            }
+        }
+        // Buckets before the head bucket were retired, the domain frees them when it's dropped
+        let mut bucket = self.head_bucket.load_ptr();
+        while !bucket.is_null() {
+            // # Safety
+            // We have &mut self, so nobody else can use the buckets, and every bucket from the
+            // head bucket on is still linked and was never retired
+            let next = unsafe { (*bucket).next.load(Ordering::Relaxed) };
+            drop(unsafe { Box::from_raw(bucket) });
+            bucket = next;
+        }
+    }
+}
+
+impl<T: Send> fmt::Debug for SecQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecQueue")
+            .field("head", &self.head.load(Ordering::Relaxed))
+            .field("tail", &self.tail.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::string::ToString;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn first_in_first_out() {
+        let queue = SecQueue::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.dequeue(), None);
+        for i in 0..100 {
+            queue.enqueue(i);
+        }
+        assert_eq!(queue.len(), 100);
+        for i in 0..50 {
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+        for i in 100..200 {
+            queue.enqueue(i);
+        }
+        for i in 50..200 {
+            assert_eq!(queue.dequeue(), Some(i));
+        }
+        assert_eq!(queue.dequeue(), None);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drained_buckets_are_freed() {
+        let queue = SecQueue::new();
+        let ((), stats) = failing_alloc::measure(|| {
+            for i in 0..100_000u64 {
+                queue.enqueue(i);
+                assert_eq!(queue.dequeue(), Some(i));
+            }
+        });
+        // Retired buckets are freed in batches, but nowhere near all of them stay around
+        assert!(stats.live_bytes() < stats.allocated_bytes as isize / 4);
+    }
+
+    #[test]
+    fn drop_drops_every_element() {
+        let counter = Arc::new(());
+        let queue = SecQueue::new();
+        for _ in 0..100 {
+            queue.enqueue(Arc::clone(&counter));
+        }
+        for _ in 0..40 {
+            queue.dequeue();
+        }
+        assert_eq!(Arc::strong_count(&counter), 61);
+        drop(queue);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn drop_frees_retired_and_linked_buckets() {
+        // Nothing dequeued, buckets retired and some left, and every bucket retired
+        for dequeued in [0, 500, 32 * 32] {
+            let ((), stats) = failing_alloc::measure(|| {
+                let queue = SecQueue::new();
+                for i in 0..32 * 32u32 {
+                    queue.enqueue(i.to_string());
+                }
+                for _ in 0..dequeued {
+                    queue.dequeue();
+                }
+            });
+            assert_eq!(
+                stats.live_bytes(),
+                0,
+                "elements or buckets leaked after {dequeued} dequeues"
+            );
+        }
+    }
+
+    #[test]
+    fn concurrent_producers_and_consumers() {
+        let queue = SecQueue::new();
+        let consumed = Mutex::new(Vec::new());
+        thread::scope(|s| {
+            for producer in 0..4u64 {
+                let queue = &queue;
+                s.spawn(move || {
+                    for i in 0..5000 {
+                        queue.enqueue((producer, i));
+                    }
+                });
+            }
+            for _ in 0..4 {
+                let (queue, consumed) = (&queue, &consumed);
+                s.spawn(move || {
+                    let mut seen = Vec::new();
+                    let mut last = [None; 4];
+                    while seen.len() < 5000 {
+                        if let Some((producer, i)) = queue.dequeue() {
+                            // Elements from one producer come out in the order they went in
+                            assert!(last[producer as usize] < Some(i));
+                            last[producer as usize] = Some(i);
+                            seen.push((producer, i));
+                        }
+                    }
+                    consumed.lock().unwrap().extend(seen);
+                });
+            }
+        });
+        let consumed = consumed.into_inner().unwrap();
+        assert_eq!(consumed.len(), 20_000);
+        assert_eq!(consumed.iter().collect::<HashSet<_>>().len(), 20_000);
+        assert_eq!(queue.dequeue(), None);
+    }
+
+    #[test]
+    fn mixed_operations_free_everything() {
+        failing_alloc::assert_no_leaks_across_threads(
+            SecQueue::<u64>::new,
+            4,
+            |queue, t| {
+                for i in 0..5000 {
+                    queue.enqueue(t as u64 * 5000 + i);
+                    if i % 3 != 0 {
+                        queue.dequeue();
+                    }
+                }
+            },
+            |queue| assert!(queue.len() >= 4 * 5000 / 3),
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::model::model;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_enqueue_and_dequeue() {
+        model(|| {
+            let queue = Arc::new(SecQueue::new());
+            let queue1 = Arc::clone(&queue);
+            let t = thread::spawn(move || {
+                queue1.enqueue(1);
+                queue1.enqueue(2);
+            });
+            // Whatever we get, it comes out in order
+            let first = queue.dequeue();
+            let second = queue.dequeue();
+            t.join().unwrap();
+            let mut rest = (queue.dequeue(), queue.dequeue(), queue.dequeue());
+            match (first, second) {
+                (Some(1), Some(2)) => assert_eq!(rest, (None, None, None)),
+                (Some(1), None) | (None, Some(1)) => {
+                    assert_eq!(rest, (Some(2), None, None))
+                }
+                _ => {
+                    rest.2 = rest.2.or(first).or(second);
+                    assert_eq!(rest, (Some(1), Some(2), None))
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn enqueue_outlasts_dequeues_closing_its_entries() {
+        // Closing the enqueue's entry twice takes four preemptions
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = builder.preemption_bound.or(Some(4));
+        builder.check(|| {
+            // Each dequeue can close the entry the enqueue is about to write, with a patience
+            // of one the enqueue's second try is a request that it or a dequeue has to put in
+            // an entry
+            let queue = Arc::new(SecQueue::new());
+            let queue1 = Arc::clone(&queue);
+            let t = thread::spawn(move || queue1.enqueue(1));
+            let first = queue.dequeue();
+            let second = queue.dequeue();
+            let third = queue.dequeue();
+            t.join().unwrap();
+            let rest = queue.dequeue();
+            let got = [first, second, third, rest];
+            assert_eq!(got.iter().flatten().count(), 1);
+            assert!(got.contains(&Some(1)));
+            assert!(queue.is_empty());
+        });
+    }
+
+    #[test]
+    fn racing_dequeues_cross_a_bucket() {
+        model(|| {
+            // Two buckets of two, the dequeues race to drain and unlink the first one
+            let queue = Arc::new(SecQueue::new());
+            for i in 0..3 {
+                queue.enqueue(i);
+            }
+            let queue1 = Arc::clone(&queue);
+            let t = thread::spawn(move || queue1.dequeue().unwrap());
+            let mine = queue.dequeue().unwrap();
+            let theirs = t.join().unwrap();
+            assert_ne!(mine, theirs);
+            assert!(mine < 2 && theirs < 2);
+            assert_eq!(queue.dequeue(), Some(2));
+            queue.enqueue(3);
+            assert_eq!(queue.dequeue(), Some(3));
+        });
+    }
+}
+
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use super::*;
+    use crate::model::check;
+    extern crate std;
+    use shuttle::thread;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn every_element_comes_out_once_in_order() {
+        check(|| {
+            let queue = Arc::new(SecQueue::new());
+            let producers = (0..2u64)
+                .map(|producer| {
+                    let queue = Arc::clone(&queue);
+                    thread::spawn(move || {
+                        for i in 0..50 {
+                            queue.enqueue((producer, i));
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            let consumers = (0..2)
+                .map(|_| {
+                    let queue = Arc::clone(&queue);
+                    thread::spawn(move || {
+                        let mut seen = Vec::new();
+                        for _ in 0..60 {
+                            if let Some(elem) = queue.dequeue() {
+                                seen.push(elem);
+                            }
+                        }
+                        seen
+                    })
+                })
+                .collect::<Vec<_>>();
+            producers.into_iter().for_each(|h| h.join().unwrap());
+            let mut all = Vec::new();
+            for consumer in consumers {
+                let seen = consumer.join().unwrap();
+                for producer in 0..2 {
+                    let mine = seen.iter().filter(|(p, _)| *p == producer);
+                    assert!(mine.clone().zip(mine.skip(1)).all(|(a, b)| a.1 < b.1));
+                }
+                all.extend(seen);
+            }
+            while let Some(elem) = queue.dequeue() {
+                all.push(elem);
+            }
+            all.sort_unstable();
+            let expected = (0..2)
+                .flat_map(|producer| (0..50).map(move |i| (producer, i)))
+                .collect::<Vec<_>>();
+            assert_eq!(all, expected);
+        });
+    }
+}
@@ -0,0 +1,113 @@
+// How `sealed::SecVec` frees the descriptors it replaces
+//
+// A thread that loaded a descriptor before it was swapped out can still be reading it, so a
+// replaced descriptor is retired instead of freed, and the reclamation scheme decides when no
+// thread can be using it anymore. The vector only needs two things from a scheme: a guard that
+// keeps a loaded pointer alive while the thread uses it, and a way to retire a pointer.
+//
+// Hazard pointers protect each pointer on its own, so a guard protects one pointer. With QSBR
+// nothing has to be protected, a guard only makes sure the thread is registered.
+extern crate alloc;
+use crate::sync::atomic::{fence, AtomicPtr, Ordering};
+use alloc::boxed::Box;
+use haphazard;
+
+// Setting up hazard pointers
+// This makes sure they all use the same Domain, guaranteeing the protection is valid.
+type Domain = haphazard::Domain<Family>;
+type HazardPointer<'domain> = crate::sync::HazardPointer<'domain, Family>;
+
+/// A way of reclaiming the memory a [`crate::sealed::SecVec`] retires.
+///
+/// Implemented by [`HazardPointers`], the default, and [`crate::qsbr::Qsbr`].
+/// This trait is sealed, the vector relies on the schemes being implemented correctly.
+pub trait Reclaim: raw::Scheme {}
+
+pub(crate) mod raw {
+    use crate::sync::atomic::AtomicPtr;
+
+    // Public so it can show up in `HazardPointers`'s guard type, but nothing outside the crate can name it
+    #[non_exhaustive]
+    pub struct Family;
+
+    pub trait Scheme: Send + Sync + Sized {
+        /// Keeps the pointers it protected from being freed until it's dropped
+        type Guard<'r>: Guard
+        where
+            Self: 'r;
+
+        fn new() -> Self;
+
+        /// A new guard, which must be created before loading the pointers it protects
+        fn guard(&self) -> Self::Guard<'_>;
+
+        /// Free `ptr` once no guard can be protecting it
+        ///
+        /// # Safety
+        /// `ptr` must come from `Box::into_raw`, must no longer be reachable by threads that
+        /// load it after this call, and must only be retired once
+        unsafe fn retire<P: Send>(&self, ptr: *mut P);
+    }
+
+    pub trait Guard {
+        /// Load the pointer in `src` and protect it
+        fn protect<P>(&mut self, src: &AtomicPtr<P>) -> *mut P;
+
+        /// Protect a pointer that this thread hasn't made visible to other threads yet
+        fn protect_raw<P>(&mut self, ptr: *mut P);
+    }
+}
+
+use raw::{Family, Guard, Scheme};
+
+/// Reclaim memory with hazard pointers.
+///
+/// Every vector has its own domain, memory retired by one vector is only scanned by that vector's
+/// operations, and everything that's left is freed when the vector is dropped.
+pub struct HazardPointers {
+    domain: Domain,
+}
+
+impl Reclaim for HazardPointers {}
+
+impl Scheme for HazardPointers {
+    type Guard<'r> = HazardPointer<'r>;
+
+    fn new() -> Self {
+        HazardPointers {
+            domain: Domain::new(&Family {}),
+        }
+    }
+
+    fn guard(&self) -> HazardPointer<'_> {
+        HazardPointer::new_in_domain(&self.domain)
+    }
+
+    unsafe fn retire<P: Send>(&self, ptr: *mut P) {
+        // # Safety
+        // Every guard that can protect `ptr` is a hazard pointer in this domain,
+        // the rest is up to the caller
+        unsafe { self.domain.retire_ptr::<P, Box<P>>(ptr) };
+    }
+}
+
+impl Guard for HazardPointer<'_> {
+    fn protect<P>(&mut self, src: &AtomicPtr<P>) -> *mut P {
+        let mut ptr = src.load(Ordering::Relaxed);
+        loop {
+            self.protect_raw(ptr);
+            // Pairs with the fence of the thread that scans the hazard pointers: either it sees
+            // the protection, or the reload below sees that `ptr` was replaced before it was retired
+            fence(Ordering::SeqCst);
+            let current = src.load(Ordering::Acquire);
+            if current == ptr {
+                return ptr;
+            }
+            ptr = current;
+        }
+    }
+
+    fn protect_raw<P>(&mut self, ptr: *mut P) {
+        haphazard::HazardPointer::protect_raw(self, ptr)
+    }
+}
@@ -2,72 +2,102 @@
 // in their paper Lock-free Dynamically Resizable Arrays
 // https://www.stroustrup.com/lock-free-vector.pdf
 extern crate alloc;
-use crate::alloc_error::{alloc_guard, capacity_overflow};
-use crate::highest_bit;
-use alloc::alloc::{handle_alloc_error, Allocator, Global, Layout};
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError, TryReserveErrorKind};
+use crate::buckets::{self, Buckets};
+use crate::ops::{Arithmetic, Bitwise};
+use crate::reclaim::raw::Guard;
+use crate::reclaim::{HazardPointers, Reclaim};
+use crate::slot::{self, Slot};
+use crate::sync::atomic::{AtomicPtr, Ordering};
+use crate::Element;
 use alloc::boxed::Box;
 use core::fmt;
 use core::marker::PhantomData;
 use core::mem;
-use core::ptr::{self, NonNull};
-use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 use crossbeam_utils::{Backoff, CachePadded};
-use haphazard;
-
-// Setting up hazard pointers
-// This makes sure they all use the same Domain, guaranteeing the protection is valid.
-#[non_exhaustive]
-struct Family;
-type Domain = haphazard::Domain<Family>;
-type HazardPointer<'domain> = haphazard::HazardPointer<'domain, Family>;
-type HazAtomicPtr<T> = haphazard::AtomicPtr<T, Family>;
 
 /// The number of elements in the first allocation.
 /// Must always be a power of 2.
-pub const FIRST_BUCKET_SIZE: usize = 8;
+pub const FIRST_BUCKET_SIZE: usize = buckets::FIRST_BUCKET_SIZE;
 
+#[cfg(not(any(loom, shuttle)))] // Slots are an internal stand-in under loom and shuttle
 #[allow(clippy::declare_interior_mutable_const)]
-pub const ATOMIC_NULLPTR: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut::<AtomicU64>());
+pub const ATOMIC_NULLPTR: AtomicPtr<Slot> = AtomicPtr::new(core::ptr::null_mut::<Slot>());
+
+/// A lock-free vector over [`Element`] types: `Copy`, at most 8 bytes and without padding.
+///
+/// Replaced descriptors are freed through `R`, hazard pointers by default.
+/// [`crate::qsbr::SecVec`] is this vector with quiescent-state-based reclamation instead.
+///
+/// Every element is stored next to a 64-bit tag and updated with a 128-bit CAS. On targets
+/// without one, such as 32-bit x86 and Arm, those CAS's take a lock and the vector is not
+/// lock-free. [`SecVec::is_lock_free`] tells which one you're on.
+pub struct SecVec<'a, T: Sized + Copy, R = HazardPointers> {
+    core: Core<'a, T, R, ()>,
+}
 
-pub struct SecVec<'a, T: Sized + Copy> {
-    buffers: CachePadded<Box<[AtomicPtr<AtomicU64>; 60]>>,
-    descriptor: CachePadded<HazAtomicPtr<Descriptor<'a, T>>>,
-    domain: Domain,
+/// The buckets and descriptors behind a [`SecVec`].
+///
+/// [`crate::waitfree::SecVec`] is built on them too, and adds announcing and helping on top.
+/// Every descriptor carries an `E` for that, which is finished along with the descriptor's write.
+pub(crate) struct Core<'a, T: Sized + Copy, R, E> {
+    buffers: CachePadded<Buckets<Slot>>,
+    descriptor: CachePadded<AtomicPtr<Descriptor<'a, T, E>>>,
+    reclaim: R,
     _boo: PhantomData<T>, // Data is stored as transmuted T's
 }
 
-struct Descriptor<'a, T: Sized> {
-    pending: HazAtomicPtr<Option<WriteDescriptor<'a, T>>>,
-    size: usize,
+pub(crate) struct Descriptor<'a, T: Sized, E> {
+    pending: AtomicPtr<Option<WriteDescriptor<'a, T>>>,
+    pub(crate) size: usize,
+    pub(crate) payload: E,
 }
 
 struct WriteDescriptor<'a, T: Sized> {
-    new: u64,
-    old: u64,
-    location: &'a AtomicU64,
-    _boo: PhantomData<T>, // New and old are transmuted T's
+    new: u128,
+    old: u128,
+    location: &'a Slot,
+    _boo: PhantomData<T>, // New and old are tagged, transmuted T's
+}
+
+/// Extra state a descriptor carries
+pub(crate) trait Payload: Send + Sync {
+    /// Called by every thread that completes the descriptor, before it can be replaced
+    fn finish(&self);
+}
+
+impl Payload for () {
+    fn finish(&self) {}
 }
 
-impl<'a, T> Descriptor<'a, T> {
-    fn new(pending: *mut Option<WriteDescriptor<'a, T>>, size: usize) -> Self {
+impl<'a, T, E> Descriptor<'a, T, E> {
+    fn new(pending: *mut Option<WriteDescriptor<'a, T>>, size: usize, payload: E) -> Self {
         Descriptor {
-            // # Safety
-            // This is safe because pending is always the result of calling WriteDescriptor::new_*_as_ptr
-            // which used the pointer from Box::into_raw which is guaranteed to be valid.
-            // Descriptors are only reclaimed through hazard pointer mechanisms or Box::from_raw
-            // if they were never shared across threads
-            pending: unsafe { HazAtomicPtr::new(pending) },
+            // pending is always the result of calling WriteDescriptor::new_*_as_ptr,
+            // which used the pointer from Box::into_raw. Write-descriptors are only reclaimed
+            // by retiring them or with Box::from_raw if they were never shared across threads
+            pending: AtomicPtr::new(pending),
             size,
+            payload,
         }
     }
 
-    fn new_as_ptr(pending: *mut Option<WriteDescriptor<'a, T>>, size: usize) -> *mut Self {
-        Box::into_raw(Box::new(Descriptor::new(pending, size)))
+    fn new_as_ptr(
+        pending: *mut Option<WriteDescriptor<'a, T>>,
+        size: usize,
+        payload: E,
+    ) -> *mut Self {
+        Box::into_raw(Box::new(Descriptor::new(pending, size, payload)))
+    }
+
+    /// A descriptor of `size` elements without a pending write
+    pub(crate) fn without_write_as_ptr(size: usize, payload: E) -> *mut Self {
+        Descriptor::new_as_ptr(WriteDescriptor::<T>::new_none_as_ptr(), size, payload)
     }
 }
 
 impl<'a, T> WriteDescriptor<'a, T> {
-    fn new(new: u64, old: u64, location: &'a AtomicU64) -> Self {
+    fn new(new: u128, old: u128, location: &'a Slot) -> Self {
         WriteDescriptor {
             new,
             old,
@@ -80,294 +110,419 @@ impl<'a, T> WriteDescriptor<'a, T> {
         Box::into_raw(Box::new(None))
     }
 
-    fn new_some_as_ptr(new: u64, old: u64, location: &'a AtomicU64) -> *mut Option<Self> {
+    fn new_some_as_ptr(new: u128, old: u128, location: &'a Slot) -> *mut Option<Self> {
         Box::into_raw(Box::new(Some(WriteDescriptor::new(new, old, location))))
     }
+
+    /// Perform the write, if it hasn't been performed already
+    fn execute(&self) {
+        // If cas of actual value fails, someone else did the write
+        // Result of cmpxchng doesn matter
+        //
+        // The tag in the slot means the CAS can only succeed before the write happens,
+        // a helper that was descheduled can't overwrite a newer write to the same slot
+        let _ =
+            self.location
+                .compare_exchange(self.old, self.new, Ordering::AcqRel, Ordering::Relaxed);
+    }
 }
 
-impl<'a, T> fmt::Debug for SecVec<'a, T>
+impl<'a, T, R> fmt::Debug for SecVec<'a, T, R>
 where
     T: Copy,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("SecVec")
+            .field("buffers", &self.core.buffers)
+            .field("descriptor", &self.core.descriptor.load(Ordering::Relaxed))
+            .field("PhantomData", &self.core._boo)
+            .finish()
+    }
+}
+
+impl<'a, T, R, E> fmt::Debug for Core<'a, T, R, E>
+where
+    T: Copy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Core")
             .field("buffers", &self.buffers)
-            .field("descriptor", &self.descriptor)
-            .field("PhantomData", &self._boo)
+            .field("descriptor", &self.descriptor.load(Ordering::Relaxed))
             .finish()
     }
 }
 
-impl<'a, T> SecVec<'a, T>
+impl<'a, T, R, E> Core<'a, T, R, E>
 where
-    T: Sized + Copy + Send + Sync,
+    T: Sized + Element + Send + Sync,
+    R: Reclaim,
+    E: Payload,
 {
-    /// Return of new instance of a SecVec, with capacity 0 and size 0;
-    pub fn new() -> Self {
-        let pending = WriteDescriptor::<T>::new_none_as_ptr();
-        let descriptor = Descriptor::<T>::new_as_ptr(pending, 0);
-        let buffers = Box::new([ATOMIC_NULLPTR; 60]);
-        let domain = Domain::new(&Family {});
+    /// A core with capacity 0 and size 0, whose first descriptor carries `payload`
+    pub(crate) fn new(payload: E) -> Self {
+        let descriptor = Descriptor::<T, E>::without_write_as_ptr(0, payload);
         Self {
-            // # Safety
-            // Constructing HazAtomicPtr is safe because the *mut Descriptor came from Box::into_raw,
-            // and this descriptor can only be reclaimed through hazptr mechanisms (retiring in the same domain)
-            descriptor: CachePadded::new(unsafe { haphazard::AtomicPtr::new(descriptor) }),
-            buffers: CachePadded::new(buffers),
-            domain,
+            // The descriptor came from Box::into_raw, and it's only reclaimed by
+            // retiring it through `reclaim` or when the vector is dropped
+            descriptor: CachePadded::new(AtomicPtr::new(descriptor)),
+            buffers: CachePadded::new(Buckets::new()),
+            reclaim: R::new(),
             _boo: PhantomData,
         }
     }
 
-    /// Return a *const T to the index specified
+    /// The scheme that reclaims the descriptors
+    pub(crate) fn reclaim(&self) -> &R {
+        &self.reclaim
+    }
+
+    /// Return a pointer to the slot at `i`
     ///
     /// # Safety
     /// The index this is called on **must** be a valid index, meaning:
     /// there must already be a bucket allocated which would hold that index
     /// **and** the index must already have been initialized with push/set
-    unsafe fn get(&self, i: usize) -> *const AtomicU64 {
-        // Check for overflow
-        let pos = i
-            .checked_add(FIRST_BUCKET_SIZE)
-            .expect("index too large, integer overflow");
-
-        let hibit = highest_bit(pos);
-
-        let offset = pos ^ (1 << hibit);
+    unsafe fn get(&self, i: usize) -> *const Slot {
+        // # Safety
+        // The caller makes sure the bucket is allocated
+        unsafe { self.buffers.get(i).unwrap_unchecked() }
+    }
 
-        // Select the correct buffer to index into
+    /// Load the current descriptor, which stays valid as long as `guard` is alive
+    pub(crate) fn current<'g>(&self, guard: &'g mut R::Guard<'_>) -> &'g Descriptor<'a, T, E> {
         // # Safety
-        // Since hibit = highest_bit(pos), and pos >= FIRST_BUCKET_SIZE
-        // The subtraction hibit - highest_bit(FIRST_BUCKET_SIZE) cannot underflow
-        let buffer = &self.buffers[(hibit - highest_bit(FIRST_BUCKET_SIZE)) as usize];
-
-        // Check that the offset doesn't exceed isize::MAX
-        assert!(
-            offset
-                .checked_mul(mem::size_of::<T>())
-                .map(|val| val < isize::MAX as usize)
-                .is_some(),
-            "pointer offset exceed isize::MAX bytes"
-        );
+        // The descriptor is never null, and once it's replaced it's only freed through
+        // `self.reclaim`, which can't free it while the guard protects it
+        unsafe { &*guard.protect(&self.descriptor) }
+    }
 
-        // Offset the pointer to return a pointer to the correct element
-        unsafe {
-            // # Safety
-            // We know that we can offset the pointer because we will have allocated a bucket
-            // to store the value. Since we only call values that are `self.descriptor.size` or smaller,
-            // we know the offset will not go out of bounds because of the assert.
-            buffer.load(Ordering::Acquire).add(offset)
+    /// Complete the write `desc` carries if it has one, then finish its payload
+    ///
+    /// Every thread calls this on a descriptor before trying to replace it.
+    pub(crate) fn complete(&self, desc: &Descriptor<'a, T, E>) {
+        // The guard is dropped before `desc` is, so the use of the write-descriptor doesn't
+        // outlive the use of `desc`. When `desc` is dropped nothing refers to its write-descriptor.
+        {
+            let mut guard = self.reclaim.guard();
+            let pending = guard.protect(&desc.pending);
+            self.complete_write(desc, pending);
         }
+        desc.payload.finish();
     }
 
-    /// Complete the given write operation, set the current write operation to None
-    fn complete_write(&self, pending: *mut Option<WriteDescriptor<T>>) {
-        // If cas of actual value fails, someone else did the write
-        // Result of cmpxchng doesn matter
+    /// Complete the given write operation, and clear it from the descriptor it belongs to
+    ///
+    /// This has to clear `desc`'s write-descriptor and not the current descriptor's, a thread
+    /// that was descheduled after loading `desc` would otherwise throw away a newer write
+    /// before it happens.
+    fn complete_write(
+        &self,
+        desc: &Descriptor<'a, T, E>,
+        pending: *mut Option<WriteDescriptor<'a, T>>,
+    ) {
+        // # Safety
+        // The write-descriptor was protected when it was loaded from `desc`
         if let Some(writedesc) = unsafe { &*pending } {
-            let _ = AtomicU64::compare_exchange(
-                writedesc.location,
-                writedesc.old,
-                writedesc.new,
-                Ordering::AcqRel,
-                Ordering::Relaxed,
-            );
+            writedesc.execute();
 
             let new_writedesc = WriteDescriptor::<T>::new_none_as_ptr();
 
-            let mut hp = HazardPointer::new_in_domain(&self.domain);
+            match desc.pending.compare_exchange(
+                pending,
+                new_writedesc,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                // # Safety
+                // We are the only thread that will retire this pointer because
+                // only one thread can succeed in swapping it out (this one).
+                Ok(old) => unsafe { self.reclaim.retire(old) },
+                // Someone else already cleared the write-descriptor
+                // # Safety
+                // The pointer never became visible to other threads
+                Err(_) => drop(unsafe { Box::from_raw(new_writedesc) }),
+            }
+        }
+    }
 
-            let old = unsafe {
-                self.descriptor
-                    .load(&mut hp)
-                    .unwrap()
-                    .pending
-                    // # Safety
-                    // new_writedesc conforms to the requirements of HazAtomicPtr::new()
-                    // because it comes from Box::into_raw and is a valid WriteDescriptor
-                    .swap_ptr(new_writedesc)
-            };
+    /// Try to replace `current_desc` with `next_desc`, and complete it if that worked.
+    /// `next_desc` is freed if it didn't.
+    pub(crate) fn install(
+        &self,
+        current_desc: &Descriptor<'a, T, E>,
+        next_desc: *mut Descriptor<'a, T, E>,
+    ) -> bool {
+        // Protect the new descriptor before other threads can see it, so that it can't be
+        // retired and reclaimed before we are done completing its write
+        let mut guard = self.reclaim.guard();
+        guard.protect_raw(next_desc);
+
+        if let Ok(replaced) = self.descriptor.compare_exchange_weak(
+            current_desc as *const _ as *mut _,
+            next_desc,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            // Another thread may already have completed the write and swapped the
+            // write-descriptor out, so `complete` loads it with protection
+            // # Safety
+            // The guard protects the new descriptor
+            self.complete(unsafe { &*next_desc });
 
             // # Safety
-            // We are the only thread that will retire this pointer because
-            // only one thread can get the result of the swap (this one).
-            // Two threads couldn't have performed a swap and both got this pointer.
-            unsafe { old.unwrap().retire_in(&self.domain) };
+            // Since the we only retire when swapping out a pointer, this is the only thread that will
+            // retire, since only one thread receives the result of the swap (this one)
+            //
+            // There will never be another load call to the ptr because all calls will go the new one.
+            // Since all uses of the inner wdesc are contained within the lifetime of the reference
+            // to the desc, there will also be no new loads on the inner wdesc.
+            unsafe { self.reclaim.retire(replaced) };
+            return true;
         }
+
+        // Deallocate the write_desc and desc that we failed to swap in
+        // # Safety
+        // Box the write_desc and desc ptrs were made from Box::into_raw, so it is safe to Box::from_raw
+        unsafe {
+            // Note: the inner wdesc also get's dropped as part of the desc's drop impl
+            drop(Box::from_raw(next_desc));
+        }
+        false
     }
 
-    pub fn push(&self, elem: T) {
+    /// A descriptor on top of `current_desc` that pushes `elem`, a packed T
+    ///
+    /// Allocates the bucket the element goes in if it isn't yet.
+    pub(crate) fn push_descriptor(
+        &self,
+        current_desc: &Descriptor<'a, T, E>,
+        elem: u64,
+        payload: E,
+    ) -> *mut Descriptor<'a, T, E> {
+        // Allocate the bucket the slot past the end is in if needed
+        let last_elem = match self.buffers.get_or_allocate(current_desc.size) {
+            Ok(slot) => slot as *const Slot,
+            Err(err) => handle_error(err),
+        };
+        // # Safety
+        // Buckets aren't freed before the vector is, and descriptors don't outlive it
+        let last_elem = unsafe { &*last_elem };
+
+        // Load from the slot, which really containes the bytes for T and a tag
+        let old = last_elem.load(Ordering::Acquire);
+
+        let next_write_desc =
+            WriteDescriptor::<T>::new_some_as_ptr(slot::next(old, elem), old, last_elem);
+
+        Descriptor::new_as_ptr(next_write_desc, current_desc.size + 1, payload)
+    }
+
+    /// The last element of the vector `desc` describes, packed, or `None` if it's empty
+    ///
+    /// `desc` has to be completed first, or the element may not have been written yet.
+    pub(crate) fn last(&self, desc: &Descriptor<'a, T, E>) -> Option<u64> {
+        let index = desc.size.checked_sub(1)?;
+        // # Safety
+        // The index is smaller than the size, so its bucket has been allocated and it has been written to
+        Some(slot::value(
+            unsafe { &*self.get(index) }.load(Ordering::Acquire),
+        ))
+    }
+
+    pub(crate) fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        // Don't even try to allocate if the elements can't fit in isize::MAX bytes
+        if size
+            .checked_mul(mem::size_of::<Slot>())
+            .is_none_or(|bytes| bytes > isize::MAX as usize)
+        {
+            return Err(TryReserveErrorKind::CapacityOverflow.into());
+        }
+        self.buffers.try_reserve(size)
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        let mut guard = self.reclaim.guard();
+        let desc = self.current(&mut guard);
+
+        // The pending write may be a push's or an update's, so it can't tell whether the size
+        // counts an element that isn't there yet. Once it's done, the size is exact.
+        self.complete(desc);
+
+        desc.size
+    }
+
+    pub(crate) fn read(&self, index: usize) -> Option<T> {
+        let mut guard = self.reclaim.guard();
+        let desc = self.current(&mut guard);
+
+        // If the last push hasn't written its element yet, the slot still holds an old value
+        self.complete(desc);
+
+        if index >= desc.size {
+            return None;
+        }
+
+        // # Safety
+        // The index is smaller than the size, so its bucket has been allocated and it has been written to
+        let elem = slot::value(unsafe { &*self.get(index) }.load(Ordering::Acquire));
+
+        // # Safety
+        // Everything in the vector was packed from a valid T by push
+        Some(unsafe { slot::unpack(elem) })
+    }
+}
+
+// The lock-free vector's own operations, its descriptors don't carry anything
+impl<'a, T, R> Core<'a, T, R, ()>
+where
+    T: Sized + Element + Send + Sync,
+    R: Reclaim,
+{
+    fn push(&self, elem: T) {
         let backoff = Backoff::new(); // Backoff causes significant speedup
         loop {
-            let mut dhp = HazardPointer::new_in_domain(&self.domain);
-            let current_desc = unsafe { self.descriptor.load(&mut dhp) }
-                .expect("invalid ptr for descriptor in push");
-
-            // Use a block to make explicit that the use of the wdesc does not outlive the use of the desc.
-            // This means that when the desc is dropped, there will be no references to the wdesc inside.
-            // And we can deallocate the wdesc with `Box::from_raw`
-            {
-                let mut wdhp = HazardPointer::new_in_domain(&self.domain);
-                let pending = unsafe { current_desc.pending.load(&mut wdhp) }
-                    .expect("invalid ptr from write-desc in push");
-
-                self.complete_write(pending as *const _ as *mut _);
-                // Hazard pointer is dropped, protection ends
-            }
+            let mut guard = self.reclaim.guard();
+            let current_desc = self.current(&mut guard);
 
-            // If we need more memory, calculate the bucket
-            let bucket = (highest_bit(current_desc.size + FIRST_BUCKET_SIZE)
-                - highest_bit(FIRST_BUCKET_SIZE)) as usize;
-            // Allocate it
-            if self.buffers[bucket].load(Ordering::Acquire).is_null() {
-                self.allocate_bucket(bucket)
-            }
+            self.complete(current_desc);
 
-            let last_elem = unsafe { &*self.get(current_desc.size) };
-
-            let next_write_desc = WriteDescriptor::<T>::new_some_as_ptr(
-                // TODO: address this in macro
-                // # Safety
-                // The `transmute_copy` is safe because we have ensured that T is the correct size at compile time
-                unsafe { mem::transmute_copy::<T, u64>(&elem) },
-                // Load from the AtomicU64, which really containes the bytes for T
-                last_elem.load(Ordering::Acquire),
-                last_elem,
-            );
-
-            let next_desc = Descriptor::<T>::new_as_ptr(next_write_desc, current_desc.size + 1);
-
-            if let Ok(replaced) = unsafe {
-                HazAtomicPtr::compare_exchange_weak_ptr(
-                    // # Safety
-                    // Safe because the pointer we swap in points to a valid object that is !null
-                    &self.descriptor,
-                    current_desc as *const _ as *mut _,
-                    next_desc,
-                )
-            } {
-                self.complete_write(next_write_desc);
+            let next_desc = self.push_descriptor(current_desc, slot::pack(elem), ());
 
-                // # Safety
-                // Since the we only retire when swapping out a pointer, this is the only thread that will
-                // retire, since only one thread receives the result of the swap (this one)
-                //
-                // There will never be another load call to the ptr because all calls will go the new one.
-                // Since all uses of the inner wdesc are contained within the lifetime of the reference
-                // to the desc, there will also be no new loads on the inner wdesc.
-                unsafe {
-                    replaced.unwrap().retire_in(&self.domain);
-                }
+            if self.install(current_desc, next_desc) {
                 break;
             }
 
-            // Deallocate the write_desc and desc that we failed to swap in
-            // # Safety
-            // Box the write_desc and desc ptrs were made from Box::into_raw, so it is safe to Box::from_raw
-            unsafe {
-                // Note: the inner wdesc also get's dropped as part of the desc's drop impl
-                Box::from_raw(next_desc);
-            }
-
             backoff.spin();
         }
     }
 
-    pub fn pop(&self) -> Option<T> {
+    /// Try to replace `current_desc` with a descriptor of `size` elements whose pending write
+    /// changes `location` from `old` to `new`, and complete that write if it worked
+    fn try_write(
+        &self,
+        current_desc: &Descriptor<'a, T, ()>,
+        location: &'a Slot,
+        old: u128,
+        new: u128,
+        size: usize,
+    ) -> bool {
+        let next_write_desc = WriteDescriptor::<T>::new_some_as_ptr(new, old, location);
+
+        let next_desc = Descriptor::new_as_ptr(next_write_desc, size, ());
+
+        self.install(current_desc, next_desc)
+    }
+
+    fn pop(&self) -> Option<T> {
         let backoff = Backoff::new(); // Backoff causes significant speedup
         loop {
-            let mut dhp = HazardPointer::new_in_domain(&self.domain);
-            let current_desc = unsafe { self.descriptor.load(&mut dhp) }
-                .expect("invalid ptr for descriptor in pop");
-
-            // Use a block to make explicit that the use of the wdesc does not outlive the use of the desc.
-            // This means that when the desc is dropped, there will be no references to the wdesc inside.
-            // And we can deallocate the wdesc with `Box::from_raw`
-            {
-                let mut wdhp = HazardPointer::new_in_domain(&self.domain);
-                let pending = unsafe { current_desc.pending.load(&mut wdhp) }
-                    .expect("invalid ptr for write-descriptor in pop");
-
-                self.complete_write(pending as *const _ as *mut _);
-                // Hazard pointer is dropped, protection ends
-            }
+            let mut guard = self.reclaim.guard();
+            let current_desc = self.current(&mut guard);
 
-            if current_desc.size == 0 {
-                return None;
-            }
+            self.complete(current_desc);
 
             // TODO: add safety comment
             // Consider if new desc is swapped in, can we read dealloced memory?
-            // Do not need to worry about underflow for the sub because we would have already returned
-            let elem = unsafe { &*self.get(current_desc.size - 1) }.load(Ordering::Acquire);
-
-            let new_pending = WriteDescriptor::<T>::new_none_as_ptr();
-
-            let next_desc = Descriptor::<T>::new_as_ptr(new_pending, current_desc.size - 1);
-
-            if let Ok(replaced) = unsafe {
-                HazAtomicPtr::compare_exchange_weak_ptr(
-                    // # Safety
-                    // Safe because the pointer we swap in points to a valid object that is !null
-                    &self.descriptor,
-                    current_desc as *const _ as *mut _,
-                    next_desc,
-                )
-            } {
-                // # Safety
-                // Since the we only retire when swapping out a pointer, this is the only thread that will
-                // retire, since only one thread receives the result of the swap (this one)
-                //
-                // There will never be another load call to the ptr because all calls will go the new one.
-                // Since all uses of the inner wdesc are contained within the lifetime of the reference
-                // to the desc, there will also be no new loads on the inner wdesc.
-                unsafe {
-                    replaced.unwrap().retire_in(&self.domain);
-                }
+            let elem = self.last(current_desc)?;
 
+            // Do not need to worry about underflow for the sub because `last` would have returned None
+            let next_desc = Descriptor::without_write_as_ptr(current_desc.size - 1, ());
+
+            if self.install(current_desc, next_desc) {
                 // # Safety
-                // TODO: address this in macro
-                // This is ok because we ensure T is the correct size at compile time
-                // We also know that elem is a valid T because it was transmuted into a usize
-                // from a valid T, therefore we are only transmuting it back
-                return Some(unsafe { mem::transmute_copy::<u64, T>(&elem) });
+                // Everything in the vector was packed from a valid T by push
+                return Some(unsafe { slot::unpack(elem) });
+            }
+
+            backoff.spin();
+        }
+    }
+
+    fn fetch_update<F>(&self, index: usize, mut f: F) -> Option<T>
+    where
+        F: FnMut(T) -> T,
+    {
+        let backoff = Backoff::new();
+        loop {
+            let mut guard = self.reclaim.guard();
+            let current_desc = self.current(&mut guard);
+
+            self.complete(current_desc);
+
+            if index >= current_desc.size {
+                return None;
             }
 
-            // Deallocate the write_desc and desc that we failed to swap in
             // # Safety
-            // Box the write_desc and desc ptrs were made from Box::into_raw, so it is safe to Box::from_raw
-            unsafe {
-                // Note: the inner wdesc also get's dropped as part of the desc's drop impl
-                Box::from_raw(next_desc);
+            // The index is smaller than the size, so its bucket has been allocated and it has been written to
+            let location = unsafe { &*self.get(index) };
+            // Nothing writes to the slot without replacing the descriptor first,
+            // so this is still the element if the descriptor CAS succeeds
+            let old = location.load(Ordering::Acquire);
+            // # Safety
+            // Everything in the vector was packed from a valid T by push
+            let elem = unsafe { slot::unpack(slot::value(old)) };
+            let new = slot::next(old, slot::pack(f(elem)));
+
+            if self.try_write(current_desc, location, old, new, current_desc.size) {
+                return Some(elem);
             }
 
             backoff.spin();
         }
     }
+}
 
-    pub fn reserve(&self, size: usize) {
-        // Cache the size to prevent another atomic op from due to calling `size()` again
-        let current_size = self.size();
-        if current_size == 0 {
-            self.allocate_bucket(0);
+impl<'a, T, R> SecVec<'a, T, R>
+where
+    T: Sized + Element + Send + Sync,
+    R: Reclaim,
+{
+    /// Return of new instance of a SecVec, with capacity 0 and size 0;
+    pub fn new() -> Self {
+        Self {
+            core: Core::new(()),
         }
+    }
+
+    /// The scheme that reclaims this vector's descriptors
+    pub(crate) fn reclaim(&self) -> &R {
+        self.core.reclaim()
+    }
 
-        // Number of allocations needed for current size
-        let mut num_current_allocs =
-            highest_bit(current_size.saturating_add(FIRST_BUCKET_SIZE) - 1)
-                .saturating_sub(highest_bit(FIRST_BUCKET_SIZE));
+    pub fn push(&self, elem: T) {
+        self.core.push(elem)
+    }
 
-        // Compare with the number of allocations needed for size `new`
-        while num_current_allocs
-            < highest_bit(size.saturating_add(FIRST_BUCKET_SIZE) - 1)
-                .saturating_sub(highest_bit(FIRST_BUCKET_SIZE))
-        {
-            num_current_allocs += 1;
-            self.allocate_bucket(num_current_allocs as usize);
-        }
+    pub fn pop(&self) -> Option<T> {
+        self.core.pop()
+    }
+
+    /// Allocate buckets for `size` elements, so pushes up to that size don't allocate.
+    ///
+    /// # Panics
+    /// If `size` elements can't fit in `isize::MAX` bytes. That is checked before anything is
+    /// allocated, so a huge `size` doesn't allocate every bucket it can first. If the allocator
+    /// fails, this aborts through `handle_alloc_error`. `try_reserve` reports both instead.
+    pub fn reserve(&self, size: usize) {
+        handle_reserve(self.try_reserve(size))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    ///
+    /// Buckets allocated before the failure stay part of the vector.
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let sv = SecVec::<u64>::new();
+    /// assert!(sv.try_reserve(100).is_ok());
+    /// assert!(sv.try_reserve(usize::MAX).is_err());
+    /// ```
+    pub fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        self.core.try_reserve(size)
     }
 
-    /// Return the size of the vector, taking into account a pending write operation
+    /// Return the size of the vector, completing a pending write operation first
     /// ```rust
     /// # use unlocked::sealed::SecVec;
     /// let sv = SecVec::<isize>::new();
@@ -377,151 +532,187 @@ where
     /// assert_eq!(sv.size(), 1);
     /// ```
     pub fn size(&self) -> usize {
-        let mut dhp = HazardPointer::new_in_domain(&self.domain);
-        let desc = unsafe { self.descriptor.load(&mut dhp) }
-            .expect("invalid pointer for descriptor in size");
+        self.core.size()
+    }
 
-        let size = desc.size;
+    /// Return whether the elements are updated with a native 128-bit CAS on this CPU.
+    ///
+    /// If not, every update of an element takes one of portable-atomic's global locks,
+    /// and a thread that is preempted while holding it blocks the others.
+    pub fn is_lock_free() -> bool {
+        Slot::is_lock_free()
+    }
 
-        // If there is a pending descriptor, we subtract one from the size because
-        // `push` increments the size, swaps the new descriptor in, and _then_ writes the value
-        // Therefore the size is one greater because the write hasn't happened yet
-        // # Safety
-        // Descriptors available to multiple threads are always retired through &self.domain
-        let mut wdhp = HazardPointer::new_in_domain(&self.domain);
-        match unsafe { desc.pending.load(&mut wdhp) }
-            .expect("invalid ptr reading write-desc in size")
-        {
-            Some(_) => size - 1,
-            None => size,
-        }
+    /// Return the element at `index`, or `None` if the vector isn't that long
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let sv = SecVec::<isize>::new();
+    /// sv.push(-1);
+    /// assert_eq!(sv.read(0), Some(-1));
+    /// assert_eq!(sv.read(1), None);
+    /// ```
+    pub fn read(&self, index: usize) -> Option<T> {
+        self.core.read(index)
     }
 
-    fn allocate_bucket(&self, bucket: usize) {
-        // The shift-left is equivalent to raising 2 to the power of bucket
-        let size = FIRST_BUCKET_SIZE * (1 << bucket);
-        let layout = match Layout::array::<AtomicU64>(size) {
-            Ok(layout) => layout,
-            Err(_) => capacity_overflow(),
-        };
+    /// Replace the element at `index` with `f` applied to it, and return the old element,
+    /// or `None` if the vector isn't that long.
+    ///
+    /// Like a push, this installs a new descriptor, so it's linearizable with every other
+    /// operation. `f` may be called more than once if other threads get in the way.
+    ///
+    /// That also means updates to different indices don't run in parallel: every update, push and
+    /// pop replaces the same descriptor, and all but one of the threads racing to do it retry.
+    /// For counters that many threads update, use [`crate::counters::SecCounters`], where an
+    /// update is a single atomic operation on the counter.
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let sv = SecVec::<u32>::new();
+    /// sv.push(3);
+    /// assert_eq!(sv.fetch_update(0, |x| x * 2), Some(3));
+    /// assert_eq!(sv.read(0), Some(6));
+    /// assert_eq!(sv.fetch_update(1, |x| x * 2), None);
+    /// ```
+    pub fn fetch_update<F>(&self, index: usize, f: F) -> Option<T>
+    where
+        F: FnMut(T) -> T,
+    {
+        self.core.fetch_update(index, f)
+    }
 
-        // Make sure allocation is ok
-        match alloc_guard(layout.size()) {
-            Ok(_) => {}
-            Err(_) => capacity_overflow(),
-        }
+    /// Add `val` to the element at `index` and return the old element,
+    /// or `None` if the vector isn't that long. Integers wrap around on overflow.
+    ///
+    /// This and the other `fetch_*` methods are `fetch_update`s: they install a descriptor,
+    /// they are not an atomic instruction on the element.
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let counters = SecVec::<u64>::new();
+    /// counters.push(0);
+    /// counters.fetch_add(0, 5);
+    /// assert_eq!(counters.fetch_add(0, 1), Some(5));
+    /// assert_eq!(counters.read(0), Some(6));
+    /// ```
+    pub fn fetch_add(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Arithmetic,
+    {
+        self.fetch_update(index, |elem| elem.add(val))
+    }
 
-        let allocator = Global;
+    /// Subtract `val` from the element at `index` and return the old element,
+    /// or `None` if the vector isn't that long. Integers wrap around on overflow.
+    pub fn fetch_sub(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Arithmetic,
+    {
+        self.fetch_update(index, |elem| elem.sub(val))
+    }
 
-        // The reason for using allocate_zeroed is that miri complains about accessing uninitialized memory otherwise
-        //
-        // The situation is when we allocate the memory, and then try to CAS a new value in:
-        // (AcqRel, Relaxed) => intrinsics::atomic_cxchg_acqrel_failrelaxed(dst, old, new),
-        //                      ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^ using uninitialized data, 
-        //                                                                                 but this operation requires initialized memory
-        // This shouldn't be an actual issue since the old value is never use, so might switch back to allocate (regular)
-        // TODO: Maybe use MaybeUninit?
-        let allocation = allocator.allocate_zeroed(layout);
-        let ptr = match allocation {
-            Ok(ptr) => ptr.as_ptr() as *mut AtomicU64,
-            Err(_) => handle_alloc_error(layout),
-        };
+    /// Replace the element at `index` with the larger of it and `val` and return the old element,
+    /// or `None` if the vector isn't that long
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let peaks = SecVec::<f64>::new();
+    /// peaks.push(1.5);
+    /// peaks.fetch_max(0, 0.5);
+    /// peaks.fetch_max(0, 2.5);
+    /// assert_eq!(peaks.read(0), Some(2.5));
+    /// ```
+    pub fn fetch_max(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Arithmetic,
+    {
+        self.fetch_update(index, |elem| elem.max(val))
+    }
 
-        // If the CAS fails, then the bucket has already been initalized with memory
-        // and we free the memory we just allocated
-        if self.buffers[bucket]
-            .compare_exchange(
-                ptr::null_mut::<AtomicU64>(),
-                ptr,
-                Ordering::AcqRel,
-                Ordering::Relaxed,
-            )
-            .is_err()
-        {
-            unsafe {
-                // # Safety
-                // We know that the pointer returned from the allocation is NonNull
-                // so we can call unwrap() on NonNull::new(). We also know that the pointer
-                // is pointing to the correct memory because we just got it from the allocation.
-                // We know the layout is valid, as it is the same layout we used to allocate.
-                allocator.deallocate(NonNull::new(ptr as *mut u8).unwrap(), layout);
-            }
-        }
+    /// Replace the element at `index` with the smaller of it and `val` and return the old element,
+    /// or `None` if the vector isn't that long
+    pub fn fetch_min(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Arithmetic,
+    {
+        self.fetch_update(index, |elem| elem.min(val))
+    }
+
+    /// Bitwise and `val` into the element at `index` and return the old element,
+    /// or `None` if the vector isn't that long
+    pub fn fetch_and(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Bitwise,
+    {
+        self.fetch_update(index, |elem| elem.and(val))
+    }
+
+    /// Bitwise or `val` into the element at `index` and return the old element,
+    /// or `None` if the vector isn't that long
+    /// ```rust
+    /// # use unlocked::sealed::SecVec;
+    /// let flags = SecVec::<u8>::new();
+    /// flags.push(0b01);
+    /// assert_eq!(flags.fetch_or(0, 0b10), Some(0b01));
+    /// assert_eq!(flags.read(0), Some(0b11));
+    /// ```
+    pub fn fetch_or(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Bitwise,
+    {
+        self.fetch_update(index, |elem| elem.or(val))
+    }
+
+    /// Bitwise xor `val` into the element at `index` and return the old element,
+    /// or `None` if the vector isn't that long
+    pub fn fetch_xor(&self, index: usize, val: T) -> Option<T>
+    where
+        T: Bitwise,
+    {
+        self.fetch_update(index, |elem| elem.xor(val))
     }
 }
 
-impl<'a, T> Default for SecVec<'a, T>
+impl<'a, T, R> Default for SecVec<'a, T, R>
 where
-    T: Copy + Sync + Send,
+    T: Element + Sync + Send,
+    R: Reclaim,
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<T> Drop for SecVec<'_, T>
+impl<T, R, E> Drop for Core<'_, T, R, E>
 where
     T: Copy,
 {
     fn drop(&mut self) {
-        // Drop buffers
-        let allocator = Global;
-        for (bucket, ptr) in self
-            .buffers
-            .iter()
-            .filter(|ptr| !ptr.load(Ordering::Relaxed).is_null())
-            .enumerate()
-        // Getting all non-null buckets
-        {
-            let size = FIRST_BUCKET_SIZE * (1 << bucket);
-            let layout = match Layout::array::<AtomicU64>(size) {
-                Ok(layout) => layout,
-                Err(_) => capacity_overflow(),
-            };
-            unsafe {
-                // # Safety
-                // We have recreated the exact same layout used to alloc the ptr in `allocate_bucket`
-                // We know the ptr isn't null becase of the filer
-                allocator.deallocate(
-                    NonNull::new(ptr.load(Ordering::Relaxed) as *mut u8).unwrap(),
-                    layout,
-                )
-            };
-        }
-
         // Retiring the current desc and wdesc
         // # Safety
         // Since we have &mut self, we have exclusive access, so we can retire the desc and wdesc ptrs.
         // It is safe to deref the ptr to the desc because it is valid because it was created with
         // Descriptor::new_as_ptr.
-        let desc = self.descriptor.load_ptr();
+        let desc = self.descriptor.load(Ordering::Relaxed);
         unsafe {
-            Box::from_raw(desc);
+            drop(Box::from_raw(desc));
         };
     }
 }
 
-impl<T> Drop for Descriptor<'_, T> {
+impl<T, E> Drop for Descriptor<'_, T, E> {
     fn drop(&mut self) {
         // # Safety
         // The pointer is valid because it's from Box::into_raw
-        // We must also ensure ref to wdesc never outlasts ref to desc
-        unsafe {
-            Box::from_raw(
-                self.pending
-                    .swap_ptr(ptr::null_mut())
-                    .unwrap()
-                    .into_inner()
-                    .as_ptr(),
-            );
-        }
+        // Write-descriptors that were swapped out were retired separately
+        drop(unsafe { Box::from_raw(self.pending.load(Ordering::Relaxed)) });
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(any(loom, shuttle))))]
 mod tests {
     use super::*;
     extern crate std;
+    use crate::failing_alloc;
+    use crate::linearizability::{History, Recorder};
+    use crate::reclaim::raw::Scheme;
     use std::sync::atomic::{AtomicIsize, Ordering};
     use std::sync::Arc;
     use std::thread::{self, JoinHandle};
@@ -538,6 +729,22 @@ mod tests {
         assert_eq!(sv.pop(), None);
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    #[cfg_attr(miri, ignore)] // Miri's 128-bit atomics don't depend on the CPU
+    fn lock_free_with_cmpxchg16b() {
+        assert_eq!(
+            SecVec::<u64>::is_lock_free(),
+            std::is_x86_feature_detected!("cmpxchg16b")
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86")]
+    fn not_lock_free_on_32_bit_x86() {
+        assert!(!SecVec::<u64>::is_lock_free());
+    }
+
     #[test]
     fn ten_push_ten_pop() {
         let sv = SecVec::<isize>::new();
@@ -552,9 +759,7 @@ mod tests {
     #[test]
     fn does_not_allocate_buffers_on_new() {
         let sv = SecVec::<isize>::new();
-        for buffer in &**sv.buffers {
-            assert!(buffer.load(Ordering::Relaxed).is_null())
-        }
+        assert_eq!(sv.core.buffers.allocated().count(), 0);
     }
 
     #[test]
@@ -573,7 +778,6 @@ mod tests {
                     }
                 })
             })
-            .into_iter()
             .collect::<Vec<JoinHandle<_>>>();
         handles.into_iter().for_each(|h| h.join().unwrap());
         #[allow(clippy::needless_collect)]
@@ -587,16 +791,549 @@ mod tests {
                     }
                 })
             })
-            .into_iter()
             .collect::<Vec<JoinHandle<_>>>();
         handles.into_iter().for_each(|h| h.join().unwrap());
     }
 
     #[cfg(not(miri))] // Too slow
     #[test]
-    #[should_panic] // The allocation is too large, will SIGABRT
+    // Caught before allocating, otherwise the allocator would fail and abort the tests
+    #[should_panic(expected = "Capacity overflowed")]
     fn reserve_usize_max() {
         let sv = SecVec::<isize>::new();
         sv.reserve(usize::MAX)
     }
+
+    #[test]
+    fn narrow_elements() {
+        let sv = SecVec::<i8>::new();
+        for i in -10..10 {
+            sv.push(i);
+        }
+        assert_eq!(sv.read(0), Some(-10));
+        for i in (-10..10).rev() {
+            assert_eq!(sv.pop(), Some(i));
+        }
+        assert_eq!(sv.pop(), None);
+    }
+
+    #[test]
+    fn read_returns_pushed_elements() {
+        let sv = SecVec::<isize>::new();
+        assert_eq!(sv.read(0), None);
+        for i in 0..20 {
+            sv.push(i);
+        }
+        for i in 0..20 {
+            assert_eq!(sv.read(i as usize), Some(i));
+        }
+        assert_eq!(sv.read(20), None);
+        sv.pop();
+        assert_eq!(sv.read(19), None);
+    }
+
+    #[test]
+    fn the_big_multithread_is_linearizable() {
+        let sv = SecVec::<u64>::new();
+        let recorder = Recorder::new();
+        let logs = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|thread| {
+                    let (sv, recorder) = (&sv, &recorder);
+                    s.spawn(move || {
+                        let mut log = recorder.log(thread);
+                        for i in 0..1000 {
+                            match i % 5 {
+                                0 | 1 => log.push((thread * 1000 + i) as u64, |elem| sv.push(elem)),
+                                2 => drop(log.pop(|| sv.pop())),
+                                3 => drop(log.size(|| sv.size())),
+                                _ => drop(log.read(i % 8, |index| sv.read(index))),
+                            }
+                        }
+                        log
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        History::new(logs).assert_linearizable();
+    }
+
+    #[test]
+    fn fetch_ops_on_each_type() {
+        let ints = SecVec::<i32>::new();
+        ints.push(10);
+        assert_eq!(ints.fetch_add(0, 5), Some(10));
+        assert_eq!(ints.fetch_sub(0, 20), Some(15));
+        assert_eq!(ints.fetch_max(0, 3), Some(-5));
+        assert_eq!(ints.fetch_min(0, -7), Some(3));
+        assert_eq!(ints.fetch_add(0, i32::MAX), Some(-7));
+        assert_eq!(ints.read(0), Some((-7i32).wrapping_add(i32::MAX)));
+        assert_eq!(ints.fetch_add(1, 1), None);
+
+        let bits = SecVec::<u16>::new();
+        bits.push(0b1100);
+        assert_eq!(bits.fetch_and(0, 0b0110), Some(0b1100));
+        assert_eq!(bits.fetch_or(0, 0b0001), Some(0b0100));
+        assert_eq!(bits.fetch_xor(0, 0b1111), Some(0b0101));
+        assert_eq!(bits.read(0), Some(0b1010));
+
+        let floats = SecVec::<f32>::new();
+        floats.push(1.0);
+        assert_eq!(floats.fetch_add(0, 0.5), Some(1.0));
+        assert_eq!(floats.fetch_max(0, f32::NAN), Some(1.5));
+        assert_eq!(floats.read(0), Some(1.5));
+
+        let flags = SecVec::<bool>::new();
+        flags.push(false);
+        assert_eq!(flags.fetch_or(0, true), Some(false));
+        assert_eq!(flags.fetch_xor(0, true), Some(true));
+        assert_eq!(flags.read(0), Some(false));
+        // Updates don't change the size
+        assert_eq!(flags.size(), 1);
+    }
+
+    #[test]
+    fn concurrent_fetch_add_counts_exactly() {
+        let counters = SecVec::<u64>::new();
+        for _ in 0..4 {
+            counters.push(0);
+        }
+        thread::scope(|s| {
+            for t in 0..4 {
+                let counters = &counters;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        counters.fetch_add((t + i) % 4, 1).unwrap();
+                    }
+                });
+            }
+        });
+        for i in 0..4 {
+            assert_eq!(counters.read(i), Some(1000));
+        }
+    }
+
+    #[test]
+    fn fetch_add_during_push_and_pop() {
+        let sv = SecVec::<u64>::new();
+        sv.push(0);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        assert!(sv.fetch_add(0, 1).is_some());
+                    }
+                });
+                s.spawn(|| {
+                    for i in 0..1000 {
+                        sv.push(i + 1_000_000);
+                        // Something above index 0 is always there to pop
+                        assert!(sv.pop().unwrap() >= 1_000_000);
+                    }
+                });
+            }
+        });
+        assert_eq!(sv.size(), 1);
+        assert_eq!(sv.read(0), Some(2000));
+    }
+
+    #[test]
+    fn fetch_add_is_linearizable() {
+        let sv = SecVec::<u64>::new();
+        let recorder = Recorder::new();
+        let logs = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|thread| {
+                    let (sv, recorder) = (&sv, &recorder);
+                    s.spawn(move || {
+                        let mut log = recorder.log(thread);
+                        for i in 0..1000 {
+                            match i % 4 {
+                                0 => log.push(i as u64, |elem| sv.push(elem)),
+                                1 => drop(
+                                    log.fetch_add(i % 3, 1, |index, val| sv.fetch_add(index, val)),
+                                ),
+                                2 => drop(log.pop(|| sv.pop())),
+                                _ => drop(log.read(i % 3, |index| sv.read(index))),
+                            }
+                        }
+                        log
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        History::new(logs).assert_linearizable();
+    }
+
+    #[test]
+    fn stale_write_completion_does_not_overwrite_newer_write() {
+        let sv = SecVec::<u64>::new();
+        sv.push(1);
+        // A helper that loaded this write-descriptor before the slot was popped and
+        // rewritten with the same bits must not be able to complete it afterwards
+        let location = unsafe { &*sv.core.get(1) };
+        let old = location.load(Ordering::Acquire);
+        let stale = WriteDescriptor::<u64>::new(slot::next(old, 2), old, location);
+        sv.push(2);
+        assert_eq!(sv.pop(), Some(2));
+        sv.push(0);
+        stale.execute();
+        assert_eq!(sv.pop(), Some(0));
+    }
+
+    #[test]
+    fn try_reserve_reports_allocation_failure() {
+        let ((), stats) = failing_alloc::measure(|| {
+            // Reserving 1000 elements allocates buckets 1 through 6, fail each of them in turn
+            for n in 0..6 {
+                let sv = SecVec::<u64>::new();
+                sv.push(1);
+                let err = failing_alloc::fail_nth(n, || sv.try_reserve(1000)).unwrap_err();
+                assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+                // The vector is still usable afterwards
+                sv.try_reserve(1000).unwrap();
+                for i in 2..1000 {
+                    sv.push(i);
+                }
+                assert_eq!(sv.size(), 999);
+                assert_eq!(sv.pop(), Some(999));
+            }
+        });
+        assert_eq!(stats.live_bytes(), 0, "descriptors or buckets leaked");
+    }
+
+    #[test]
+    fn drop_frees_everything() {
+        let ((), stats) = failing_alloc::measure(|| {
+            let sv = SecVec::<u64>::new();
+            for i in 0..1000 {
+                sv.push(i);
+            }
+            for _ in 0..500 {
+                sv.pop();
+            }
+            sv.reserve(5000);
+        });
+        assert_eq!(stats.live_bytes(), 0, "descriptors or buckets leaked");
+    }
+
+    #[test]
+    fn the_big_multithread_frees_everything() {
+        failing_alloc::assert_no_leaks_across_threads(
+            SecVec::<u64>::new,
+            5,
+            |sv, _| {
+                for i in 0..1000 {
+                    sv.push(i);
+                    if i % 3 == 0 {
+                        sv.pop();
+                    }
+                }
+            },
+            |_| {},
+        );
+    }
+
+    /// Swap in a descriptor for pushing `elem` without doing its write, like a push that was
+    /// descheduled right after its CAS in `try_write`. Returns the descriptor and its write.
+    #[allow(clippy::type_complexity)]
+    fn install_without_writing<'a>(
+        sv: &SecVec<'a, u64>,
+        elem: u64,
+    ) -> (
+        *mut Descriptor<'a, u64, ()>,
+        *mut Option<WriteDescriptor<'a, u64>>,
+    ) {
+        let current = sv.core.descriptor.load(Ordering::Acquire);
+        let size = unsafe { (*current).size };
+        sv.reserve(size + 1);
+        let location = unsafe { &*sv.core.get(size) };
+        let old = location.load(Ordering::Acquire);
+        let pending =
+            WriteDescriptor::new_some_as_ptr(slot::next(old, slot::pack(elem)), old, location);
+        let desc = Descriptor::new_as_ptr(pending, size + 1, ());
+        let replaced = sv
+            .core
+            .descriptor
+            .compare_exchange(current, desc, Ordering::AcqRel, Ordering::Relaxed)
+            .expect("nothing else uses the vector");
+        unsafe { sv.core.reclaim.retire(replaced) };
+        (desc, pending)
+    }
+
+    #[test]
+    fn stale_completion_doesnt_clear_a_newer_write() {
+        let sv = SecVec::<u64>::new();
+        // A thread loads the first push's descriptor and write, then is descheduled
+        let (first, first_write) = install_without_writing(&sv, 1);
+        let mut guard = sv.core.reclaim.guard();
+        guard.protect_raw(first);
+        let mut write_guard = sv.core.reclaim.guard();
+        write_guard.protect_raw(first_write);
+        // Meanwhile another thread completes the first write, and a second push swaps in its
+        // descriptor
+        sv.core.complete_write(unsafe { &*first }, first_write);
+        install_without_writing(&sv, 2);
+        // The first thread wakes up and completes the first write again. That must not clear
+        // the second push's write before it happens.
+        sv.core.complete_write(unsafe { &*first }, first_write);
+        assert_eq!(sv.read(1), Some(2));
+        assert_eq!(sv.read(0), Some(1));
+    }
+
+    #[test]
+    fn try_reserve_reports_capacity_overflow() {
+        let sv = SecVec::<u64>::new();
+        assert_eq!(
+            sv.try_reserve(usize::MAX).unwrap_err().kind(),
+            TryReserveErrorKind::CapacityOverflow
+        );
+    }
+
+    #[test]
+    fn new_aborts_on_allocation_failure() {
+        failing_alloc::assert_every_failure_aborts(
+            "sealed::tests::new_aborts_on_allocation_failure",
+            || (),
+            |_| drop(SecVec::<u64>::new()),
+        );
+    }
+
+    #[test]
+    fn push_aborts_on_allocation_failure() {
+        // Pushing the 9th element needs a new bucket as well as descriptors
+        failing_alloc::assert_every_failure_aborts(
+            "sealed::tests::push_aborts_on_allocation_failure",
+            || {
+                let sv = SecVec::<u64>::new();
+                for i in 0..8 {
+                    sv.push(i);
+                }
+                sv
+            },
+            |sv| sv.push(8),
+        );
+    }
+
+    #[test]
+    fn pop_aborts_on_allocation_failure() {
+        failing_alloc::assert_every_failure_aborts(
+            "sealed::tests::pop_aborts_on_allocation_failure",
+            || {
+                let sv = SecVec::<u64>::new();
+                sv.push(1);
+                sv
+            },
+            |sv| assert_eq!(sv.pop(), Some(1)),
+        );
+    }
+
+    #[test]
+    fn reserve_aborts_on_allocation_failure() {
+        failing_alloc::assert_every_failure_aborts(
+            "sealed::tests::reserve_aborts_on_allocation_failure",
+            SecVec::<u64>::new,
+            |sv| sv.reserve(1000),
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::model::model;
+    extern crate std;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_push() {
+        model(|| {
+            let sv = Arc::new(SecVec::<u64>::new());
+            let sv1 = Arc::clone(&sv);
+            let t = thread::spawn(move || sv1.push(1));
+            sv.push(2);
+            t.join().unwrap();
+
+            assert_eq!(sv.size(), 2);
+            let mut popped = [sv.pop().unwrap(), sv.pop().unwrap()];
+            popped.sort();
+            assert_eq!(popped, [1, 2]);
+            assert_eq!(sv.pop(), None);
+        });
+    }
+
+    #[test]
+    fn concurrent_push_pop() {
+        model(|| {
+            let sv = Arc::new(SecVec::<u64>::new());
+            sv.push(1);
+            let sv1 = Arc::clone(&sv);
+            let t = thread::spawn(move || sv1.push(2));
+            let popped = sv.pop().unwrap();
+            t.join().unwrap();
+
+            // Either the pop went first and took 1, or the push went first and the pop took 2
+            let left = sv.pop().unwrap();
+            assert!(matches!((popped, left), (1, 2) | (2, 1)));
+            assert_eq!(sv.pop(), None);
+        });
+    }
+
+    #[test]
+    fn size_during_push() {
+        model(|| {
+            let sv = Arc::new(SecVec::<u64>::new());
+            let sv1 = Arc::clone(&sv);
+            let t = thread::spawn(move || sv1.push(1));
+            // The push may or may not have happened yet
+            assert!(sv.size() <= 1);
+            t.join().unwrap();
+            assert_eq!(sv.size(), 1);
+        });
+    }
+
+    #[test]
+    fn reserve_during_push() {
+        model(|| {
+            let sv = Arc::new(SecVec::<u64>::new());
+            let sv1 = Arc::clone(&sv);
+            let t = thread::spawn(move || sv1.reserve(FIRST_BUCKET_SIZE + 1));
+            sv.push(1);
+            t.join().unwrap();
+
+            assert_eq!(sv.size(), 1);
+            assert!(sv.core.buffers.get(0).is_some());
+            assert!(sv.core.buffers.get(FIRST_BUCKET_SIZE).is_some());
+            assert_eq!(sv.pop(), Some(1));
+        });
+    }
+}
+
+// Only shuttle's own atomics are scheduling points. haphazard uses `core`'s atomics unless it's
+// built for loom, so publishing a hazard pointer and scanning them never get preempted here, only
+// the descriptor loads, swaps and CAS's around them do. Bugs that need a context switch in the
+// middle of protecting a pointer only show up in the loom models.
+#[cfg(all(test, shuttle))]
+mod shuttle_tests {
+    use super::*;
+    use crate::model::check;
+    extern crate std;
+    use crate::linearizability::{History, Recorder};
+    use shuttle::sync::atomic::AtomicU64;
+    use shuttle::thread;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    #[test]
+    fn the_big_multithread() {
+        check(|| {
+            let data = Arc::new(SecVec::<u64>::new());
+            let sum = Arc::new(AtomicU64::new(0));
+            let handles = (0..5)
+                .map(|_| {
+                    let data = Arc::clone(&data);
+                    thread::spawn(move || {
+                        for _ in 0..100 {
+                            data.push(5);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().for_each(|h| h.join().unwrap());
+            let handles = (0..5)
+                .map(|_| {
+                    let data = Arc::clone(&data);
+                    let sum = Arc::clone(&sum);
+                    thread::spawn(move || {
+                        for _ in 0..100 {
+                            sum.fetch_add(data.pop().unwrap_or(0), Ordering::Relaxed);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().for_each(|h| h.join().unwrap());
+            assert_eq!(sum.load(Ordering::Relaxed), 100 * 5 * 5);
+            assert_eq!(data.size(), 0);
+        });
+    }
+
+    #[test]
+    fn concurrent_push_pop_loses_nothing() {
+        check(|| {
+            let data = Arc::new(SecVec::<u64>::new());
+            let popped = Arc::new(Mutex::new(Vec::new()));
+            let handles = (0..5)
+                .map(|thread| {
+                    let data = Arc::clone(&data);
+                    let popped = Arc::clone(&popped);
+                    thread::spawn(move || {
+                        for i in 0..100 {
+                            data.push(thread * 100 + i);
+                            if i % 2 == 1 {
+                                let elem = data.pop().expect("pushed more than popped");
+                                popped.lock().unwrap().push(elem);
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().for_each(|h| h.join().unwrap());
+
+            let mut popped = popped.lock().unwrap().clone();
+            while let Some(elem) = data.pop() {
+                popped.push(elem);
+            }
+            assert_eq!(popped.iter().collect::<HashSet<_>>().len(), 500);
+            assert_eq!(popped.len(), 500);
+        });
+    }
+
+    #[test]
+    fn mixed_operations_are_linearizable() {
+        check(|| {
+            let data = SecVec::<u64>::new();
+            let recorder = Recorder::new();
+            let logs = thread::scope(|s| {
+                let handles = (0..3)
+                    .map(|thread| {
+                        let (data, recorder) = (&data, &recorder);
+                        s.spawn(move || {
+                            let mut log = recorder.log(thread);
+                            for i in 0..30 {
+                                match i % 5 {
+                                    0 | 1 => {
+                                        log.push((thread * 100 + i) as u64, |elem| data.push(elem))
+                                    }
+                                    2 => drop(log.pop(|| data.pop())),
+                                    3 => drop(log.size(|| data.size())),
+                                    _ if i % 2 == 0 => {
+                                        log.fetch_add(i % 4, 1, |index, val| {
+                                            data.fetch_add(index, val)
+                                        });
+                                    }
+                                    _ => drop(log.read(i % 4, |index| data.read(index))),
+                                }
+                            }
+                            log
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            History::new(logs).assert_linearizable();
+        });
+    }
 }
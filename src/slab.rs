@@ -0,0 +1,475 @@
+// A generational slab on the same buckets as `AppendVec`
+//
+// Every entry is one tagged slot: the high 64 bits are the entry's generation and whether it's
+// occupied, the low 64 bits are the (packed) value. Reading, inserting into and removing from an
+// entry are each one atomic operation on the slot, and a key only matches the slot while the
+// generation it was handed out with is still there.
+//
+// Removed entries go on a free list, a Treiber stack threaded through the entries' `next` fields.
+// Its head is tagged the same way slots are, so a pop that was descheduled between loading the
+// head and its CAS can't succeed after the entry has been popped and pushed back (ABA).
+extern crate alloc;
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::slot::{self, Slot};
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::Element;
+use core::fmt;
+use core::marker::PhantomData;
+use crossbeam_utils::{Backoff, CachePadded};
+
+const OCCUPIED: u64 = 1;
+
+/// The handle `insert` returns. It stays valid until the value is removed,
+/// even if the entry is reused afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key {
+    /// The entry the value is stored in
+    pub index: usize,
+    /// How many times the entry had been removed from when the value was inserted
+    pub generation: u64,
+}
+
+/// A lock-free slab: a table of [`Element`] values behind generational keys.
+///
+/// `insert` reuses removed entries before growing, `remove` frees an entry, and `get` with the key
+/// of a removed value returns `None`, even if something else has been inserted in its place.
+///
+/// Like a `SecVec`, the values have to be `Copy`, at most 8 bytes and without padding, so an index or a pointer
+/// rather than the object itself. They are copied in and out of the slab, no reference is ever
+/// handed out, so any thread can remove a value while others are reading it.
+///
+/// Entries are tagged slots like a `SecVec`'s elements, so the slab is only lock-free where
+/// [`crate::sealed::SecVec::is_lock_free`] is true.
+/// ```rust
+/// use unlocked::slab::SecSlab;
+///
+/// let sessions = SecSlab::new();
+/// let alice = sessions.insert(1u64);
+/// assert_eq!(sessions.get(alice), Some(1));
+/// assert_eq!(sessions.remove(alice), Some(1));
+/// // The entry is reused, but the old key doesn't match it anymore
+/// let bob = sessions.insert(2);
+/// assert_eq!(bob.index, alice.index);
+/// assert_eq!(sessions.get(alice), None);
+/// assert_eq!(sessions.get(bob), Some(2));
+/// ```
+pub struct SecSlab<T> {
+    entries: Buckets<Entry>,
+    // Tag and index + 1 of the first free entry, 0 if there is none
+    free: CachePadded<Slot>,
+    // Entries that have never been used are past this one
+    next: CachePadded<AtomicUsize>,
+    len: CachePadded<AtomicUsize>,
+    _boo: PhantomData<T>, // Values are stored packed
+}
+
+struct Entry {
+    slot: Slot,
+    // Index + 1 of the next free entry, while this one is on the free list
+    next: AtomicUsize,
+}
+
+// # Safety
+// Generation 0, vacant, and the end of the free list are all zeroes
+unsafe impl Zeroable for Entry {
+    fn zeroed() -> Self {
+        Self {
+            slot: Slot::new(0),
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+#[inline]
+fn state(word: u128) -> u64 {
+    (word >> 64) as u64
+}
+
+#[inline]
+fn generation(word: u128) -> u64 {
+    state(word) >> 1
+}
+
+#[inline]
+fn is_occupied(word: u128) -> bool {
+    state(word) & OCCUPIED != 0
+}
+
+#[inline]
+fn word(generation: u64, occupied: bool, value: u64) -> u128 {
+    let state = generation << 1 | occupied as u64;
+    (state as u128) << 64 | value as u128
+}
+
+impl<T> SecSlab<T>
+where
+    T: Element + Send + Sync,
+{
+    /// Return a new, empty slab. No buckets are allocated until the first insert.
+    pub fn new() -> Self {
+        Self {
+            entries: Buckets::new(),
+            free: CachePadded::new(Slot::new(0)),
+            next: CachePadded::new(AtomicUsize::new(0)),
+            len: CachePadded::new(AtomicUsize::new(0)),
+            _boo: PhantomData,
+        }
+    }
+
+    /// Store a value and return the key to it
+    /// ```rust
+    /// # use unlocked::slab::SecSlab;
+    /// let slab = SecSlab::new();
+    /// let key = slab.insert('a');
+    /// assert_eq!(slab.get(key), Some('a'));
+    /// ```
+    pub fn insert(&self, value: T) -> Key {
+        let index = match self.pop_free() {
+            Some(index) => index,
+            None => self.next.fetch_add(1, Ordering::Relaxed),
+        };
+        let entry = match self.entries.get_or_allocate(index) {
+            Ok(entry) => entry,
+            Err(err) => handle_error(err),
+        };
+        // Counted before the value is visible, so the remove of it can't be counted first
+        self.len.fetch_add(1, Ordering::Relaxed);
+        // The entry is ours until it's occupied, so the CAS can't fail
+        let old = entry.slot.load(Ordering::Acquire);
+        debug_assert!(!is_occupied(old));
+        let generation = generation(old);
+        let new = word(generation, true, slot::pack(value));
+        let _ = entry
+            .slot
+            .compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed);
+        Key { index, generation }
+    }
+
+    /// Return the value stored under `key`, or `None` if it has been removed
+    pub fn get(&self, key: Key) -> Option<T> {
+        let word = self.entries.get(key.index)?.slot.load(Ordering::Acquire);
+        if is_occupied(word) && generation(word) == key.generation {
+            // # Safety
+            // Occupied slots hold a value packed by insert
+            Some(unsafe { slot::unpack(slot::value(word)) })
+        } else {
+            None
+        }
+    }
+
+    /// Return whether a value is stored under `key`
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove the value stored under `key` and return it,
+    /// or `None` if it had already been removed
+    /// ```rust
+    /// # use unlocked::slab::SecSlab;
+    /// let slab = SecSlab::new();
+    /// let key = slab.insert(1);
+    /// assert_eq!(slab.remove(key), Some(1));
+    /// assert_eq!(slab.remove(key), None);
+    /// ```
+    pub fn remove(&self, key: Key) -> Option<T> {
+        let entry = self.entries.get(key.index)?;
+        let backoff = Backoff::new();
+        loop {
+            let old = entry.slot.load(Ordering::Acquire);
+            if !is_occupied(old) || generation(old) != key.generation {
+                return None;
+            }
+            let new = word(key.generation + 1, false, 0);
+            if entry
+                .slot
+                .compare_exchange(old, new, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                self.push_free(key.index, entry);
+                // # Safety
+                // The slot was occupied, so it held a value packed by insert
+                return Some(unsafe { slot::unpack(slot::value(old)) });
+            }
+            backoff.spin();
+        }
+    }
+
+    /// Return the number of values in the slab
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make room for entries `0..size` up front.
+    /// Inserts only grow the slab once every removed entry has been reused.
+    pub fn reserve(&self, size: usize) {
+        handle_reserve(self.try_reserve(size))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    /// ```rust
+    /// # use unlocked::slab::SecSlab;
+    /// let slab = SecSlab::<u32>::new();
+    /// assert!(slab.try_reserve(100).is_ok());
+    /// assert!(slab.try_reserve(usize::MAX).is_err());
+    /// ```
+    pub fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        self.entries.try_reserve(size)
+    }
+
+    fn pop_free(&self) -> Option<usize> {
+        let backoff = Backoff::new();
+        loop {
+            let head = self.free.load(Ordering::Acquire);
+            let index = slot::value(head).checked_sub(1)? as usize;
+            // Only entries that have been allocated are ever pushed onto the free list
+            let next = self
+                .entries
+                .get(index)
+                .unwrap()
+                .next
+                .load(Ordering::Relaxed);
+            // If the entry was popped (and maybe pushed again) in the meantime the tag has changed
+            if self
+                .free
+                .compare_exchange(
+                    head,
+                    slot::next(head, next as u64),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return Some(index);
+            }
+            backoff.spin();
+        }
+    }
+
+    fn push_free(&self, index: usize, entry: &Entry) {
+        let backoff = Backoff::new();
+        loop {
+            let head = self.free.load(Ordering::Acquire);
+            entry
+                .next
+                .store(slot::value(head) as usize, Ordering::Relaxed);
+            if self
+                .free
+                .compare_exchange(
+                    head,
+                    slot::next(head, index as u64 + 1),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                return;
+            }
+            backoff.spin();
+        }
+    }
+}
+
+impl<T> Default for SecSlab<T>
+where
+    T: Element + Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for SecSlab<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecSlab")
+            .field("len", &self.len.load(Ordering::Relaxed))
+            .field("next", &self.next.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate std;
+    use crate::failing_alloc;
+    use alloc::vec::Vec;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn insert_get_remove() {
+        let slab = SecSlab::new();
+        assert!(slab.is_empty());
+        let keys = (0..100).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        assert_eq!(slab.len(), 100);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(key.index, i);
+            assert_eq!(slab.get(*key), Some(i as i32));
+        }
+        for key in keys.iter().step_by(2) {
+            assert_eq!(slab.remove(*key), Some(key.index as i32));
+        }
+        assert_eq!(slab.len(), 50);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(slab.contains(*key), i % 2 == 1);
+        }
+    }
+
+    #[test]
+    fn stale_keys_miss() {
+        let slab = SecSlab::new();
+        let old = slab.insert(1u8);
+        slab.remove(old);
+        let new = slab.insert(2);
+        assert_eq!(new.index, old.index);
+        assert_eq!(new.generation, old.generation + 1);
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab.get(new), Some(2));
+        // Keys that were never handed out don't match either
+        assert_eq!(
+            slab.get(Key {
+                index: 5,
+                generation: 0
+            }),
+            None
+        );
+        assert_eq!(
+            slab.get(Key {
+                index: usize::MAX,
+                generation: 0
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn removed_entries_are_reused_before_growing() {
+        let slab = SecSlab::new();
+        let keys = (0..10).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        for key in &keys {
+            slab.remove(*key);
+        }
+        let mut reused = (0..10).map(|i| slab.insert(i).index).collect::<Vec<_>>();
+        reused.sort_unstable();
+        assert_eq!(reused, (0..10).collect::<Vec<_>>());
+        assert_eq!(slab.insert(10).index, 10);
+    }
+
+    #[test]
+    fn concurrent_insert_and_remove() {
+        let slab = SecSlab::new();
+        let kept = thread::scope(|s| {
+            let handles = (0..4u64)
+                .map(|t| {
+                    let slab = &slab;
+                    s.spawn(move || {
+                        let mut kept = Vec::new();
+                        for i in 0..2000 {
+                            let value = t * 2000 + i;
+                            let key = slab.insert(value);
+                            assert_eq!(slab.get(key), Some(value));
+                            if i % 2 == 0 {
+                                assert_eq!(slab.remove(key), Some(value));
+                                assert_eq!(slab.get(key), None);
+                            } else {
+                                kept.push((key, value));
+                            }
+                        }
+                        kept
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(slab.len(), 4000);
+        let indices = kept
+            .iter()
+            .map(|(key, _)| key.index)
+            .collect::<HashSet<_>>();
+        assert_eq!(indices.len(), 4000, "two values share an entry");
+        for (key, value) in kept {
+            assert_eq!(slab.get(key), Some(value));
+        }
+        // At most one removed entry per thread wasn't reused
+        assert!(slab.next.load(Ordering::Relaxed) <= 4004);
+    }
+
+    #[test]
+    fn the_free_list_doesnt_allocate() {
+        let slab = SecSlab::new();
+        let mut keys = (0..8).map(|i| slab.insert(i)).collect::<Vec<_>>();
+        // The free list is threaded through the entries themselves
+        let ((), stats) = failing_alloc::measure(|| {
+            for _ in 0..100 {
+                for (i, key) in keys.iter_mut().enumerate() {
+                    assert_eq!(slab.remove(*key), Some(i));
+                    *key = slab.insert(i);
+                }
+            }
+        });
+        assert_eq!(stats.allocations, 0);
+        assert_eq!(slab.len(), 8);
+    }
+
+    #[test]
+    fn insert_aborts_on_allocation_failure() {
+        // The first insert reuses the removed entry, the second one needs a new bucket
+        failing_alloc::assert_every_failure_aborts(
+            "slab::tests::insert_aborts_on_allocation_failure",
+            || {
+                let slab = SecSlab::new();
+                let keys = (0..8u64).map(|i| slab.insert(i)).collect::<Vec<_>>();
+                slab.remove(keys[3]);
+                slab
+            },
+            |slab| {
+                assert_eq!(slab.insert(8).index, 3);
+                slab.insert(9);
+            },
+        );
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    extern crate std;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn concurrent_remove_and_insert() {
+        loom::model(|| {
+            let slab = Arc::new(SecSlab::new());
+            let a = slab.insert(1u64);
+            let b = slab.insert(2u64);
+            let slab1 = Arc::clone(&slab);
+            let t = thread::spawn(move || {
+                slab1.remove(a);
+                slab1.insert(3)
+            });
+            slab.remove(b);
+            let d = slab.insert(4);
+            let c = t.join().unwrap();
+
+            // Both removed entries were reused, one each
+            assert_ne!(c.index, d.index);
+            assert!(c.index < 2 && d.index < 2);
+            assert_eq!(slab.get(c), Some(3));
+            assert_eq!(slab.get(d), Some(4));
+            assert_eq!(slab.get(a), None);
+            assert_eq!(slab.len(), 2);
+        });
+    }
+}
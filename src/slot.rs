@@ -0,0 +1,147 @@
+// Tagged slots, to make write-descriptors ABA-safe
+//
+// A write-descriptor does `compare_exchange(location, old, new)` on a slot. If the slot is popped and
+// pushed to again with the same bits between a helper loading the write-descriptor and performing
+// its CAS, the stale CAS succeeds and overwrites the newer element (the ABA problem).
+// See Dechev et. al., 2010, Understanding and Effectively Preventing the ABA Problem
+// in Descriptor-based Lock-free Designs
+//
+// To prevent this, every slot stores the number of times it has been written to next to the element.
+// Every write increments the tag, so once a write-descriptor's write has happened, the slot can never
+// contain its `old` value again and stale CAS's always fail.
+//
+// Element and tag take 128 bits, so a slot needs a double-width CAS. x86_64 (cmpxchg16b, detected
+// at runtime), aarch64 and a few others have one, and portable-atomic uses it. Everywhere else,
+// 32-bit x86 and Arm included, portable-atomic falls back to a global table of seqlocks, and the
+// collections on slots are not lock-free there: a thread preempted while it holds one of those
+// locks blocks every thread that touches a slot hashing to it. A 64-bit-only scheme would need
+// room for a tag next to an 8-byte element, so the fallback is documented rather than avoided.
+use crate::buckets::Zeroable;
+use crate::sync::atomic::AtomicU128;
+use core::{mem, ptr};
+
+/// A slot in a bucket. The low 64 bits are the element (a packed T),
+/// the high 64 bits are the number of times the slot has been written to.
+pub(crate) type Slot = AtomicU128;
+
+// # Safety
+// A zeroed slot holds the element 0 with tag 0, where every bucket starts out
+unsafe impl Zeroable for Slot {
+    fn zeroed() -> Self {
+        Slot::new(0)
+    }
+}
+
+/// Return the element stored in a slot's contents
+#[inline]
+pub(crate) fn value(word: u128) -> u64 {
+    word as u64
+}
+
+/// Return the contents a slot should have after writing `elem` to it,
+/// given its current contents
+#[inline]
+pub(crate) fn next(word: u128, elem: u64) -> u128 {
+    let tag = (word >> 64) as u64;
+    ((tag.wrapping_add(1) as u128) << 64) | elem as u128
+}
+
+/// Element types of the vectors and tables that keep their elements in 64-bit words.
+///
+/// Elements are copied in and out of a `u64` byte by byte, so they can't be larger than 8
+/// bytes (checked at compile time) and every one of their bytes has to be initialized. Padding
+/// isn't: a `SecVec<(u8, u16)>` would read an uninitialized byte on every push. That's what this
+/// trait rules out. It's implemented for the primitive types and arrays of them, implement it
+/// for your own `#[repr(C)]` or `#[repr(transparent)]` types that have no padding.
+///
+/// ```compile_fail
+/// // (u8, u16) has a padding byte
+/// let sv = unlocked::sealed::SecVec::<(u8, u16)>::new();
+/// ```
+///
+/// # Safety
+/// No value of the type may contain uninitialized bytes: no padding, no `MaybeUninit` or
+/// union fields that can be uninitialized.
+pub unsafe trait Element: Copy {}
+
+macro_rules! impl_element {
+    ($($ty:ty),*) => {$(
+        // # Safety
+        // Primitive types have no padding
+        unsafe impl Element for $ty {}
+    )*};
+}
+
+impl_element!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+impl_element!(f32, f64, bool, char, ());
+
+// # Safety
+// Array elements are laid out back to back without padding
+unsafe impl<T: Element, const N: usize> Element for [T; N] {}
+
+/// Return the bits a slot stores for `elem`: its bytes at the start of a zeroed u64
+///
+/// `transmute_copy::<T, u64>` would read past the end of any T smaller than 8 bytes.
+/// `Element` makes sure T has no padding, since padding bytes are uninitialized.
+#[inline]
+pub(crate) fn pack<T: Element>(elem: T) -> u64 {
+    const {
+        assert!(
+            mem::size_of::<T>() <= mem::size_of::<u64>(),
+            "elements can't be larger than 8 bytes"
+        )
+    };
+    let mut bits = 0u64;
+    // # Safety
+    // T fits in a u64 (checked at compile time), and the two don't overlap
+    unsafe {
+        ptr::copy_nonoverlapping(
+            &elem as *const T as *const u8,
+            &mut bits as *mut u64 as *mut u8,
+            mem::size_of::<T>(),
+        )
+    };
+    bits
+}
+
+/// Get back the element `pack` turned into `bits`
+///
+/// # Safety
+/// `bits` must have come from `pack::<T>`
+#[inline]
+pub(crate) unsafe fn unpack<T: Element>(bits: u64) -> T {
+    // # Safety
+    // The first size_of::<T>() bytes of `bits` are a valid T, and a u64 may not be aligned enough
+    unsafe { ptr::read_unaligned(&bits as *const u64 as *const T) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_round_trips() {
+        for elem in [0u8, 1, 0x7f, u8::MAX] {
+            assert_eq!(unsafe { unpack::<u8>(pack(elem)) }, elem);
+        }
+        for elem in [i16::MIN, -1, 0, i16::MAX] {
+            assert_eq!(unsafe { unpack::<i16>(pack(elem)) }, elem);
+        }
+        for elem in [f32::MIN, -0.5, 0.0, f32::MAX] {
+            assert_eq!(unsafe { unpack::<f32>(pack(elem)) }, elem);
+        }
+        for elem in ['a', '\u{10FFFF}'] {
+            assert_eq!(unsafe { unpack::<char>(pack(elem)) }, elem);
+        }
+        for elem in [i64::MIN, -1, u32::MAX as i64, i64::MAX] {
+            assert_eq!(unsafe { unpack::<i64>(pack(elem)) }, elem);
+        }
+    }
+
+    #[test]
+    fn pack_only_uses_the_elements_bytes() {
+        assert_eq!(pack(u8::MAX).count_ones(), 8);
+        assert_eq!(pack(-1i16).count_ones(), 16);
+        assert_eq!(pack(u32::MAX).count_ones(), 32);
+    }
+}
@@ -0,0 +1,468 @@
+// A vector that can be written at any index, on the same buckets as `AppendVec`
+//
+// Any index can be addressed as soon as the bucket it lives in is allocated, whatever has been
+// written before it, so `insert_at` just allocates that bucket if it has to. Entries are grouped
+// 64 to an occupancy word, so every bucket carries the bitmap of its own entries, and iterating
+// skips empty groups a word at a time.
+//
+// Each entry is a slot like a `SecVec` element's: the value in the low 64 bits, and in the high
+// ones a flag that's set while the entry is occupied. `insert_at` and `remove` are a single swap of
+// the whole slot and `get` a single load, so each takes effect at that instant and the table is
+// linearizable.
+//
+// The occupancy words are only a hint for `iter`, so it can skip empty groups. Their bit is set
+// after an insert fills an entry and cleared after a remove empties it, and a remove that clears
+// it checks the entry again in case an insert filled it in between. Once the writes to an entry
+// are done, its bit is set exactly when it's occupied. `iter` checks each entry it visits.
+use crate::alloc_error::{handle_error, handle_reserve, TryReserveError};
+use crate::buckets::{Buckets, Zeroable};
+use crate::slot::{self, Slot};
+use crate::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::Element;
+use core::fmt;
+use core::marker::PhantomData;
+use crossbeam_utils::CachePadded;
+
+/// The number of entries that share an occupancy word.
+/// Small under loom, which has to track every atomic in a bucket.
+#[cfg(not(loom))]
+const GROUP_SIZE: usize = u64::BITS as usize;
+#[cfg(loom)]
+const GROUP_SIZE: usize = 2;
+
+/// A lock-free table of [`Element`] values at arbitrary indices, such as a direct-mapped table keyed
+/// by integer ids.
+///
+/// Unlike a `SecVec`, indices don't have to be written in order: `insert_at` allocates the bucket
+/// an index lives in on demand, and `get` returns `None` for indices that were never written.
+/// Buckets double in size like a `SecVec`'s, so the one holding index `i` has room for about `i`
+/// entries. This is meant for ids that are handed out more or less densely, not for hashes.
+///
+/// Like a `SecVec`, the values have to be `Copy`, at most 8 bytes and without padding. They are
+/// copied in and out, no reference is ever handed out.
+///
+/// Entries are 128-bit slots like a `SecVec`'s elements, so the table is only lock-free where
+/// [`crate::sealed::SecVec::is_lock_free`] is true.
+/// ```rust
+/// use unlocked::sparse::SparseSecVec;
+///
+/// let users = SparseSecVec::new();
+/// assert!(users.insert_at(1_000, 'a'));
+/// assert!(users.insert_at(3, 'b'));
+/// assert_eq!(users.get(1_000), Some('a'));
+/// assert_eq!(users.get(999), None);
+/// assert_eq!(users.len(), 2);
+/// // In index order
+/// assert_eq!(users.iter().collect::<Vec<_>>(), [(3, 'b'), (1_000, 'a')]);
+/// ```
+pub struct SparseSecVec<T> {
+    groups: Buckets<Group>,
+    len: CachePadded<AtomicUsize>,
+    _boo: PhantomData<T>, // Values are stored packed
+}
+
+struct Group {
+    // Bit i is set while entry i holds a value, give or take the writes in progress
+    occupied: AtomicU64,
+    entries: [Slot; GROUP_SIZE],
+}
+
+// # Safety
+// A group with no entries occupied is all zeroes
+unsafe impl Zeroable for Group {
+    fn zeroed() -> Self {
+        Self {
+            occupied: AtomicU64::new(0),
+            entries: core::array::from_fn(|_| Slot::zeroed()),
+        }
+    }
+}
+
+// The high half of an occupied entry's slot
+const OCCUPIED: u128 = 1 << 64;
+
+#[inline]
+fn is_occupied(word: u128) -> bool {
+    word & OCCUPIED != 0
+}
+
+impl<T> SparseSecVec<T>
+where
+    T: Element + Send + Sync,
+{
+    /// Return a new, empty vector. No buckets are allocated until the first insert.
+    pub fn new() -> Self {
+        Self {
+            groups: Buckets::new(),
+            len: CachePadded::new(AtomicUsize::new(0)),
+            _boo: PhantomData,
+        }
+    }
+
+    /// Write `value` at `index`, allocating its bucket if needed.
+    /// Returns `true` if the index was empty, `false` if a value was overwritten.
+    ///
+    /// Of several threads inserting at the same empty index, exactly one sees `true`.
+    ///
+    /// # Panics
+    /// If the bucket for `index` can't be allocated
+    pub fn insert_at(&self, index: usize, value: T) -> bool {
+        let (group, bit) = Self::entry(index);
+        let group = match self.groups.get_or_allocate(group) {
+            Ok(group) => group,
+            Err(err) => handle_error(err),
+        };
+        let new = OCCUPIED | slot::pack(value) as u128;
+        let old = group.entries[index % GROUP_SIZE].swap(new, Ordering::AcqRel);
+        let vacant = !is_occupied(old);
+        if vacant {
+            group.occupied.fetch_or(bit, Ordering::AcqRel);
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        vacant
+    }
+
+    /// Return the value at `index`, or `None` if nothing is stored there.
+    pub fn get(&self, index: usize) -> Option<T> {
+        let (group, _) = Self::entry(index);
+        let word = self.groups.get(group)?.entries[index % GROUP_SIZE].load(Ordering::Acquire);
+        // # Safety
+        // Occupied entries hold a value packed by `insert_at`
+        is_occupied(word).then(|| unsafe { slot::unpack(slot::value(word)) })
+    }
+
+    /// Return whether a value is stored at `index`
+    pub fn contains(&self, index: usize) -> bool {
+        let (group, _) = Self::entry(index);
+        self.groups.get(group).is_some_and(|group| {
+            is_occupied(group.entries[index % GROUP_SIZE].load(Ordering::Acquire))
+        })
+    }
+
+    /// Empty `index`, returning whether a value was stored there.
+    ///
+    /// Of several threads removing the same value, exactly one sees `true`.
+    /// ```rust
+    /// # use unlocked::sparse::SparseSecVec;
+    /// let ids = SparseSecVec::new();
+    /// ids.insert_at(7, 70u32);
+    /// assert!(ids.remove(7));
+    /// assert!(!ids.remove(7));
+    /// assert_eq!(ids.get(7), None);
+    /// ```
+    pub fn remove(&self, index: usize) -> bool {
+        let (group, bit) = Self::entry(index);
+        let Some(group) = self.groups.get(group) else {
+            return false;
+        };
+        let entry = &group.entries[index % GROUP_SIZE];
+        if !is_occupied(entry.load(Ordering::Acquire)) {
+            // Don't write to entries that are already empty
+            return false;
+        }
+        let removed = is_occupied(entry.swap(0, Ordering::AcqRel));
+        if removed {
+            group.occupied.fetch_and(!bit, Ordering::AcqRel);
+            // An insert may have filled the entry and set the bit before we cleared it
+            if is_occupied(entry.load(Ordering::Acquire)) {
+                group.occupied.fetch_or(bit, Ordering::AcqRel);
+            }
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /// Return the number of values stored
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the indices that hold a value and their values, in index order.
+    ///
+    /// Each entry is read when the iterator gets to it, so with concurrent writes this may not be
+    /// the contents at any single point in time.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, T)> + '_ {
+        self.groups
+            .allocated()
+            .flat_map(|(first, groups)| groups.iter().zip(first..))
+            .flat_map(|(group, group_index)| {
+                let mut occupied = group.occupied.load(Ordering::Acquire);
+                core::iter::from_fn(move || {
+                    if occupied == 0 {
+                        return None;
+                    }
+                    let offset = occupied.trailing_zeros() as usize;
+                    occupied &= occupied - 1;
+                    Some((offset, group.entries[offset].load(Ordering::Acquire)))
+                })
+                .filter(|&(_, word)| is_occupied(word))
+                .map(move |(offset, word)| {
+                    // # Safety
+                    // Same as in `get`
+                    (group_index * GROUP_SIZE + offset, unsafe {
+                        slot::unpack(slot::value(word))
+                    })
+                })
+            })
+    }
+
+    /// Make room for indices `0..len` so that inserting at them doesn't allocate memory
+    pub fn reserve(&self, len: usize) {
+        handle_reserve(self.try_reserve(len))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    ///
+    /// Buckets allocated before the failure stay part of the vector.
+    pub fn try_reserve(&self, len: usize) -> Result<(), TryReserveError> {
+        self.groups.try_reserve(len.div_ceil(GROUP_SIZE))
+    }
+
+    /// Return the group `index` is in, and its bit in the group's occupancy word
+    #[inline]
+    fn entry(index: usize) -> (usize, u64) {
+        (index / GROUP_SIZE, 1 << (index % GROUP_SIZE))
+    }
+}
+
+impl<T> Default for SparseSecVec<T>
+where
+    T: Element + Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for SparseSecVec<T>
+where
+    T: Element + Send + Sync + fmt::Debug,
+{
+    /// Formats the values as a map from their indices
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    extern crate alloc;
+    extern crate std;
+    use crate::buckets::{bucket_len, locate, FIRST_BUCKET_SIZE};
+    use crate::failing_alloc;
+    use crate::TryReserveErrorKind;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use core::mem;
+    use std::thread;
+
+    #[test]
+    fn insert_get_and_remove_anywhere() {
+        let table = SparseSecVec::new();
+        assert!(table.is_empty());
+        for i in (0..10_000).step_by(7).rev() {
+            assert!(table.insert_at(i, i as u32));
+        }
+        assert_eq!(table.len(), 1429);
+        for i in 0..10_100 {
+            assert_eq!(table.get(i), (i < 10_000 && i % 7 == 0).then_some(i as u32));
+        }
+        assert!(!table.insert_at(14, 0));
+        assert_eq!(table.get(14), Some(0));
+        assert!(table.remove(14));
+        assert!(!table.contains(14));
+        assert!(!table.remove(15));
+        // Past every allocated bucket
+        assert!(!table.remove(usize::MAX / 2));
+        assert_eq!(table.get(usize::MAX / 2), None);
+        assert_eq!(table.len(), 1428);
+    }
+
+    #[test]
+    fn only_the_written_bucket_is_allocated() {
+        let index = 1 << 20;
+        let ((), stats) = failing_alloc::measure(|| {
+            let table = SparseSecVec::new();
+            table.insert_at(index, 1u64);
+            assert_eq!(table.get(index), Some(1));
+            assert_eq!(table.get(0), None);
+        });
+        let (bucket, _) = locate(index / GROUP_SIZE).unwrap();
+        let bucket_bytes = bucket_len(bucket) * mem::size_of::<Group>();
+        // Every bucket up to it would be almost twice that
+        assert!(stats.allocated_bytes < bucket_bytes + bucket_bytes / 2);
+    }
+
+    #[test]
+    fn iter_and_debug_are_in_index_order() {
+        let table = SparseSecVec::new();
+        for i in [700, 1, 64, 65, 63] {
+            table.insert_at(i, i as i16 - 100);
+        }
+        table.remove(64);
+        let entries = table.iter().collect::<Vec<_>>();
+        assert_eq!(entries, [(1, -99), (63, -37), (65, -35), (700, 600)]);
+        assert_eq!(format!("{table:?}"), "{1: -99, 63: -37, 65: -35, 700: 600}");
+    }
+
+    #[test]
+    fn racing_inserts_at_the_same_index_have_one_winner() {
+        let table = SparseSecVec::new();
+        let vacant = thread::scope(|s| {
+            let handles = (0..4u64)
+                .map(|t| {
+                    let table = &table;
+                    s.spawn(move || (0..5000).filter(|&i| table.insert_at(i, t)).count())
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .sum::<usize>()
+        });
+        assert_eq!(vacant, 5000);
+        assert_eq!(table.len(), 5000);
+        assert!((0..5000).all(|i| table.get(i).is_some_and(|t| t < 4)));
+    }
+
+    #[test]
+    fn concurrent_inserts_and_removes_of_neighbours() {
+        // Threads own alternating indices, so they share every occupancy word
+        let table = SparseSecVec::new();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let table = &table;
+                s.spawn(move || {
+                    for round in 0..50 {
+                        for i in (t..4096).step_by(4) {
+                            if round % 2 == 0 {
+                                assert!(table.insert_at(i, i));
+                                assert_eq!(table.get(i), Some(i));
+                            } else {
+                                assert!(table.remove(i));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        // Every thread ended with a removing round
+        assert!(table.is_empty());
+        assert_eq!(table.iter().count(), 0);
+    }
+
+    #[test]
+    fn only_inserting_allocates() {
+        let table = SparseSecVec::<u32>::new();
+        let ((), stats) = failing_alloc::measure(|| {
+            assert_eq!(table.get(100_000), None);
+            assert!(!table.contains(100_000));
+            assert!(!table.remove(100_000));
+            assert_eq!(table.iter().count(), 0);
+        });
+        assert_eq!(stats.allocations, 0);
+    }
+
+    #[test]
+    fn try_reserve_counts_indices() {
+        // The first bucket holds 8 groups
+        let len = FIRST_BUCKET_SIZE * GROUP_SIZE;
+        let table = SparseSecVec::new();
+        let ((), stats) = failing_alloc::measure(|| table.try_reserve(len).unwrap());
+        assert_eq!(stats.allocations, 1);
+        let ((), stats) = failing_alloc::measure(|| {
+            table.insert_at(len - 1, 1u32);
+        });
+        assert_eq!(stats.allocations, 0);
+
+        // One more index needs the next bucket
+        let err = failing_alloc::fail_nth(0, || table.try_reserve(len + 1)).unwrap_err();
+        assert!(matches!(err.kind(), TryReserveErrorKind::AllocError { .. }));
+        assert_eq!(table.get(len), None);
+        table.try_reserve(len + 1).unwrap();
+        let ((), stats) = failing_alloc::measure(|| {
+            table.insert_at(len, 2);
+        });
+        assert_eq!(stats.allocations, 0);
+        assert_eq!(table.len(), 2);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    #[test]
+    fn reader_sees_a_whole_write_or_nothing() {
+        loom::model(|| {
+            let table = Arc::new(SparseSecVec::new());
+            let table1 = Arc::clone(&table);
+            // Index 1 shares a group with index 0, and both threads race to allocate the bucket
+            let t = thread::spawn(move || table1.insert_at(1, u64::MAX));
+            table.insert_at(0, 7);
+            let seen = table.get(1);
+            assert!(seen.is_none() || seen == Some(u64::MAX));
+            assert!(t.join().unwrap());
+            assert_eq!(table.get(1), Some(u64::MAX));
+            assert_eq!(table.get(0), Some(7));
+            assert_eq!(table.len(), 2);
+        });
+    }
+
+    #[test]
+    fn racing_insert_and_remove() {
+        loom::model(|| {
+            let table = Arc::new(SparseSecVec::new());
+            table.insert_at(0, 1u32);
+            let table1 = Arc::clone(&table);
+            let t = thread::spawn(move || table1.remove(0));
+            let vacant = table.insert_at(0, 2);
+            let removed = t.join().unwrap();
+            // The remove took the first value, or the second one went in over the first and
+            // was removed
+            assert!(removed);
+            match table.get(0) {
+                Some(2) => assert!(vacant),
+                None => assert!(!vacant),
+                other => panic!("unexpected {other:?}"),
+            }
+            assert_eq!(table.len(), table.contains(0) as usize);
+            // The hint bit is right again once both are done
+            assert_eq!(table.iter().count(), table.len());
+        });
+    }
+
+    #[test]
+    fn reads_see_the_writes_in_order() {
+        loom::model(|| {
+            let table = Arc::new(SparseSecVec::new());
+            table.insert_at(0, 1u32);
+            let table1 = Arc::clone(&table);
+            let t = thread::spawn(move || {
+                table1.remove(0);
+                table1.insert_at(0, 2)
+            });
+            // Where each read can be in the history: before the remove, between the two, after
+            // the insert. A later read can't be earlier in it.
+            let step = |seen| match seen {
+                Some(1) => 0,
+                None => 1,
+                Some(2) => 2,
+                other => panic!("unexpected {other:?}"),
+            };
+            let first = step(table.get(0));
+            let second = step(table.get(0));
+            assert!(first <= second);
+            assert!(t.join().unwrap());
+            assert_eq!(table.get(0), Some(2));
+        });
+    }
+}
@@ -0,0 +1,176 @@
+// One trait for everything that can be pushed to and popped from by many threads at once,
+// so code can be written once and run on any of the vectors, or on a lock-based baseline
+extern crate alloc;
+extern crate std;
+use crate::Element;
+use alloc::vec::Vec;
+#[cfg(feature = "segqueue")]
+use crossbeam_queue::SegQueue;
+use std::sync::Mutex;
+
+/// A collection that many threads can push to and pop from through a shared reference.
+///
+/// Implemented by every `SecVec` in the crate, and by `Mutex<Vec<T>>` and, with the `segqueue`
+/// feature, crossbeam's `SegQueue` to compare them against. Together with `Default`, generic code
+/// can swap implementations with one type parameter:
+/// ```rust
+/// use unlocked::ConcurrentStack;
+///
+/// fn fill<S: ConcurrentStack<u64> + Default>(n: u64) -> S {
+///     let stack = S::default();
+///     stack.reserve(n as usize);
+///     for i in 0..n {
+///         stack.push(i);
+///     }
+///     stack
+/// }
+///
+/// let sealed = fill::<unlocked::sealed::SecVec<u64>>(10);
+/// let mutex = fill::<std::sync::Mutex<Vec<u64>>>(10);
+/// assert_eq!(sealed.pop(), Some(9));
+/// assert_eq!(mutex.size(), 10);
+/// ```
+pub trait ConcurrentStack<T> {
+    /// Add an element to the top
+    fn push(&self, elem: T);
+
+    /// Remove and return the element on top, or `None` if there isn't one
+    fn pop(&self) -> Option<T>;
+
+    /// Return the number of elements
+    fn size(&self) -> usize;
+
+    /// Make room for at least `size` elements in total, if the collection can
+    fn reserve(&self, size: usize);
+}
+
+macro_rules! impl_for_secvec {
+    ($($module:ident),*) => {$(
+        impl<T> ConcurrentStack<T> for crate::$module::SecVec<'_, T>
+        where
+            T: Element + Send + Sync,
+        {
+            fn push(&self, elem: T) {
+                self.push(elem)
+            }
+
+            fn pop(&self) -> Option<T> {
+                self.pop()
+            }
+
+            fn size(&self) -> usize {
+                self.size()
+            }
+
+            fn reserve(&self, size: usize) {
+                self.reserve(size)
+            }
+        }
+    )*};
+}
+
+impl_for_secvec!(leaky, sealed, qsbr, waitfree);
+
+/// The lock-based baseline. A poisoned lock panics.
+impl<T> ConcurrentStack<T> for Mutex<Vec<T>> {
+    fn push(&self, elem: T) {
+        self.lock().unwrap().push(elem)
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.lock().unwrap().pop()
+    }
+
+    fn size(&self) -> usize {
+        self.lock().unwrap().len()
+    }
+
+    fn reserve(&self, size: usize) {
+        let mut vec = self.lock().unwrap();
+        let additional = size.saturating_sub(vec.len());
+        vec.reserve(additional)
+    }
+}
+
+/// **Note**: a `SegQueue` is a queue, so `pop` returns the *oldest* element. It's here as a
+/// lock-free baseline for throughput, not for code that relies on last-in-first-out order.
+/// `reserve` does nothing, since it allocates a segment at a time.
+#[cfg(feature = "segqueue")]
+impl<T> ConcurrentStack<T> for SegQueue<T> {
+    fn push(&self, elem: T) {
+        self.push(elem)
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.pop()
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn reserve(&self, _size: usize) {}
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Every implementation has to agree on the basics, except SegQueue's order
+    fn basics<S: ConcurrentStack<u64> + Default>(lifo: bool) {
+        let stack = S::default();
+        assert_eq!(stack.size(), 0);
+        assert_eq!(stack.pop(), None);
+        stack.reserve(100);
+        for i in 0..100 {
+            stack.push(i);
+        }
+        assert_eq!(stack.size(), 100);
+        assert_eq!(stack.pop(), Some(if lifo { 99 } else { 0 }));
+        assert_eq!(stack.size(), 99);
+    }
+
+    fn threads<S: ConcurrentStack<u64> + Default + Sync>() {
+        let stack = S::default();
+        thread::scope(|s| {
+            for t in 0..4 {
+                let stack = &stack;
+                s.spawn(move || {
+                    for i in 0..500 {
+                        stack.push(t * 500 + i);
+                    }
+                });
+            }
+        });
+        assert_eq!(stack.size(), 2000);
+        let mut popped = Vec::new();
+        while let Some(elem) = stack.pop() {
+            popped.push(elem);
+        }
+        popped.sort_unstable();
+        assert_eq!(popped, (0..2000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn every_stack_agrees() {
+        basics::<crate::leaky::SecVec<u64>>(true);
+        basics::<crate::sealed::SecVec<u64>>(true);
+        basics::<crate::qsbr::SecVec<u64>>(true);
+        basics::<crate::waitfree::SecVec<u64>>(true);
+        basics::<Mutex<Vec<u64>>>(true);
+        #[cfg(feature = "segqueue")]
+        basics::<SegQueue<u64>>(false);
+    }
+
+    #[test]
+    fn every_stack_keeps_concurrent_pushes() {
+        threads::<crate::leaky::SecVec<u64>>();
+        threads::<crate::sealed::SecVec<u64>>();
+        threads::<crate::qsbr::SecVec<u64>>();
+        threads::<crate::waitfree::SecVec<u64>>();
+        threads::<Mutex<Vec<u64>>>();
+        #[cfg(feature = "segqueue")]
+        threads::<SegQueue<u64>>();
+    }
+}
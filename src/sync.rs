@@ -0,0 +1,124 @@
+// Atomics facade, so the same code can be model checked with loom or run under shuttle's scheduler
+// Build with `RUSTFLAGS="--cfg loom"` or `RUSTFLAGS="--cfg shuttle"` to swap in their atomics
+// (same pattern haphazard uses)
+#[cfg(all(loom, shuttle))]
+compile_error!("loom and shuttle can't be used at the same time");
+
+#[cfg(loom)]
+pub(crate) mod atomic {
+    pub(crate) use super::emulated::AtomicU128;
+    pub(crate) use loom::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+    };
+}
+
+#[cfg(shuttle)]
+pub(crate) mod atomic {
+    pub(crate) use super::emulated::AtomicU128;
+    pub(crate) use shuttle::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+    };
+}
+
+#[cfg(not(any(loom, shuttle)))]
+pub(crate) mod atomic {
+    pub(crate) use core::sync::atomic::{
+        fence, AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering,
+    };
+    pub(crate) use portable_atomic::{AtomicU128, AtomicU64};
+}
+
+#[cfg(any(loom, shuttle))]
+mod emulated {
+    use super::atomic::Ordering;
+    #[cfg(loom)]
+    use loom::sync::Mutex;
+    #[cfg(shuttle)]
+    use shuttle::sync::Mutex;
+
+    /// Neither loom nor shuttle have 128 bit atomics, so slots are emulated with their mutex.
+    ///
+    /// A mutex is stronger than any ordering we ask for, so this can't find bugs that are
+    /// only caused by a too-weak ordering on a slot. Everything else (descriptors, buckets,
+    /// hazard pointers) still uses their atomics and is checked normally.
+    #[derive(Debug)]
+    pub(crate) struct AtomicU128(Mutex<u128>);
+
+    impl AtomicU128 {
+        pub(crate) fn new(val: u128) -> Self {
+            Self(Mutex::new(val))
+        }
+
+        pub(crate) fn load(&self, _: Ordering) -> u128 {
+            *self.0.lock().unwrap()
+        }
+
+        pub(crate) fn swap(&self, new: u128, _: Ordering) -> u128 {
+            core::mem::replace(&mut *self.0.lock().unwrap(), new)
+        }
+
+        pub(crate) fn compare_exchange(
+            &self,
+            current: u128,
+            new: u128,
+            _: Ordering,
+            _: Ordering,
+        ) -> Result<u128, u128> {
+            let mut val = self.0.lock().unwrap();
+            if *val == current {
+                *val = new;
+                Ok(current)
+            } else {
+                Err(*val)
+            }
+        }
+
+        pub(crate) fn is_lock_free() -> bool {
+            false
+        }
+    }
+}
+
+#[cfg(not(loom))]
+pub(crate) use haphazard::HazardPointer;
+
+#[cfg(loom)]
+pub(crate) use self::hazard::HazardPointer;
+
+#[cfg(loom)]
+mod hazard {
+    use core::mem::ManuallyDrop;
+    use core::ops::{Deref, DerefMut};
+
+    /// A hazard pointer that is never handed back to its domain.
+    ///
+    /// haphazard spins without yielding while another thread holds the lock on the domain's list
+    /// of released hazard pointers, and loom can't get out of a loop like that. If nothing is ever
+    /// released that list stays empty and the lock is never taken. The leaked records are freed
+    /// with the domain, and the domain frees everything retired when it's dropped.
+    ///
+    /// Public because it's the guard type of `reclaim::HazardPointers`, the module is private.
+    pub struct HazardPointer<'domain, F>(ManuallyDrop<haphazard::HazardPointer<'domain, F>>);
+
+    impl<'domain, F> HazardPointer<'domain, F> {
+        pub(crate) fn new_in_domain(domain: &'domain haphazard::Domain<F>) -> Self {
+            Self(ManuallyDrop::new(haphazard::HazardPointer::new_in_domain(
+                domain,
+            )))
+        }
+    }
+
+    impl<'domain, F> Deref for HazardPointer<'domain, F> {
+        type Target = haphazard::HazardPointer<'domain, F>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
+
+    impl<F> DerefMut for HazardPointer<'_, F> {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            &mut self.0
+        }
+    }
+}
@@ -0,0 +1,562 @@
+// Implementation based on work by Dechev et. al., 2006
+// in their paper Lock-free Dynamically Resizable Arrays
+// https://www.stroustrup.com/lock-free-vector.pdf
+//
+// Wait-freedom comes from announcing operations and helping them, as done by Feldman et. al., 2016
+// in their paper An Efficient Wait-free Vector
+// https://ieeexplore.ieee.org/document/7160790
+// Operations first try the lock-free algorithm a few times, and only announce themselves if that fails.
+// This is the fast-path-slow-path methodology by Kogan & Petrank, 2012
+// https://dl.acm.org/doi/10.1145/2145816.2145835
+//
+// The buckets and descriptors are `sealed::SecVec`'s. This only adds the announcements, and the
+// operation each descriptor carries out for them.
+extern crate alloc;
+extern crate std;
+use crate::alloc_error::{handle_reserve, TryReserveError};
+use crate::reclaim::raw::{Guard, Scheme};
+use crate::reclaim::HazardPointers;
+use crate::sealed::{Core, Descriptor, Payload};
+use crate::slot;
+use crate::sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering};
+use crate::Element;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::Cell;
+use core::fmt;
+use core::ptr;
+use crossbeam_utils::{Backoff, CachePadded};
+
+/// The number of times an operation tries to complete on its own before announcing itself.
+pub const FAST_PATH_ATTEMPTS: usize = 8;
+
+/// The number of operations that can be announced at the same time.
+/// Operations are wait-free as long as at most this many threads use the vector at once.
+pub const MAX_ANNOUNCED: usize = 64;
+
+// The states of an announced operation
+const PENDING: u32 = 0;
+const DONE: u32 = 1;
+const DONE_WITH_VALUE: u32 = 2;
+
+std::thread_local! {
+    // The announcement slot this thread checks next
+    static HELP_CURSOR: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A wait-free vector over [`Element`] types.
+///
+/// The algorithm is the same as [`crate::sealed::SecVec`], except that `push` and `pop` are
+/// guaranteed to finish in a bounded number of steps, no matter what the other threads do.
+///
+/// Every operation first tries the lock-free algorithm [`FAST_PATH_ATTEMPTS`] times. If it keeps
+/// losing the race on the descriptor, it announces itself in an announcement array and every
+/// other thread helps it complete before starting anything new. Threads also check one
+/// announcement slot at the start of every operation, so announced operations are helped even
+/// by threads that never fall off the fast path.
+///
+/// An operation is bounded as long as no more than [`MAX_ANNOUNCED`] threads use the vector
+/// concurrently. With more threads, operations that find the announcement array full help
+/// the announced operations and try again, which is only lock-free.
+///
+/// Elements are stored like a `sealed::SecVec`'s, so on targets without a 128-bit CAS writing
+/// one takes a lock, and no operation is wait-free there. See
+/// [`crate::sealed::SecVec::is_lock_free`].
+///
+/// Memory reclamation is achieved through the use of hazard pointers.
+pub struct SecVec<'a, T: Sized + Copy> {
+    core: Core<'a, T, HazardPointers, Applies>,
+    announcements: Box<[CachePadded<AtomicPtr<Arc<Operation>>>; MAX_ANNOUNCED]>,
+    // Operations with smaller phases are helped first
+    phase: CachePadded<AtomicU64>,
+}
+
+/// The announced operation a descriptor carries out, if any
+#[derive(Default)]
+struct Applies {
+    operation: Option<Arc<Operation>>,
+    // The element removed from the vector, if this descriptor is from a pop
+    popped: Option<u64>,
+}
+
+/// An operation that a thread could not complete on its own
+struct Operation {
+    phase: u64,
+    kind: OperationKind,
+    state: AtomicU32,
+    // Only valid once the state is DONE_WITH_VALUE
+    result: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+enum OperationKind {
+    Push(u64), // The element is a transmuted T
+    Pop,
+}
+
+// Every thread completes a descriptor before it tries to replace it, so an announced operation
+// is marked as done before anyone can apply it again
+impl Payload for Applies {
+    fn finish(&self) {
+        if let Some(operation) = &self.operation {
+            operation.complete(self.popped);
+        }
+    }
+}
+
+impl Operation {
+    fn new(phase: u64, kind: OperationKind) -> Self {
+        Operation {
+            phase,
+            kind,
+            state: AtomicU32::new(PENDING),
+            result: AtomicU64::new(0),
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.state.load(Ordering::Acquire) == PENDING
+    }
+
+    /// Mark the operation as done, with the element it popped if it was a pop
+    fn complete(&self, popped: Option<u64>) {
+        let state = match popped {
+            Some(elem) => {
+                // Every thread completes the operation from the same descriptor,
+                // so they all store the same result
+                self.result.store(elem, Ordering::Relaxed);
+                DONE_WITH_VALUE
+            }
+            None => DONE,
+        };
+        let _ = self
+            .state
+            .compare_exchange(PENDING, state, Ordering::Release, Ordering::Relaxed);
+    }
+
+    /// The element popped by the operation, only meaningful once it is done
+    fn result(&self) -> Option<u64> {
+        match self.state.load(Ordering::Acquire) {
+            DONE_WITH_VALUE => Some(self.result.load(Ordering::Relaxed)),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, T> fmt::Debug for SecVec<'a, T>
+where
+    T: Copy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecVec")
+            .field("core", &self.core)
+            .field("phase", &self.phase)
+            .finish()
+    }
+}
+
+impl<'a, T> SecVec<'a, T>
+where
+    T: Sized + Element + Send + Sync,
+{
+    /// Return of new instance of a SecVec, with capacity 0 and size 0;
+    pub fn new() -> Self {
+        let announcements = Box::new(core::array::from_fn(|_| {
+            CachePadded::new(AtomicPtr::new(ptr::null_mut()))
+        }));
+        Self {
+            core: Core::new(Applies::default()),
+            announcements,
+            phase: CachePadded::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Build a descriptor that applies the operation on top of `current_desc`
+    fn next_descriptor(
+        &self,
+        current_desc: &Descriptor<'a, T, Applies>,
+        kind: OperationKind,
+        operation: Option<Arc<Operation>>,
+    ) -> *mut Descriptor<'a, T, Applies> {
+        match kind {
+            OperationKind::Push(elem) => self.core.push_descriptor(
+                current_desc,
+                elem,
+                Applies {
+                    operation,
+                    popped: None,
+                },
+            ),
+            // Popping an empty vector still swaps in a descriptor, so that the pop is only done once
+            OperationKind::Pop => {
+                let popped = self.core.last(current_desc);
+                Descriptor::without_write_as_ptr(
+                    current_desc.size - usize::from(popped.is_some()),
+                    Applies { operation, popped },
+                )
+            }
+        }
+    }
+
+    /// The fast path: try to apply the operation once without any help.
+    ///
+    /// Returns `None` if another thread changed the descriptor first,
+    /// otherwise returns the popped element, if there was one.
+    fn try_apply(&self, kind: OperationKind) -> Option<Option<u64>> {
+        let mut guard = self.core.reclaim().guard();
+        let current_desc = self.core.current(&mut guard);
+
+        self.core.complete(current_desc);
+
+        // Popping an empty vector doesn't need to change anything
+        if let (OperationKind::Pop, 0) = (kind, current_desc.size) {
+            return Some(None);
+        }
+
+        let next_desc = self.next_descriptor(current_desc, kind, None);
+        // # Safety
+        // We haven't published the descriptor yet, so we still have exclusive access
+        let popped = unsafe { &*next_desc }.payload.popped;
+
+        self.core.install(current_desc, next_desc).then_some(popped)
+    }
+
+    /// Apply an announced operation, unless some other thread already has
+    fn help(&self, operation: &Arc<Operation>) {
+        while operation.is_pending() {
+            let mut guard = self.core.reclaim().guard();
+            let current_desc = self.core.current(&mut guard);
+
+            // If the current descriptor applied the operation, this marks it as done
+            self.core.complete(current_desc);
+            if !operation.is_pending() {
+                break;
+            }
+
+            // Every thread applying the operation is racing to swap in a descriptor
+            // on top of the same current descriptor, so at most one can win
+            let next_desc =
+                self.next_descriptor(current_desc, operation.kind, Some(Arc::clone(operation)));
+            self.core.install(current_desc, next_desc);
+        }
+    }
+
+    /// The operation announced in `slot`, if there is one that's still pending
+    fn announced(&self, slot: &AtomicPtr<Arc<Operation>>) -> Option<Arc<Operation>> {
+        let mut guard = self.core.reclaim().guard();
+        // haphazard's inherent `protect` takes its own AtomicPtr, this is the one from `Guard`
+        let announcement = Guard::protect(&mut guard, slot);
+        // # Safety
+        // Announcements come from Box::into_raw, and are only freed through the vector's
+        // reclamation after they are withdrawn, which the guard prevents
+        let operation = unsafe { announcement.as_ref() }?;
+        operation.is_pending().then(|| Arc::clone(operation))
+    }
+
+    /// Help every announced operation whose phase is at most `phase`
+    fn help_announced(&self, phase: u64) {
+        for slot in self.announcements.iter() {
+            match self.announced(slot) {
+                Some(operation) if operation.phase <= phase => self.help(&operation),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Check the next announcement slot for this thread, and help the operation in it
+    fn help_one(&self) {
+        let slot = HELP_CURSOR.with(|cursor| {
+            let slot = cursor.get();
+            cursor.set((slot + 1) % MAX_ANNOUNCED);
+            slot
+        });
+        if let Some(operation) = self.announced(&self.announcements[slot]) {
+            self.help(&operation);
+        }
+    }
+
+    /// The slow path: announce the operation and get every thread to help with it
+    fn announce(&self, kind: OperationKind) -> Option<u64> {
+        let operation = Arc::new(Operation::new(
+            self.phase.fetch_add(1, Ordering::Relaxed),
+            kind,
+        ));
+        let announcement = Box::into_raw(Box::new(Arc::clone(&operation)));
+
+        let slot = loop {
+            if let Some(slot) = self.announcements.iter().find(|slot| {
+                slot.compare_exchange(
+                    ptr::null_mut(),
+                    announcement,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+            }) {
+                break slot;
+            }
+            // Every slot is taken, finish the announced operations so that they free up their slot
+            self.help_announced(u64::MAX);
+        };
+
+        // Help every operation that was announced before this one, then this one
+        self.help_announced(operation.phase);
+        self.help(&operation);
+
+        // # Safety
+        // Only the thread that announced the operation withdraws it, and it can't be loaded
+        // again after being swapped out, so it is safe to retire
+        unsafe {
+            self.core
+                .reclaim()
+                .retire(slot.swap(ptr::null_mut(), Ordering::AcqRel));
+        }
+
+        operation.result()
+    }
+
+    pub fn push(&self, elem: T) {
+        self.help_one();
+
+        let elem = slot::pack(elem);
+
+        let backoff = Backoff::new(); // Backoff causes significant speedup
+        for _ in 0..FAST_PATH_ATTEMPTS {
+            if self.try_apply(OperationKind::Push(elem)).is_some() {
+                return;
+            }
+            backoff.spin();
+        }
+
+        self.announce(OperationKind::Push(elem));
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.help_one();
+
+        let backoff = Backoff::new(); // Backoff causes significant speedup
+        let mut popped = None;
+        for _ in 0..FAST_PATH_ATTEMPTS {
+            popped = self.try_apply(OperationKind::Pop);
+            if popped.is_some() {
+                break;
+            }
+            backoff.spin();
+        }
+
+        // # Safety
+        // Everything in the vector was packed from a valid T by push
+        popped
+            .unwrap_or_else(|| self.announce(OperationKind::Pop))
+            .map(|elem| unsafe { slot::unpack(elem) })
+    }
+
+    /// Allocate buckets for `size` elements, so pushes up to that size don't allocate.
+    ///
+    /// # Panics
+    /// If `size` elements can't fit in `isize::MAX` bytes. If the allocator fails, this aborts
+    /// through `handle_alloc_error`. `try_reserve` reports both instead.
+    pub fn reserve(&self, size: usize) {
+        handle_reserve(self.try_reserve(size))
+    }
+
+    /// Like `reserve`, but reports an error instead of panicking or aborting
+    /// if the capacity overflows or the allocator fails.
+    pub fn try_reserve(&self, size: usize) -> Result<(), TryReserveError> {
+        self.core.try_reserve(size)
+    }
+
+    /// Return the size of the vector, completing a pending write operation first
+    /// ```rust
+    /// # use unlocked::waitfree::SecVec;
+    /// let sv = SecVec::<isize>::new();
+    /// sv.push(-1);
+    /// sv.push(-2);
+    /// sv.pop();
+    /// assert_eq!(sv.size(), 1);
+    /// ```
+    pub fn size(&self) -> usize {
+        self.core.size()
+    }
+}
+
+impl<'a, T> Default for SecVec<'a, T>
+where
+    T: Element + Sync + Send,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for SecVec<'_, T>
+where
+    T: Copy,
+{
+    fn drop(&mut self) {
+        // Operations withdraw their announcement before returning, but a thread that
+        // panicked in the middle of one might have left it behind
+        // # Safety
+        // Since we have &mut self, we have exclusive access
+        for slot in self.announcements.iter() {
+            let announcement = slot.swap(ptr::null_mut(), Ordering::Relaxed);
+            if !announcement.is_null() {
+                drop(unsafe { Box::from_raw(announcement) });
+            }
+        }
+        // The buckets and descriptors are freed by the core
+    }
+}
+
+#[cfg(all(test, not(any(loom, shuttle))))]
+mod tests {
+    use super::*;
+    use crate::linearizability::{History, Recorder};
+    use std::sync::atomic::AtomicIsize;
+    use std::thread::{self, JoinHandle};
+    use std::vec::Vec;
+
+    #[test]
+    fn size_starts_at_0() {
+        let sv = SecVec::<usize>::new();
+        assert_eq!(0, sv.size());
+    }
+
+    #[test]
+    fn pop_empty_returns_none() {
+        let sv = SecVec::<usize>::new();
+        assert_eq!(sv.pop(), None);
+    }
+
+    #[test]
+    fn ten_push_ten_pop() {
+        let sv = SecVec::<isize>::new();
+        for i in 0..10 {
+            sv.push(i);
+        }
+        for i in (0..10).rev() {
+            assert_eq!(sv.pop(), Some(i));
+        }
+    }
+
+    #[test]
+    fn slow_path_push_and_pop() {
+        let sv = SecVec::<isize>::new();
+        sv.announce(OperationKind::Push(7));
+        sv.push(8);
+        assert_eq!(sv.size(), 2);
+        assert_eq!(sv.announce(OperationKind::Pop), Some(8));
+        assert_eq!(sv.announce(OperationKind::Pop), Some(7));
+        assert_eq!(sv.announce(OperationKind::Pop), None);
+        assert_eq!(sv.size(), 0);
+    }
+
+    #[test]
+    fn announced_operations_get_helped() {
+        let sv = SecVec::<isize>::new();
+        // Announce an operation without helping it, like a thread that got descheduled
+        let operation = Arc::new(Operation::new(0, OperationKind::Push(5)));
+        sv.announcements[0].store(
+            Box::into_raw(Box::new(Arc::clone(&operation))),
+            Ordering::Release,
+        );
+
+        // Any thread that starts an operation on the vector eventually helps
+        for _ in 0..MAX_ANNOUNCED {
+            sv.size();
+            sv.push(6);
+        }
+        assert!(!operation.is_pending());
+        assert_eq!(sv.size(), MAX_ANNOUNCED + 1);
+    }
+
+    #[test]
+    fn the_big_multithread() {
+        static FIVE: isize = 5;
+        let data = Arc::new(SecVec::<isize>::new());
+        data.reserve(100 * 5);
+        let sum = Arc::new(AtomicIsize::new(0));
+        #[allow(clippy::needless_collect)]
+        let handles = (0..5)
+            .map(|_| {
+                let data = Arc::clone(&data);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        data.push(FIVE);
+                    }
+                })
+            })
+            .collect::<Vec<JoinHandle<_>>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        #[allow(clippy::needless_collect)]
+        let handles = (0..5)
+            .map(|_| {
+                let data = Arc::clone(&data);
+                let sum = Arc::clone(&sum);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        sum.fetch_add(data.pop().unwrap_or(0), Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect::<Vec<JoinHandle<_>>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(sum.load(Ordering::Relaxed), 100 * 5 * FIVE);
+    }
+
+    #[test]
+    fn the_big_multithread_slow_path() {
+        // Every operation announces itself, so threads are constantly helping each other
+        let data = Arc::new(SecVec::<isize>::new());
+        let sum = Arc::new(AtomicIsize::new(0));
+        #[allow(clippy::needless_collect)]
+        let handles = (0..5)
+            .map(|i| {
+                let data = Arc::clone(&data);
+                let sum = Arc::clone(&sum);
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        data.announce(OperationKind::Push(i));
+                    }
+                    for _ in 0..100 {
+                        let popped = data.announce(OperationKind::Pop).unwrap_or(0);
+                        sum.fetch_add(popped as isize, Ordering::Relaxed);
+                    }
+                })
+            })
+            .collect::<Vec<JoinHandle<_>>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        // Every element that was pushed was popped exactly once
+        assert_eq!(sum.load(Ordering::Relaxed), (0..5).sum::<isize>() * 100);
+        assert_eq!(data.size(), 0);
+    }
+
+    #[test]
+    fn the_big_multithread_is_linearizable() {
+        let sv = SecVec::<u64>::new();
+        let recorder = Recorder::new();
+        let logs = thread::scope(|s| {
+            let handles = (0..4)
+                .map(|thread| {
+                    let (sv, recorder) = (&sv, &recorder);
+                    s.spawn(move || {
+                        let mut log = recorder.log(thread);
+                        for i in 0..1000 {
+                            match i % 4 {
+                                0 | 1 => log.push((thread * 1000 + i) as u64, |elem| sv.push(elem)),
+                                2 => drop(log.pop(|| sv.pop())),
+                                _ => drop(log.size(|| sv.size())),
+                            }
+                        }
+                        log
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        History::new(logs).assert_linearizable();
+    }
+}
@@ -1,8 +1,8 @@
-# Run miri ten times with different random seeds
+# Run the tests under miri with different random seeds
 for i in (seq 20 30);
     echo "Running with seed $i"
-    MIRIFLAGS="-Zmiri-seed=$i" cargo miri run
-    if test $status -eq 1
+    MIRIFLAGS="-Zmiri-seed=$i" cargo miri test --lib
+    if test $status -ne 0
         exit 1
     end
-end
\ No newline at end of file
+end
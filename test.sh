@@ -1,9 +1,10 @@
-#!bin/sh
+#!/bin/bash
 
+# Run the tests under miri with different random seeds
 for i in {20..30}; do
     echo "Running with seed $i"
-    MIRIFLAGS="-Zmiri-seed=$i" cargo miri run --target x86_64-unknown-linux-gnu
+    MIRIFLAGS="-Zmiri-seed=$i" cargo miri test --lib --target x86_64-unknown-linux-gnu
     if [[ $? -ne 0 ]]; then
         exit 1
     fi
-done
\ No newline at end of file
+done